@@ -109,6 +109,17 @@ impl NfwsHandle {
     pub fn send_text(&mut self, msg: String) -> bool {
         self.cmd_tx.try_send(NfwsCmd::SendTextMessage(msg)).is_ok()
     }
+
+    pub fn send_binary(&mut self, msg: Vec<u8>) -> bool {
+        self.cmd_tx
+            .try_send(NfwsCmd::SendBinaryMessage(msg))
+            .is_ok()
+    }
+
+    /// Closes the websocket. The handle stops receiving events once it has.
+    pub fn close(&mut self) -> bool {
+        self.cmd_tx.try_send(NfwsCmd::Disconnect).is_ok()
+    }
 }
 
 async fn connect_websocket(
@@ -194,6 +205,7 @@ async fn connect_websocket(
                     }
                     Ok(NfwsCmd::Disconnect) => {
                         debug!("Received disconnect command");
+                        let _ = ws_sender.close().await;
                         break;
                     }
                 }
@@ -0,0 +1,146 @@
+//! Measures our latency to Edgegap's beacons, so the matchmaker can put our server near us,
+//! even if our IP address is a proxy or VPN.
+//!
+//! On startup, if [`BevygapClientConfig::beacons_url`] is set, the beacon list is fetched from
+//! matchmaker_httpd, and a websocket is opened to each beacon's TCP port, since browsers can't
+//! open plain TCP connections. A beacon has answered once the websocket connects or sends us
+//! something. Errors and closes are dropped, since a refused connection looks the same as a
+//! beacon hanging up on us. The results go in [`BevygapBeaconLatencies`], and the best few are
+//! sent with the session request.
+//!
+//! These aren't true round trip times. They're taken in the first frame that sees the answer,
+//! so they're rounded up to a frame, and they include DNS, the TCP connect and the websocket
+//! handshake. That's still good enough to tell near beacons from far ones.
+use crate::BevygapClientConfig;
+use bevy::prelude::*;
+use bevy::utils::{Duration, Instant};
+use bevy_nfws::prelude::*;
+use bevygap_shared::protocol::{Beacon, BeaconLatency};
+
+/// How many latencies are sent with the session request.
+const LATENCIES_SENT: usize = 3;
+
+/// How long the beacons that answered took, lowest first. See the module docs for why these
+/// are only roughly round trip times.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct BevygapBeaconLatencies(pub Vec<BeaconLatency>);
+
+impl BevygapBeaconLatencies {
+    /// The ones worth sending to the matchmaker.
+    pub(crate) fn best(&self) -> Vec<BeaconLatency> {
+        self.0.iter().take(LATENCIES_SENT).cloned().collect()
+    }
+
+    fn insert(&mut self, latency: BeaconLatency) {
+        let index = self.0.partition_point(|l| l.rtt_ms <= latency.rtt_ms);
+        self.0.insert(index, latency);
+    }
+}
+
+/// Marks websockets used for beacons, so the matchmaker request systems leave them alone.
+#[derive(Component, Debug)]
+pub(crate) enum BeaconSocket {
+    /// Fetching the beacon list from matchmaker_httpd.
+    List,
+    Probe {
+        host: String,
+        started: Instant,
+    },
+}
+
+enum ProbeResult {
+    Waiting,
+    /// How long after starting the probe we saw the answer.
+    Answered(Duration),
+    Failed(String),
+}
+
+/// Reads this frame's events for a beacon probe. Connecting doesn't mean anything yet, since
+/// it's sent before we've tried.
+fn poll_probe(nfws: &mut NfwsHandle, started: Instant) -> ProbeResult {
+    loop {
+        match nfws.next_event() {
+            NfwsPollResult::Event(NfwsEvent::Connecting) => {}
+            NfwsPollResult::Event(
+                NfwsEvent::Connected | NfwsEvent::TextMessage(_) | NfwsEvent::BinaryMessage(_),
+            ) => return ProbeResult::Answered(started.elapsed()),
+            NfwsPollResult::Event(NfwsEvent::Error(e)) => {
+                return ProbeResult::Failed(format!("{e:?}"))
+            }
+            NfwsPollResult::Event(NfwsEvent::Closed(reason)) => {
+                return ProbeResult::Failed(format!("closed {reason:?}"))
+            }
+            NfwsPollResult::Closed => return ProbeResult::Failed("socket gone".to_string()),
+            NfwsPollResult::Empty => return ProbeResult::Waiting,
+        }
+    }
+}
+
+pub(crate) fn fetch_beacons(config: Res<BevygapClientConfig>, mut commands: Commands) {
+    let Some(url) = &config.beacons_url else {
+        return;
+    };
+    info!("Fetching beacon list: {url}");
+    commands.spawn((NfwsHandle::new(url.clone()), BeaconSocket::List));
+}
+
+pub(crate) fn handle_beacon_sockets(
+    mut q: Query<(Entity, &mut NfwsHandle, &BeaconSocket)>,
+    config: Res<BevygapClientConfig>,
+    mut latencies: ResMut<BevygapBeaconLatencies>,
+    mut commands: Commands,
+) {
+    for (entity, mut nfws, socket) in q.iter_mut() {
+        match socket {
+            BeaconSocket::List => match nfws.next_event() {
+                NfwsPollResult::Empty => {}
+                NfwsPollResult::Closed => commands.entity(entity).despawn(),
+                NfwsPollResult::Event(NfwsEvent::TextMessage(msg)) => {
+                    match serde_json::from_str::<Vec<Beacon>>(&msg) {
+                        Ok(beacons) => {
+                            info!("Probing {} beacons", beacons.len());
+                            for beacon in beacons {
+                                let url = format!("ws://{}:{}", beacon.host, beacon.port);
+                                commands.spawn((
+                                    NfwsHandle::new(url),
+                                    BeaconSocket::Probe {
+                                        host: beacon.host,
+                                        started: Instant::now(),
+                                    },
+                                ));
+                            }
+                        }
+                        Err(_) => warn!("Unable to fetch beacon list: {msg}"),
+                    }
+                    nfws.close();
+                    commands.entity(entity).despawn();
+                }
+                NfwsPollResult::Event(NfwsEvent::Error(e)) => {
+                    warn!("Unable to fetch beacon list: {e:?}");
+                    commands.entity(entity).despawn();
+                }
+                NfwsPollResult::Event(_) => {}
+            },
+            BeaconSocket::Probe { host, started } => {
+                match poll_probe(&mut nfws, *started) {
+                    ProbeResult::Waiting if started.elapsed() < config.beacon_probe_timeout => {
+                        continue
+                    }
+                    ProbeResult::Answered(rtt) if rtt < config.beacon_probe_timeout => {
+                        debug!("Beacon {host} answered in {rtt:?}");
+                        latencies.insert(BeaconLatency {
+                            host: host.clone(),
+                            rtt_ms: rtt.as_millis() as u32,
+                        });
+                    }
+                    ProbeResult::Waiting | ProbeResult::Answered(_) => {
+                        debug!("Beacon {host} didn't answer in time");
+                    }
+                    ProbeResult::Failed(why) => debug!("Beacon {host} didn't answer: {why}"),
+                }
+                nfws.close();
+                commands.entity(entity).despawn();
+            }
+        }
+    }
+}
@@ -0,0 +1,41 @@
+//! Triggered as a matchmaker request progresses, so game UIs can react with observers,
+//! eg `app.observe(|trigger: Trigger<Progress>| ...)`, instead of parsing the state's string.
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use bevygap_shared::protocol::MatchmakerErrorKind;
+use std::net::SocketAddr;
+
+/// The matchmaker websocket is open, and the request has been sent.
+#[derive(Event, Debug, Clone)]
+pub struct MatchmakerConnected;
+
+/// The matchmaker created a session for us, and is waiting for a server.
+#[derive(Event, Debug, Clone)]
+pub struct SessionAccepted {
+    pub session_id: String,
+}
+
+/// A progress report from the matchmaker.
+#[derive(Event, Debug, Clone)]
+pub struct Progress {
+    /// As reported by the matchmaker, eg "Ready (3)".
+    pub status: String,
+    /// Since the request started, including any retries.
+    pub elapsed: Duration,
+}
+
+/// We have a connect token, and are about to connect to the gameserver at `addr`.
+#[derive(Event, Debug, Clone)]
+pub struct SessionReady {
+    pub addr: SocketAddr,
+}
+
+/// A request attempt failed.
+#[derive(Event, Debug, Clone)]
+pub struct MatchmakingFailed {
+    pub kind: MatchmakerErrorKind,
+    pub message: String,
+    /// True if the request will be retried, according to the
+    /// [`BevygapRetryPolicy`](crate::BevygapRetryPolicy). Otherwise the state is now `Error`.
+    pub retryable: bool,
+}
@@ -1,18 +1,35 @@
 use base64::prelude::*;
+use beacons::BevygapBeaconLatencies;
 use bevy::prelude::*;
+use bevy::utils::{Duration, Instant};
 use bevy_nfws::prelude::*;
 use bevygap_shared::protocol::*;
+use bevygap_shared::wire::*;
 use lightyear::prelude::{client::*, *};
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 
 pub mod prelude {
+    pub use super::beacons::BevygapBeaconLatencies;
+    pub use super::events::*;
     pub use super::traits::*;
     pub use super::BevygapClientConfig;
     pub use super::BevygapClientPlugin;
     pub use super::BevygapClientState;
+    pub use super::BevygapLobby;
+    pub use super::BevygapParty;
+    pub use super::BevygapReconnectSecret;
+    pub use super::BevygapRetryPolicy;
+    pub use bevygap_shared::protocol::{LobbyRequest, MatchmakerErrorKind, PartyRequest};
+    pub use bevygap_shared::wire::WireFormat;
 }
+mod beacons;
+mod events;
 mod traits;
 
+/// Netcode connect tokens are always this size.
+const CONNECT_TOKEN_BYTES: usize = 2048;
+
 #[derive(States, Debug, Clone, Default, Eq, PartialEq, Hash)]
 pub enum BevygapClientState {
     #[default]
@@ -25,11 +42,18 @@ pub enum BevygapClientState {
     ReadyToConnect,
     /// We triggered a connection attempt.
     Finished,
-    /// The request failed
-    Error(u16, String),
+    /// The request failed, after any retries. Some kinds of error, like timeouts or being rate
+    /// limited, are worth trying again shortly, see [`BevygapClientState::should_retry_later`].
+    Error(MatchmakerErrorKind, String),
 }
 
 impl BevygapClientState {
+    /// True if the request failed for now, rather than for good.
+    /// These are the errors [`BevygapRetryPolicy`] retries.
+    pub fn should_retry_later(&self) -> bool {
+        matches!(self, BevygapClientState::Error(kind, _) if kind.is_retryable())
+    }
+
     // run condition alternative to in_state(Enum(_with_param_))
     // since in_state doesn't support enum variants with parameters
     fn pending_state() -> impl FnMut(Option<Res<State<BevygapClientState>>>) -> bool + Clone {
@@ -55,8 +79,94 @@ pub struct BevygapClientConfig {
     pub game_name: String,
     /// The version of the game, used in the matchmaker request.
     pub game_version: String,
+    /// Set to create or join a party, to play on the same server as your friends.
+    pub party: Option<PartyRequest>,
+    /// Set to create or join a named lobby. The host starts it with `bevygap_start_lobby`.
+    pub lobby: Option<LobbyRequest>,
+    /// Set to ask for a new connect token for the server we were disconnected from,
+    /// instead of a new session. See [`BevygapReconnectSecret`].
+    pub reconnect_secret: Option<String>,
+    /// Bearer token (eg a JWT) from your login system, if the matchmaker requires authentication.
+    pub auth_token: Option<String>,
+    /// How failed requests are retried.
+    pub retry: BevygapRetryPolicy,
+    /// Give up on a request with a 408 error if it isn't ready by then, including retries.
+    /// None waits forever, eg for a lobby whose host might take a while to start it.
+    pub request_timeout: Option<Duration>,
+    /// The beacon list endpoint of matchmaker_httpd, eg:
+    /// ws://localhost:3000/matchmaker/beacons/ws
+    /// If set, we measure our latency to each beacon on startup, and send the best
+    /// with our request, so the server is placed near us. See [`BevygapBeaconLatencies`].
+    pub beacons_url: Option<String>,
+    /// Beacons that take longer than this to answer are ignored.
+    pub beacon_probe_timeout: Duration,
+    /// How messages to the matchmaker are encoded. MessagePack needs the `msgpack` feature.
+    pub wire_format: WireFormat,
+}
+
+/// Retries requests that failed with a retryable error (see
+/// [`BevygapClientState::should_retry_later`]), waiting longer after each attempt.
+#[derive(Debug, Clone)]
+pub struct BevygapRetryPolicy {
+    /// Attempts in total, including the first. 1 never retries.
+    pub max_attempts: u32,
+    /// Wait before the first retry. Doubles for each retry after that.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for BevygapRetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl BevygapRetryPolicy {
+    /// How long to wait after this many failed attempts.
+    fn backoff(&self, failed_attempts: u32) -> Duration {
+        let doublings = failed_attempts.saturating_sub(1).min(16);
+        (self.initial_backoff * 2u32.pow(doublings)).min(self.max_backoff)
+    }
+}
+
+/// The request in progress, kept across retries.
+#[derive(Resource, Debug)]
+struct BevygapRequestAttempts {
+    /// 1 for the first attempt
+    attempt: u32,
+    /// When the first attempt started, for the request timeout.
+    started: Instant,
+    /// Set while waiting to retry.
+    retry_at: Option<Instant>,
+}
+
+/// Inserted when the matchmaker reports on our party, so the game can show the code
+/// to the leader, and how many players have joined.
+#[derive(Resource, Debug, Clone)]
+pub struct BevygapParty {
+    pub code: String,
+    pub members: u8,
+    pub size: u8,
+}
+
+/// Inserted when the matchmaker reports on our lobby, so the game can list who's waiting,
+/// and show the host a start button.
+#[derive(Resource, Debug, Clone)]
+pub struct BevygapLobby {
+    pub name: String,
+    pub members: u8,
+    pub host: bool,
 }
 
+/// Inserted when the matchmaker gives us a session. If we're disconnected, the
+/// `bevygap_reconnect_client` command uses this to get back into the same server.
+#[derive(Resource, Debug, Clone)]
+pub struct BevygapReconnectSecret(pub String);
+
 impl Default for BevygapClientConfig {
     fn default() -> Self {
         Self {
@@ -64,6 +174,15 @@ impl Default for BevygapClientConfig {
             fake_client_ip: None,
             game_name: "bevygap-spaceships".to_string(),
             game_version: "1".to_string(),
+            party: None,
+            lobby: None,
+            reconnect_secret: None,
+            auth_token: None,
+            retry: BevygapRetryPolicy::default(),
+            request_timeout: Some(Duration::from_secs(180)),
+            beacons_url: None,
+            beacon_probe_timeout: Duration::from_secs(2),
+            wire_format: WireFormat::Json,
         }
     }
 }
@@ -75,12 +194,25 @@ impl Plugin for BevygapClientPlugin {
         app.add_plugins(NfwsPlugin);
         app.init_resource::<BevygapClientConfig>();
         app.init_state::<BevygapClientState>();
+        app.init_resource::<BevygapBeaconLatencies>();
+
+        app.add_systems(Startup, beacons::fetch_beacons);
+        app.add_systems(
+            Update,
+            beacons::handle_beacon_sockets.run_if(any_with_component::<beacons::BeaconSocket>),
+        );
 
         app.add_systems(OnEnter(BevygapClientState::Request), request_token);
 
         app.add_systems(
             Update,
-            handle_matchmaker_response.run_if(BevygapClientState::pending_state()),
+            (
+                handle_matchmaker_response,
+                check_request_timeout,
+                retry_request,
+            )
+                .chain()
+                .run_if(BevygapClientState::pending_state()),
         );
 
         app.add_systems(OnEnter(BevygapClientState::ReadyToConnect), connect_client);
@@ -90,12 +222,34 @@ impl Plugin for BevygapClientPlugin {
 fn request_token(
     mut next_state: ResMut<NextState<BevygapClientState>>,
     config: Res<BevygapClientConfig>,
+    attempts: Option<ResMut<BevygapRequestAttempts>>,
+    mut q: Query<(Entity, &mut NfwsHandle), Without<beacons::BeaconSocket>>,
     mut commands: Commands,
 ) {
+    // a retry, if retry_request sent us here. otherwise a fresh request.
+    let attempt = match attempts {
+        Some(mut attempts) if attempts.retry_at.take().is_some() => {
+            attempts.attempt += 1;
+            attempts.attempt
+        }
+        _ => {
+            commands.insert_resource(BevygapRequestAttempts {
+                attempt: 1,
+                started: Instant::now(),
+                retry_at: None,
+            });
+            1
+        }
+    };
+    // don't let a previous attempt's socket report on this one.
+    for (entity, mut nfws) in q.iter_mut() {
+        nfws.close();
+        commands.entity(entity).despawn();
+    }
     // TODO check if mm url is wss:// but matchmaker-tls not enabled, and on native, issue warning.
     // TODO issue warning if mm url starts http instead of ws. MM is ws!
     info!(
-        "Initiating matchmaker websocket connection: {}",
+        "Initiating matchmaker websocket connection: {} (attempt {attempt})",
         config.matchmaker_url
     );
     commands.spawn(NfwsHandle::new(config.matchmaker_url.clone()));
@@ -106,148 +260,394 @@ fn request_token(
 }
 
 fn handle_matchmaker_response(
-    mut q: Query<(Entity, &mut NfwsHandle)>,
+    mut q: Query<(Entity, &mut NfwsHandle), Without<beacons::BeaconSocket>>,
     mut commands: Commands,
     mut client_config: ResMut<ClientConfig>,
     mut next_state: ResMut<NextState<BevygapClientState>>,
     config: Res<BevygapClientConfig>,
+    mut attempts: Option<ResMut<BevygapRequestAttempts>>,
+    latencies: Res<BevygapBeaconLatencies>,
 ) {
     for (entity, mut nfws) in q.iter_mut() {
-        match nfws.next_event() {
+        let rec = match nfws.next_event() {
             NfwsPollResult::Closed => {
                 info!("EV None = closed, despawning");
                 commands.entity(entity).despawn();
                 continue;
             }
             NfwsPollResult::Empty => continue,
-            NfwsPollResult::Event(rec) => {
-                info!("EV: {rec:?}");
-                match rec {
-                    NfwsEvent::Connecting => {
-                        info!("Matchmaker: {rec:?}");
-                    }
-                    NfwsEvent::Connected => {
-                        info!("Matchmaker: {rec:?}");
-                        let req = RequestSession {
-                            client_ip: config.fake_client_ip.clone(),
-                            game: config.game_name.clone(),
-                            version: config.game_version.clone(),
-                        };
-                        let payload = serde_json::to_string(&req).unwrap();
-                        info!("Sending payload: {payload}");
-                        nfws.send_text(payload);
-                    }
-                    NfwsEvent::Error(nfws_err) => match nfws_err {
-                        NfwsErr::Connecting => next_state.set(BevygapClientState::Error(
-                            0,
-                            "Can't connect to matchmaker".to_string(),
-                        )),
-                        NfwsErr::Receiving(msg) => next_state.set(BevygapClientState::Error(
-                            0,
-                            format!("Rcv error from matchmaker: {msg}"),
-                        )),
-                        NfwsErr::Sending(msg) => next_state.set(BevygapClientState::Error(
-                            0,
-                            format!("Send error to matchmaker: {msg}"),
-                        )),
-                    },
-                    NfwsEvent::Closed(frame) => {
-                        info!("Matchmaker connection closed: {frame:?}");
-                    }
-                    NfwsEvent::BinaryMessage(_vec) => {
-                        warn!("Matchmaker: binary msg received, unhandled.");
+            NfwsPollResult::Event(rec) => rec,
+        };
+        info!("EV: {rec:?}");
+        let frame = match rec {
+            NfwsEvent::Connecting => {
+                info!("Matchmaker: {rec:?}");
+                continue;
+            }
+            NfwsEvent::Connected => {
+                info!("Matchmaker: {rec:?}");
+                commands.trigger(events::MatchmakerConnected);
+                let req = RequestSession {
+                    client_ip: config.fake_client_ip.clone(),
+                    game: config.game_name.clone(),
+                    version: config.game_version.clone(),
+                    party: config.party.clone(),
+                    lobby: config.lobby.clone(),
+                    reconnect_secret: config.reconnect_secret.clone(),
+                    auth_token: config.auth_token.clone(),
+                    latencies: latencies.best(),
+                };
+                let envelope = Envelope::new(KIND_REQUEST_SESSION, &req, None).unwrap();
+                send_envelope(&mut nfws, &envelope, config.wire_format);
+                continue;
+            }
+            NfwsEvent::Error(nfws_err) => {
+                let (kind, msg) = match nfws_err {
+                    NfwsErr::Connecting => (
+                        MatchmakerErrorKind::NoCapacity,
+                        "Can't connect to matchmaker".to_string(),
+                    ),
+                    NfwsErr::Receiving(msg) => (
+                        MatchmakerErrorKind::Internal,
+                        format!("Rcv error from matchmaker: {msg}"),
+                    ),
+                    NfwsErr::Sending(msg) => (
+                        MatchmakerErrorKind::Internal,
+                        format!("Send error to matchmaker: {msg}"),
+                    ),
+                };
+                request_failed(
+                    &mut commands,
+                    kind,
+                    msg,
+                    &config,
+                    attempts.as_deref_mut(),
+                    &mut next_state,
+                );
+                continue;
+            }
+            NfwsEvent::Closed(frame) => {
+                info!("Matchmaker connection closed: {frame:?}");
+                continue;
+            }
+            NfwsEvent::TextMessage(msg) => Frame::Text(msg),
+            NfwsEvent::BinaryMessage(bytes) => Frame::Binary(bytes),
+        };
+        let envelope = match Envelope::decode(&frame) {
+            Ok((envelope, _)) => envelope,
+            Err(e) => {
+                warn!("Unhandled msg from matchmaker: {frame:?} ({e})");
+                warn!("Despawning client entity");
+                request_failed(
+                    &mut commands,
+                    MatchmakerErrorKind::Internal,
+                    "Unhandled response from matchmaker".to_string(),
+                    &config,
+                    attempts.as_deref_mut(),
+                    &mut next_state,
+                );
+                commands.entity(entity).despawn();
+                continue;
+            }
+        };
+        // newer matchmakers may send kinds of message we don't know about.
+        let Some(feedback) = envelope.feedback() else {
+            warn!("Skipping {} message from matchmaker", envelope.kind);
+            continue;
+        };
+        info!(">>> {feedback:?}");
+        match feedback {
+            SessionRequestFeedback::Acknowledged => next_state.set(
+                BevygapClientState::AwaitingResponse("Request acknowledged".to_string()),
+            ),
+            SessionRequestFeedback::SessionRequestAccepted(sess_id) => {
+                next_state.set(BevygapClientState::AwaitingResponse(format!(
+                    "Session created: {sess_id}"
+                )));
+                commands.trigger(events::SessionAccepted {
+                    session_id: sess_id,
+                });
+            }
+            SessionRequestFeedback::ProgressReport(prog_msg) => {
+                next_state.set(BevygapClientState::AwaitingResponse(format!(
+                    "Progress: {prog_msg}"
+                )));
+                commands.trigger(events::Progress {
+                    status: prog_msg,
+                    elapsed: attempts
+                        .as_ref()
+                        .map(|attempts| attempts.started.elapsed())
+                        .unwrap_or_default(),
+                });
+            }
+            SessionRequestFeedback::PartyUpdate {
+                code,
+                members,
+                size,
+            } => {
+                commands.insert_resource(BevygapParty {
+                    code: code.clone(),
+                    members,
+                    size,
+                });
+                next_state.set(BevygapClientState::AwaitingResponse(format!(
+                    "Party {code}: waiting for players {members}/{size}"
+                )))
+            }
+            SessionRequestFeedback::LobbyUpdate {
+                name,
+                members,
+                host,
+            } => {
+                commands.insert_resource(BevygapLobby {
+                    name: name.clone(),
+                    members,
+                    host,
+                });
+                next_state.set(BevygapClientState::AwaitingResponse(format!(
+                    "Lobby {name}: {members} players waiting"
+                )))
+            }
+            SessionRequestFeedback::Error(err_code, err_msg) => request_failed(
+                &mut commands,
+                MatchmakerErrorKind::from_code(err_code),
+                err_msg,
+                &config,
+                attempts.as_deref_mut(),
+                &mut next_state,
+            ),
+            SessionRequestFeedback::SessionReady {
+                token,
+                ip,
+                port,
+                cert_digest,
+                ports,
+                reconnect_secret,
+            } => {
+                if let Some(secret) = reconnect_secret {
+                    commands.insert_resource(BevygapReconnectSecret(secret));
+                }
+                let cert_digest = cert_digest.replace(':', "");
+                info!("Using cert digest {cert_digest}");
+                let connect_token = match parse_connect_token(&token) {
+                    Ok(connect_token) => connect_token,
+                    Err(e) => {
+                        error!("{e}");
+                        request_failed(
+                            &mut commands,
+                            MatchmakerErrorKind::Internal,
+                            e,
+                            &config,
+                            attempts.as_deref_mut(),
+                            &mut next_state,
+                        );
+                        continue;
                     }
-                    NfwsEvent::TextMessage(msg) => {
-                        let Ok(feedback) =
-                            serde_json::from_slice::<SessionRequestFeedback>(msg.as_bytes())
-                        else {
-                            warn!("Unhandled msg type from matchmaker: {msg:?}");
-                            warn!("Despawning client entity");
-                            next_state.set(BevygapClientState::Error(
-                                0,
-                                "Unhandled response from matchmaker".to_string(),
-                            ));
-                            commands.entity(entity).despawn();
-                            continue;
-                        };
-                        info!(">>> {feedback:?}");
-                        match feedback {
-                            SessionRequestFeedback::Acknowledged => {
-                                next_state.set(BevygapClientState::AwaitingResponse(
-                                    "Request acknowledged".to_string(),
-                                ))
-                            }
-                            SessionRequestFeedback::SessionRequestAccepted(sess_id) => next_state
-                                .set(BevygapClientState::AwaitingResponse(format!(
-                                    "Session created: {sess_id}"
-                                ))),
-                            SessionRequestFeedback::ProgressReport(prog_msg) => {
-                                next_state.set(BevygapClientState::AwaitingResponse(format!(
-                                    "Progress: {prog_msg}"
-                                )))
-                            }
-                            SessionRequestFeedback::Error(err_code, err_msg) => {
-                                next_state.set(BevygapClientState::Error(err_code, err_msg))
-                            }
-                            SessionRequestFeedback::SessionReady {
-                                token,
-                                ip,
-                                port,
-                                cert_digest,
-                            } => {
-                                let cert_digest = cert_digest.replace(':', "");
-                                info!("Using cert digest {cert_digest}");
-                                let tok_bytes = BASE64_STANDARD.decode(&token).unwrap();
-                                assert_eq!(
-                                    tok_bytes.len(),
-                                    2048,
-                                    "ConnectTokens should be 2048 bytes exactly"
-                                );
-                                let connect_token =
-                                    ConnectToken::try_from_bytes(tok_bytes.as_slice()).unwrap();
-
-                                // TODO be defensive here
-                                let server_addr: SocketAddr =
-                                    format!("{ip}:{port}").parse().expect(
-                                        "invalid gameserver addr/port from matchmaker response?",
-                                    );
-
-                                info!("Got matchmaker response, game server: {server_addr:?}");
-
-                                if let NetConfig::Netcode { auth, io, .. } = &mut client_config.net
-                                {
-                                    info!("Setting Netcode connect token and server addr");
-                                    *auth = Authentication::Token(connect_token);
-                                    // inject gameserver address and port into lightyear client transport
-                                    // (preserves existing client_addr if it was already set)
-                                    let client_addr = match &mut io.transport {
-                                        client::ClientTransport::WebTransportClient {
-                                            client_addr,
-                                            ..
-                                        } => client_addr,
-                                        _ => panic!("Unsupported transport: {:?}", io.transport),
-                                    };
-                                    io.transport = client::ClientTransport::WebTransportClient {
-                                        client_addr: *client_addr,
-                                        server_addr,
-                                        #[cfg(target_family = "wasm")]
-                                        certificate_digest: cert_digest,
-                                    };
-                                } else {
-                                    panic!("Unsupported netconfig, only supports Netcode for now.");
-                                }
-                                next_state.set(BevygapClientState::ReadyToConnect);
-                            }
-                        }
+                };
+
+                info!("Got matchmaker response, game server: {ip} {ports:?}");
+
+                let NetConfig::Netcode { auth, io, .. } = &mut client_config.net else {
+                    request_failed(
+                        &mut commands,
+                        MatchmakerErrorKind::Internal,
+                        "Unsupported netconfig, only supports Netcode for now.".to_string(),
+                        &config,
+                        attempts.as_deref_mut(),
+                        &mut next_state,
+                    );
+                    continue;
+                };
+                info!("Setting Netcode connect token and server addr");
+                *auth = Authentication::Token(connect_token);
+                let addr = match set_server_addr(&mut io.transport, &ip, &ports, port, cert_digest)
+                {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        error!("{e}");
+                        request_failed(
+                            &mut commands,
+                            MatchmakerErrorKind::Internal,
+                            e,
+                            &config,
+                            attempts.as_deref_mut(),
+                            &mut next_state,
+                        );
+                        continue;
                     }
-                }
+                };
+                commands.trigger(events::SessionReady { addr });
+                next_state.set(BevygapClientState::ReadyToConnect);
             }
         }
     }
 }
 
+/// Text frames for json, binary for MessagePack.
+pub(crate) fn send_envelope(nfws: &mut NfwsHandle, envelope: &Envelope, format: WireFormat) {
+    match envelope.encode(format) {
+        Frame::Text(text) => {
+            info!("Sending payload: {text}");
+            nfws.send_text(text);
+        }
+        Frame::Binary(bytes) => {
+            info!("Sending {} byte payload", bytes.len());
+            nfws.send_binary(bytes);
+        }
+    }
+}
+
+/// Moves to `Error(kind, msg)`, unless the error is retryable and the retry policy allows
+/// another attempt, in which case retry_request tries again after the backoff.
+fn request_failed(
+    commands: &mut Commands,
+    kind: MatchmakerErrorKind,
+    msg: String,
+    config: &BevygapClientConfig,
+    attempts: Option<&mut BevygapRequestAttempts>,
+    next_state: &mut NextState<BevygapClientState>,
+) {
+    let retry_in = attempts.and_then(|attempts| {
+        let backoff = config.retry.backoff(attempts.attempt);
+        let out_of_time = config
+            .request_timeout
+            .is_some_and(|timeout| attempts.started.elapsed() + backoff >= timeout);
+        if !kind.is_retryable() || attempts.attempt >= config.retry.max_attempts || out_of_time {
+            return None;
+        }
+        attempts.retry_at = Some(Instant::now() + backoff);
+        Some(backoff)
+    });
+    commands.trigger(events::MatchmakingFailed {
+        kind,
+        message: msg.clone(),
+        retryable: retry_in.is_some(),
+    });
+    match retry_in {
+        Some(backoff) => {
+            warn!("Matchmaker request failed with {kind:?}: {msg}, retrying in {backoff:?}");
+            next_state.set(BevygapClientState::AwaitingResponse(format!(
+                "Retrying in {}s ({msg})",
+                backoff.as_secs()
+            )));
+        }
+        None => {
+            warn!("Matchmaker request failed with {kind:?}: {msg}");
+            next_state.set(BevygapClientState::Error(kind, msg));
+        }
+    }
+}
+
+/// Gives up on the request once it has taken longer than the request timeout.
+fn check_request_timeout(
+    config: Res<BevygapClientConfig>,
+    attempts: Option<Res<BevygapRequestAttempts>>,
+    mut q: Query<(Entity, &mut NfwsHandle), Without<beacons::BeaconSocket>>,
+    mut next_state: ResMut<NextState<BevygapClientState>>,
+    mut commands: Commands,
+) {
+    let (Some(timeout), Some(attempts)) = (config.request_timeout, attempts) else {
+        return;
+    };
+    if attempts.started.elapsed() < timeout {
+        return;
+    }
+    warn!("Matchmaker request timed out after {timeout:?}");
+    for (entity, mut nfws) in q.iter_mut() {
+        nfws.close();
+        commands.entity(entity).despawn();
+    }
+    commands.remove_resource::<BevygapRequestAttempts>();
+    let message = "Timed out waiting for the matchmaker".to_string();
+    commands.trigger(events::MatchmakingFailed {
+        kind: MatchmakerErrorKind::Timeout,
+        message: message.clone(),
+        retryable: false,
+    });
+    next_state.set(BevygapClientState::Error(
+        MatchmakerErrorKind::Timeout,
+        message,
+    ));
+}
+
+/// Sends the request again once the backoff is over.
+fn retry_request(
+    attempts: Option<Res<BevygapRequestAttempts>>,
+    mut next_state: ResMut<NextState<BevygapClientState>>,
+) {
+    let Some(retry_at) = attempts.and_then(|attempts| attempts.retry_at) else {
+        return;
+    };
+    if Instant::now() >= retry_at {
+        next_state.set(BevygapClientState::Request);
+    }
+}
+
+/// Decodes the base64 connect token the matchmaker sent.
+fn parse_connect_token(token: &str) -> Result<ConnectToken, String> {
+    let tok_bytes = BASE64_STANDARD
+        .decode(token)
+        .map_err(|e| format!("Connect token isn't valid base64: {e}"))?;
+    if tok_bytes.len() != CONNECT_TOKEN_BYTES {
+        return Err(format!(
+            "Connect token should be {CONNECT_TOKEN_BYTES} bytes exactly, got {}",
+            tok_bytes.len()
+        ));
+    }
+    ConnectToken::try_from_bytes(tok_bytes.as_slice())
+        .map_err(|e| format!("Invalid connect token: {e:?}"))
+}
+
+/// Injects the gameserver address into whichever lightyear client transport the game
+/// configured, using the matchmaker's port for that transport.
+/// (preserves existing client_addr if it was already set)
+#[cfg_attr(not(target_family = "wasm"), allow(unused_variables))]
+fn set_server_addr(
+    transport: &mut client::ClientTransport,
+    ip: &str,
+    ports: &BTreeMap<GameTransport, u16>,
+    port: u16,
+    cert_digest: String,
+) -> Result<SocketAddr, String> {
+    let server_addr = |game_transport: GameTransport| -> Result<SocketAddr, String> {
+        // older matchmakers only send the one (WebTransport) port.
+        let port = match ports.get(&game_transport) {
+            Some(port) => *port,
+            None if ports.is_empty() && game_transport == GameTransport::WebTransport => port,
+            None => return Err(format!("Gameserver doesn't offer {game_transport}")),
+        };
+        format!("{ip}:{port}")
+            .parse()
+            .map_err(|e| format!("Invalid gameserver address {ip}:{port}: {e}"))
+    };
+    let addr = match transport {
+        client::ClientTransport::WebTransportClient { client_addr, .. } => {
+            let client_addr = *client_addr;
+            let addr = server_addr(GameTransport::WebTransport)?;
+            *transport = client::ClientTransport::WebTransportClient {
+                client_addr,
+                server_addr: addr,
+                #[cfg(target_family = "wasm")]
+                certificate_digest: cert_digest,
+            };
+            addr
+        }
+        // netcode sends to the server addresses in the connect token, nothing to set.
+        client::ClientTransport::UdpSocket(_) => server_addr(GameTransport::Udp)?,
+        client::ClientTransport::WebSocketClient {
+            server_addr: ws_server_addr,
+        } => {
+            *ws_server_addr = server_addr(GameTransport::WebSocket)?;
+            *ws_server_addr
+        }
+        other => return Err(format!("Unsupported transport: {other:?}")),
+    };
+    Ok(addr)
+}
+
 fn connect_client(mut commands: Commands, mut next_state: ResMut<NextState<BevygapClientState>>) {
     info!("Connecting to server...");
+    commands.remove_resource::<BevygapRequestAttempts>();
     commands.connect_client();
     next_state.set(BevygapClientState::Finished);
 }
@@ -1,22 +1,93 @@
+use crate::beacons::BeaconSocket;
 use crate::prelude::*;
+use crate::BevygapRequestAttempts;
 use bevy::ecs::world::Command;
 use bevy::prelude::*;
+use bevy_nfws::prelude::*;
+use bevygap_shared::protocol::LobbyCommand;
+use bevygap_shared::wire::{Envelope, KIND_LOBBY_COMMAND};
 
 struct BevygapConnectCommand;
 
 impl Command for BevygapConnectCommand {
     fn apply(self, world: &mut World) {
+        // a fresh request, not a reconnect or a retry
+        world.remove_resource::<BevygapRequestAttempts>();
+        world.resource_mut::<BevygapClientConfig>().reconnect_secret = None;
         let mut s = world.resource_mut::<NextState<BevygapClientState>>();
         s.set(BevygapClientState::Request);
     }
 }
 
+struct BevygapReconnectCommand;
+
+impl Command for BevygapReconnectCommand {
+    fn apply(self, world: &mut World) {
+        let Some(secret) = world.get_resource::<BevygapReconnectSecret>().cloned() else {
+            warn!("No reconnect secret, requesting a new session instead");
+            BevygapConnectCommand.apply(world);
+            return;
+        };
+        world.remove_resource::<BevygapRequestAttempts>();
+        world.resource_mut::<BevygapClientConfig>().reconnect_secret = Some(secret.0);
+        let mut s = world.resource_mut::<NextState<BevygapClientState>>();
+        s.set(BevygapClientState::Request);
+    }
+}
+
+struct BevygapStartLobbyCommand;
+
+impl Command for BevygapStartLobbyCommand {
+    fn apply(self, world: &mut World) {
+        let envelope = Envelope::new(KIND_LOBBY_COMMAND, &LobbyCommand::Start, None).unwrap();
+        let format = world.resource::<BevygapClientConfig>().wire_format;
+        // the matchmaker websocket is still open while we wait in the lobby.
+        let mut q = world.query_filtered::<&mut NfwsHandle, Without<BeaconSocket>>();
+        for mut nfws in q.iter_mut(world) {
+            crate::send_envelope(&mut nfws, &envelope, format);
+        }
+    }
+}
+
+struct BevygapCancelCommand;
+
+impl Command for BevygapCancelCommand {
+    fn apply(self, world: &mut World) {
+        let mut q = world.query_filtered::<Entity, (With<NfwsHandle>, Without<BeaconSocket>)>();
+        let entities: Vec<Entity> = q.iter(world).collect();
+        for entity in entities {
+            if let Some(mut nfws) = world.get_mut::<NfwsHandle>(entity) {
+                nfws.close();
+            }
+            world.despawn(entity);
+        }
+        world.remove_resource::<BevygapRequestAttempts>();
+        let mut s = world.resource_mut::<NextState<BevygapClientState>>();
+        s.set(BevygapClientState::Dormant);
+    }
+}
+
 pub trait BevygapConnectExt {
     fn bevygap_connect_client(&mut self);
+    /// Rejoin the server we were last connected to, if it's still running.
+    fn bevygap_reconnect_client(&mut self);
+    /// Start the lobby we're hosting, so everyone in it gets a server.
+    fn bevygap_start_lobby(&mut self);
+    /// Stop waiting on the matchmaker (including any retries), and go back to `Dormant`.
+    fn bevygap_cancel_request(&mut self);
 }
 
 impl<'w, 's> BevygapConnectExt for Commands<'w, 's> {
     fn bevygap_connect_client(&mut self) {
         self.add(BevygapConnectCommand);
     }
+    fn bevygap_reconnect_client(&mut self) {
+        self.add(BevygapReconnectCommand);
+    }
+    fn bevygap_start_lobby(&mut self) {
+        self.add(BevygapStartLobbyCommand);
+    }
+    fn bevygap_cancel_request(&mut self) {
+        self.add(BevygapCancelCommand);
+    }
 }
@@ -0,0 +1,104 @@
+//! Edgegap's location beacons, which clients measure their latency to.
+//!
+//! matchmaker_httpd asks for the beacon list on "matchmaker.beacons", and passes it on to
+//! clients. Clients that probed the beacons send their best round trip times with their
+//! session request, and the beacon with the lowest one is used as the client's location
+//! when placing the session, instead of wherever their IP geolocates to.
+use crate::session_backend::ClientLocation;
+use crate::session_request_streamer::SessionRequest;
+use crate::MatchmakerState;
+use bevygap_shared::names::MATCHMAKER_BEACONS;
+use bevygap_shared::protocol::Beacon;
+use futures::StreamExt;
+use log::*;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Beacons rarely change, so the list is only fetched from the backend this often.
+const BEACON_LIST_TTL: Duration = Duration::from_secs(600);
+/// Only this many of a client's latencies are looked at.
+const MAX_LATENCIES: usize = 5;
+
+/// The backend's beacon list, and when we fetched it.
+#[derive(Clone, Default)]
+pub(crate) struct BeaconCache {
+    beacons: Arc<Mutex<Option<(Instant, Vec<Beacon>)>>>,
+}
+
+impl BeaconCache {
+    /// If the list can't be refreshed, the stale one is better than nothing.
+    pub(crate) async fn get(&self, state: &MatchmakerState) -> Vec<Beacon> {
+        let mut cached = self.beacons.lock().await;
+        if let Some((fetched, beacons)) = cached.as_ref() {
+            if fetched.elapsed() < BEACON_LIST_TTL {
+                return beacons.clone();
+            }
+        }
+        match state.backend().beacons().await {
+            Ok(beacons) => {
+                info!("Fetched {} beacons", beacons.len());
+                *cached = Some((Instant::now(), beacons.clone()));
+                beacons
+            }
+            Err(e) => {
+                warn!("Unable to fetch beacons: {e}");
+                cached
+                    .as_ref()
+                    .map(|(_, beacons)| beacons.clone())
+                    .unwrap_or_default()
+            }
+        }
+    }
+}
+
+/// Where the client seems to be, if they sent latencies for any beacons we know.
+pub(crate) async fn client_location(
+    state: &MatchmakerState,
+    session_request: &SessionRequest,
+) -> Option<ClientLocation> {
+    let latencies = session_request.latencies();
+    if latencies.is_empty() {
+        return None;
+    }
+    let beacons = state.beacons.get(state).await;
+    let (latency, beacon) = latencies
+        .iter()
+        .take(MAX_LATENCIES)
+        .filter_map(|latency| {
+            let beacon = beacons.iter().find(|b| b.host == latency.host)?;
+            Some((latency, beacon))
+        })
+        .min_by_key(|(latency, _)| latency.rtt_ms)?;
+    info!(
+        "Client {} is closest to the {}, {} beacon ({}ms)",
+        session_request.client_ip, beacon.city, beacon.country, latency.rtt_ms
+    );
+    Some(ClientLocation {
+        ip: session_request.client_ip.clone(),
+        latitude: beacon.latitude,
+        longitude: beacon.longitude,
+    })
+}
+
+/// Replies to beacon list requests from matchmaker_httpd, with a json array of [`Beacon`].
+pub(crate) async fn beacon_list_responder(
+    state: &MatchmakerState,
+) -> Result<(), async_nats::Error> {
+    let client = state.nats_client();
+    let subject = state.nats.namespace().subject(MATCHMAKER_BEACONS);
+    info!("Listening for beacon list requests on '{subject}'");
+    let mut sub = client
+        .queue_subscribe(subject, "matchmaker".to_string())
+        .await?;
+    while let Some(message) = sub.next().await {
+        let Some(reply_to) = message.reply else {
+            warn!("Beacon list request with no reply-to, discarding");
+            continue;
+        };
+        let beacons = state.beacons.get(state).await;
+        let payload = serde_json::to_vec(&beacons).unwrap();
+        client.publish(reply_to, payload.into()).await?;
+    }
+    Ok(())
+}
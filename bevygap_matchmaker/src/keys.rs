@@ -0,0 +1,184 @@
+//! Lightyear private keys, used to sign connect tokens.
+//!
+//! A key can be given directly, or loaded with file:PATH, env:VAR or kv:BUCKET/KEY (a NATS KV
+//! secret). Keys are 32 bytes, as hex, base64, or comma separated decimals.
+//!
+//! To rotate keys, start the matchmaker with the new key, and the old one as
+//! --lightyear-previous-private-key (or --app-previous-private-key for an --app's own key).
+//! Lightyear gameservers only have one key, so they report which one they were started with, and
+//! gameservers still on the previous key get tokens signed with it, until
+//! --key-rotation-window-seconds after the rotation started. The first matchmaker to start with a
+//! new key pair records when that was in the key_rotations KV, so restarts don't extend the
+//! window. Everything else, including new deployments, gets tokens signed with the new key.
+use crate::MatchmakerState;
+use base64::prelude::*;
+use bevygap_shared::nats::BevygapNats;
+use bevygap_shared::protocol::private_key_id;
+use lightyear::connection::netcode::PRIVATE_KEY_BYTES;
+use log::*;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub(crate) type PrivateKey = [u8; PRIVATE_KEY_BYTES];
+
+/// Loads a key from wherever `source` says, see the module docs.
+pub(crate) async fn load_private_key(
+    source: &str,
+    nats: &BevygapNats,
+) -> Result<PrivateKey, String> {
+    let source = source.trim();
+    if let Some(path) = source.strip_prefix("file:") {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can't read private key file {path}: {e}"))?;
+        parse_private_key(&contents)
+    } else if let Some(var) = source.strip_prefix("env:") {
+        let contents =
+            std::env::var(var).map_err(|e| format!("Can't read private key env {var}: {e}"))?;
+        parse_private_key(&contents)
+    } else if let Some(location) = source.strip_prefix("kv:") {
+        let (bucket, key) = location
+            .split_once('/')
+            .ok_or_else(|| format!("Expected kv:BUCKET/KEY, got kv:{location}"))?;
+        let jetstream = async_nats::jetstream::new(nats.client());
+        let kv = jetstream
+            .get_key_value(bucket)
+            .await
+            .map_err(|e| format!("Can't open KV bucket {bucket}: {e}"))?;
+        let value = kv
+            .get(key)
+            .await
+            .map_err(|e| format!("Can't read private key from KV {bucket}/{key}: {e}"))?
+            .ok_or_else(|| format!("No private key in KV {bucket}/{key}"))?;
+        parse_private_key(&String::from_utf8_lossy(&value))
+    } else {
+        parse_private_key(source)
+    }
+}
+
+/// Parses 32 bytes from hex, base64, or the format 1,2,3,4..
+/// An empty string is the all-zero key, which is only allowed with --insecure-dev-key.
+pub(crate) fn parse_private_key(key: &str) -> Result<PrivateKey, String> {
+    let key = key.trim();
+    let bytes: Vec<u8> = if key.is_empty() {
+        vec![0u8; PRIVATE_KEY_BYTES]
+    } else if key.contains(',') {
+        key.chars()
+            .filter(|c| c.is_ascii_digit() || *c == ',')
+            .collect::<String>()
+            .split(',')
+            .map(|s| {
+                s.parse::<u8>()
+                    .map_err(|_| format!("Failed to parse number '{s}' in private key"))
+            })
+            .collect::<Result<_, _>>()?
+    } else if key.len() == PRIVATE_KEY_BYTES * 2 && key.chars().all(|c| c.is_ascii_hexdigit()) {
+        (0..key.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&key[i..i + 2], 16).unwrap())
+            .collect()
+    } else {
+        BASE64_STANDARD
+            .decode(key)
+            .map_err(|_| "Private key isn't hex, base64 or comma separated numbers".to_string())?
+    };
+    let len = bytes.len();
+    bytes
+        .try_into()
+        .map_err(|_| format!("Private key must be {PRIVATE_KEY_BYTES} bytes, got {len}"))
+}
+
+pub(crate) fn is_zero_key(key: &PrivateKey) -> bool {
+    key.iter().all(|b| *b == 0)
+}
+
+/// The key we sign with, and the one we're rotating away from, if any.
+#[derive(Debug, Clone)]
+pub(crate) struct Keyring {
+    current: PrivateKey,
+    /// The previous key and its id, and when we stop using it.
+    previous: Option<(PrivateKey, String, SystemTime)>,
+}
+
+impl Keyring {
+    pub(crate) fn new(current: PrivateKey) -> Self {
+        Self {
+            current,
+            previous: None,
+        }
+    }
+
+    /// Keeps signing tokens with `previous` for gameservers that use it, until `window` after
+    /// the rotation from `previous` to our current key started.
+    pub(crate) async fn with_previous(
+        mut self,
+        previous: PrivateKey,
+        window: Duration,
+        nats: &BevygapNats,
+    ) -> Result<Self, String> {
+        let key_id = private_key_id(&previous);
+        let started = rotation_started(nats, &key_id, &private_key_id(&self.current)).await?;
+        let until = started + window;
+        if SystemTime::now() > until {
+            info!("Rotation from private key {key_id} finished, not using the previous key");
+        }
+        self.previous = Some((previous, key_id, until));
+        Ok(self)
+    }
+
+    pub(crate) fn current(&self) -> &PrivateKey {
+        &self.current
+    }
+
+    /// The key to sign tokens for this deployment with.
+    pub(crate) async fn signing_key(
+        &self,
+        state: &MatchmakerState,
+        request_id: Option<&str>,
+    ) -> PrivateKey {
+        let (Some((previous, previous_id, until)), Some(request_id)) = (&self.previous, request_id)
+        else {
+            return self.current;
+        };
+        if SystemTime::now() > *until {
+            return self.current;
+        }
+        match state.nats.kv_server_key_ids().get(request_id).await {
+            Ok(Some(key_id)) if key_id.as_ref() == previous_id.as_bytes() => {
+                info!("Deployment {request_id} uses the previous private key, signing with it");
+                *previous
+            }
+            Ok(_) => self.current,
+            Err(e) => {
+                warn!("Unable to look up private key id of {request_id}: {e}");
+                self.current
+            }
+        }
+    }
+}
+
+/// When the rotation from one key to the other started. The first matchmaker to see the key
+/// pair records the time, and everyone after uses it.
+async fn rotation_started(
+    nats: &BevygapNats,
+    previous_id: &str,
+    current_id: &str,
+) -> Result<SystemTime, String> {
+    let kv = nats.kv_key_rotations();
+    let key = format!("{previous_id}.{current_id}");
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    // fails if the rotation was already recorded, which is fine.
+    if kv.create(&key, now.to_string().into()).await.is_ok() {
+        info!("Started private key rotation from {previous_id} to {current_id}");
+    }
+    let value = kv
+        .get(&key)
+        .await
+        .map_err(|e| format!("Can't read key rotation {key} from KV: {e}"))?
+        .ok_or_else(|| format!("No key rotation {key} in KV"))?;
+    let secs = String::from_utf8_lossy(&value)
+        .parse::<u64>()
+        .map_err(|_| format!("Invalid start time for key rotation {key}"))?;
+    Ok(UNIX_EPOCH + Duration::from_secs(secs))
+}
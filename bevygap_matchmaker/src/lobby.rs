@@ -0,0 +1,492 @@
+//! Named lobbies, kept by the matchmaker in NATS and mirrored in the backend's lobbies
+//! (Edgegap's lobbies API).
+//!
+//! The host creates a lobby by name, others join it by name, and the host starts it when
+//! everyone's there. Starting deploys the backend's lobby and creates one session for all the
+//! members, like a full party, and every member gets a connect token for it.
+//!
+//! Lobby state, including each member's IP and where to send them progress, lives in the
+//! lobbies KV bucket, and lobby events are published on "lobby.{name}", so members can be
+//! waiting on any matchmaker instance. Each member's request task also listens on the
+//! responder's control subject, where matchmaker_httpd forwards the host's commands, and
+//! says when a member's websocket closes. Members who go away are dropped from the lobby, and
+//! if the host goes away, the lobby is closed.
+//!
+//! Once started, a lobby's session is cleaned up like any other once its server empties.
+//! The delete worker then calls [`session_ended`], which terminates the backend's lobby and
+//! forgets it, so the name can be reused.
+use crate::session_backend::{BackendError, ClientLocation};
+use crate::session_request_streamer::*;
+use crate::{AppEntry, MatchmakerState};
+use async_nats::jetstream::kv::{CreateErrorKind, Operation};
+use async_nats::Subscriber;
+use bevygap_shared::protocol::*;
+use futures::StreamExt;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+/// Most players we'll let into one lobby.
+pub const MAX_LOBBY_SIZE: u8 = 32;
+/// How long a lobby can stay open before the host starts it.
+pub const LOBBY_OPEN_SECONDS: u64 = 600;
+
+/// A player waiting in a lobby.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LobbyMember {
+    client_ip: String,
+    location: Option<ClientLocation>,
+    /// The member's responder subject, for sending them progress while the session starts.
+    reply_to: String,
+}
+
+/// What we keep in the lobbies KV, by lobby name.
+#[derive(Serialize, Deserialize, Debug)]
+struct LobbyState {
+    app_name: String,
+    app_version: String,
+    members: Vec<LobbyMember>,
+    started: bool,
+    /// The lobby's session, once it's started.
+    #[serde(default)]
+    session_id: Option<String>,
+}
+
+/// Published on "lobby.{name}" to every member's request task.
+#[derive(Serialize, Deserialize, Debug)]
+enum LobbyEvent {
+    Members(u8),
+    Started(ReadySession),
+    Closed(u16, String),
+}
+
+/// Started lobbies are also stored by session id, so they can be terminated when their
+/// session is deleted. Lobby names can't contain '.', so these keys can't clash.
+fn session_key(session_id: &str) -> String {
+    format!("session.{session_id}")
+}
+
+fn events_subject(state: &MatchmakerState, name: &str) -> String {
+    state.nats.namespace().lobby(name)
+}
+
+/// Lobby names end up in NATS subjects and KV keys, so keep them simple.
+fn validate_name(name: &str) -> Result<(), MyError> {
+    let valid = !name.is_empty()
+        && name.len() <= 30
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if valid {
+        Ok(())
+    } else {
+        Err(MyError::Bevygap(
+            MatchmakerErrorKind::BadRequest,
+            "Lobby names must be 1-30 letters, numbers, '_' or '-'".into(),
+        ))
+    }
+}
+
+async fn subscribe(state: &MatchmakerState, subject: String) -> Result<Subscriber, MyError> {
+    state
+        .nats_client()
+        .subscribe(subject)
+        .await
+        .map_err(|e| MyError::Nats(Box::new(e)))
+}
+
+async fn publish_event(
+    state: &MatchmakerState,
+    name: &str,
+    event: &LobbyEvent,
+) -> Result<(), MyError> {
+    let payload = serde_json::to_vec(event).unwrap();
+    state
+        .nats_client()
+        .publish(events_subject(state, name), payload.into())
+        .await?;
+    Ok(())
+}
+
+/// Handles the lobby part of a session request: creates or joins the lobby, and waits until
+/// the lobby is started and has a session.
+pub(crate) async fn lobby_session(
+    state: &MatchmakerState,
+    app: &AppEntry,
+    request: LobbyRequest,
+    client_ip: String,
+    location: Option<ClientLocation>,
+    responder: &ChunkResponder,
+) -> Result<ReadySession, MyError> {
+    let member = LobbyMember {
+        client_ip,
+        location,
+        reply_to: responder.reply_subject().to_string(),
+    };
+    match request {
+        LobbyRequest::Create { name } => {
+            validate_name(&name)?;
+            // subscribe before the lobby exists, so we can't miss anything.
+            let events = subscribe(state, events_subject(state, &name)).await?;
+            let control = responder.subscribe_control().await?;
+            create_lobby(state, app, &name, member).await?;
+            info!("Lobby {name} created for {app}");
+            responder
+                .send(SessionRequestFeedback::LobbyUpdate {
+                    name: name.clone(),
+                    members: 1,
+                    host: true,
+                })
+                .await?;
+            let result = wait_for_lobby(state, app, &name, events, control, true, responder).await;
+            if let Err(e) = &result {
+                let (kind, msg) = e.kind_and_message();
+                abandon_lobby(state, &name, kind.code(), msg).await;
+            }
+            result
+        }
+        LobbyRequest::Join { name } => {
+            validate_name(&name)?;
+            let events = subscribe(state, events_subject(state, &name)).await?;
+            let control = responder.subscribe_control().await?;
+            let members = join_lobby(state, app, &name, member).await?;
+            info!("Joined lobby {name}, now {members} players");
+            publish_event(state, &name, &LobbyEvent::Members(members)).await?;
+            let result = wait_for_lobby(state, app, &name, events, control, false, responder).await;
+            if result.is_err() {
+                leave_lobby(state, &name, responder.reply_subject()).await;
+            }
+            result
+        }
+    }
+}
+
+async fn create_lobby(
+    state: &MatchmakerState,
+    app: &AppEntry,
+    name: &str,
+    host: LobbyMember,
+) -> Result<(), MyError> {
+    let lobby = LobbyState {
+        app_name: app.app_name.clone(),
+        app_version: app.app_version.clone(),
+        members: vec![host],
+        started: false,
+        session_id: None,
+    };
+    let payload = serde_json::to_vec(&lobby).unwrap();
+    // create fails if the key exists, so this also reserves the name.
+    state
+        .nats
+        .kv_lobbies()
+        .create(name, payload.into())
+        .await
+        .map_err(|e| match e.kind() {
+            CreateErrorKind::AlreadyExists => MyError::Bevygap(
+                MatchmakerErrorKind::BadRequest,
+                format!("Lobby {name} already exists"),
+            ),
+            _ => MyError::Nats(Box::new(e)),
+        })?;
+    if let Err(e) = state.backend().create_lobby(name).await {
+        let _ = state.nats.kv_lobbies().delete(name).await;
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Applies `change` to the lobby's KV state, retrying if someone else changed it meanwhile.
+async fn update_lobby<T>(
+    state: &MatchmakerState,
+    name: &str,
+    change: impl Fn(&mut LobbyState) -> Result<T, MyError>,
+) -> Result<T, MyError> {
+    let kv = state.nats.kv_lobbies();
+    loop {
+        let entry = kv
+            .entry(name)
+            .await
+            .map_err(|e| MyError::Nats(Box::new(e)))?;
+        let Some(entry) = entry.filter(|entry| entry.operation == Operation::Put) else {
+            return Err(MyError::Bevygap(
+                MatchmakerErrorKind::BadRequest,
+                format!("No lobby named {name}"),
+            ));
+        };
+        let mut lobby: LobbyState = serde_json::from_slice(&entry.value).map_err(|e| {
+            MyError::Bevygap(
+                MatchmakerErrorKind::Internal,
+                format!("Bad lobby entry: {e}"),
+            )
+        })?;
+        let result = change(&mut lobby)?;
+        let payload = serde_json::to_vec(&lobby).unwrap();
+        match kv.update(name, payload.into(), entry.revision).await {
+            Ok(_) => return Ok(result),
+            Err(e) => debug!("Lobby {name} changed under us, retrying: {e}"),
+        }
+    }
+}
+
+/// Adds a member, returning how many members the lobby has now.
+async fn join_lobby(
+    state: &MatchmakerState,
+    app: &AppEntry,
+    name: &str,
+    member: LobbyMember,
+) -> Result<u8, MyError> {
+    update_lobby(state, name, |lobby| {
+        if lobby.app_name != app.app_name || lobby.app_version != app.app_version {
+            return Err(MyError::Bevygap(
+                MatchmakerErrorKind::BadRequest,
+                format!(
+                    "Lobby {name} is for {} @ {}",
+                    lobby.app_name, lobby.app_version
+                ),
+            ));
+        }
+        if lobby.started {
+            return Err(MyError::Bevygap(
+                MatchmakerErrorKind::BadRequest,
+                format!("Lobby {name} has already started"),
+            ));
+        }
+        if lobby.members.len() >= MAX_LOBBY_SIZE as usize {
+            return Err(MyError::Bevygap(
+                MatchmakerErrorKind::BadRequest,
+                format!("Lobby {name} is full"),
+            ));
+        }
+        lobby.members.push(member.clone());
+        Ok(lobby.members.len() as u8)
+    })
+    .await
+}
+
+/// Relays lobby events to this member, until the lobby is deployed or closed, or the member
+/// goes away. Only the host's commands are acted on.
+async fn wait_for_lobby(
+    state: &MatchmakerState,
+    app: &AppEntry,
+    name: &str,
+    mut events: Subscriber,
+    mut control: Subscriber,
+    host: bool,
+    responder: &ChunkResponder,
+) -> Result<ReadySession, MyError> {
+    let mut started = false;
+    // members can't tell when the host starts, so give them time for the session too.
+    let deadline = Instant::now()
+        + Duration::from_secs(LOBBY_OPEN_SECONDS + crate::MAX_SESSION_CREATION_SECONDS);
+    let open_deadline = Instant::now() + Duration::from_secs(LOBBY_OPEN_SECONDS);
+    loop {
+        tokio::select! {
+            Some(message) = control.next() => {
+                match RequestControl::decode(&message.payload) {
+                    Ok(RequestControl::Cancel) => {
+                        let msg = if host {
+                            format!("Lobby {name}'s host went away")
+                        } else {
+                            format!("Left lobby {name}")
+                        };
+                        return Err(MyError::Bevygap(MatchmakerErrorKind::BadRequest, msg));
+                    }
+                    Ok(RequestControl::Lobby(LobbyCommand::Start)) if host && !started => {
+                        started = true;
+                        info!("Host is starting lobby {name}");
+                        let state = state.clone();
+                        let app = app.clone();
+                        let name = name.to_string();
+                        tokio::spawn(async move { start_lobby(&state, &app, &name).await });
+                    }
+                    Ok(command) => warn!("Ignoring lobby command for {name}: {command:?}"),
+                    Err(e) => warn!("{e} for lobby {name}"),
+                }
+            }
+            Some(message) = events.next() => {
+                let event = match serde_json::from_slice::<LobbyEvent>(&message.payload) {
+                    Ok(event) => event,
+                    Err(e) => {
+                        warn!("Undecodable lobby event for {name}: {e}");
+                        continue;
+                    }
+                };
+                match event {
+                    LobbyEvent::Members(members) => {
+                        let update = SessionRequestFeedback::LobbyUpdate {
+                            name: name.to_string(),
+                            members,
+                            host,
+                        };
+                        responder.send(update).await?;
+                    }
+                    LobbyEvent::Started(session) => return Ok(session),
+                    LobbyEvent::Closed(code, msg) => {
+                        return Err(MyError::Bevygap(MatchmakerErrorKind::from_code(code), msg))
+                    }
+                }
+            }
+            _ = tokio::time::sleep_until(open_deadline), if host && !started => {
+                return Err(MyError::Bevygap(
+                    MatchmakerErrorKind::Timeout,
+                    format!("Lobby {name} wasn't started in time"),
+                ));
+            }
+            _ = tokio::time::sleep_until(deadline) => {
+                return Err(MyError::Bevygap(MatchmakerErrorKind::Timeout, format!("Lobby {name} timed out")));
+            }
+        }
+    }
+}
+
+/// Removes a member who went away before the lobby started, and tells everyone else.
+async fn leave_lobby(state: &MatchmakerState, name: &str, reply_to: &str) {
+    let members = update_lobby(state, name, |lobby| {
+        if lobby.started {
+            return Ok(None);
+        }
+        lobby.members.retain(|member| member.reply_to != reply_to);
+        Ok(Some(lobby.members.len() as u8))
+    })
+    .await;
+    match members {
+        Ok(Some(members)) => {
+            info!("Left lobby {name}, now {members} players");
+            let _ = publish_event(state, name, &LobbyEvent::Members(members)).await;
+        }
+        Ok(None) => {}
+        Err(e) => debug!("Not leaving lobby {name}: {e}"),
+    }
+}
+
+/// Deploys the lobby, creates one session for everyone in it, and tells every member where
+/// to connect. If it fails, members are told the lobby closed, and it's terminated.
+async fn start_lobby(state: &MatchmakerState, app: &AppEntry, name: &str) {
+    match start_lobby_session(state, app, name).await {
+        Ok(session) => {
+            info!("Lobby {name} is ready: {session:?}");
+            let _ = publish_event(state, name, &LobbyEvent::Started(session)).await;
+        }
+        Err(e) => {
+            error!("Failed to start lobby {name}: {e}");
+            let (kind, msg) = e.kind_and_message();
+            let _ = publish_event(state, name, &LobbyEvent::Closed(kind.code(), msg)).await;
+            terminate_lobby(state, name).await;
+        }
+    }
+}
+
+async fn start_lobby_session(
+    state: &MatchmakerState,
+    app: &AppEntry,
+    name: &str,
+) -> Result<ReadySession, MyError> {
+    // nobody can join once it's started, so these are all the members.
+    let members = update_lobby(state, name, |lobby| {
+        if lobby.started {
+            return Err(MyError::Bevygap(
+                MatchmakerErrorKind::BadRequest,
+                format!("Lobby {name} has already started"),
+            ));
+        }
+        lobby.started = true;
+        Ok(lobby.members.clone())
+    })
+    .await?;
+
+    state.backend().deploy_lobby(name).await?;
+
+    // members may be waiting on other matchmaker instances, so they hear about the session
+    // from the lobby's events. Progress goes straight to their responders.
+    let mut results = Vec::new();
+    let members = members
+        .into_iter()
+        .map(|member| {
+            let (result_tx, result_rx) = oneshot::channel();
+            results.push(result_rx);
+            GroupMember {
+                client_ip: member.client_ip,
+                location: member.location,
+                responder: ChunkResponder::for_subject(state.nats_client(), member.reply_to),
+                result_tx,
+            }
+        })
+        .collect();
+    start_group_session(state, app, &format!("lobby {name}"), None, members).await;
+
+    let Some(result) = results.into_iter().next() else {
+        return Err(MyError::Bevygap(
+            MatchmakerErrorKind::Internal,
+            format!("Lobby {name} has no members"),
+        ));
+    };
+    let session = match result.await {
+        Ok(result) => result.map_err(|(kind, msg)| MyError::Bevygap(kind, msg))?,
+        Err(_) => {
+            return Err(MyError::Bevygap(
+                MatchmakerErrorKind::Internal,
+                format!("No session result for lobby {name}"),
+            ))
+        }
+    };
+
+    // so the lobby is terminated along with its session.
+    let session_id = session.session_id.clone();
+    update_lobby(state, name, |lobby| {
+        lobby.session_id = Some(session_id.clone());
+        Ok(())
+    })
+    .await?;
+    state
+        .nats
+        .kv_lobbies()
+        .put(session_key(&session_id), name.to_string().into())
+        .await
+        .map_err(|e| MyError::Nats(Box::new(e)))?;
+    Ok(session)
+}
+
+/// Closes a lobby that's given up on before it started. Once started, the lobby is terminated
+/// along with its session, when the players leave.
+async fn abandon_lobby(state: &MatchmakerState, name: &str, code: u16, msg: String) {
+    let started = match state.nats.kv_lobbies().get(name).await {
+        Ok(Some(payload)) => serde_json::from_slice::<LobbyState>(&payload)
+            .map(|lobby| lobby.started)
+            .unwrap_or(false),
+        _ => false,
+    };
+    if started {
+        return;
+    }
+    info!("Closing lobby {name}: {msg}");
+    let _ = publish_event(state, name, &LobbyEvent::Closed(code, msg)).await;
+    terminate_lobby(state, name).await;
+}
+
+/// Terminates the backend's lobby, and forgets the lobby so the name can be reused.
+async fn terminate_lobby(state: &MatchmakerState, name: &str) {
+    match state.backend().terminate_lobby(name).await {
+        Ok(()) => info!("Terminated lobby {name}"),
+        Err(BackendError::NotFound(_)) => debug!("Lobby {name} was already terminated"),
+        Err(e) => warn!("Failed to terminate lobby {name}: {e}"),
+    }
+    let _ = state.nats.kv_lobbies().delete(name).await;
+}
+
+/// Called once a session is deleted. If it was a lobby's session, the lobby is terminated.
+pub(crate) async fn session_ended(state: &MatchmakerState, session_id: &str) {
+    let kv = state.nats.kv_lobbies();
+    let key = session_key(session_id);
+    let name = match kv.get(&key).await {
+        Ok(Some(name)) => String::from_utf8_lossy(&name).to_string(),
+        Ok(None) => return,
+        Err(e) => {
+            warn!("Unable to check if session {session_id} was a lobby's: {e}");
+            return;
+        }
+    };
+    terminate_lobby(state, &name).await;
+    let _ = kv.delete(&key).await;
+}
@@ -5,32 +5,50 @@
 // use async_nats::jetstream::stream::StorageType;
 use async_nats::Client;
 use clap::Parser;
-use edgegap_async::apis::applications_api::*;
 use edgegap_async::apis::configuration::*;
 use futures::stream::StreamExt;
-use lightyear::connection::netcode::PRIVATE_KEY_BYTES;
 use log::*;
 use tracing_subscriber::{layer::*, util::*};
 
+use bevygap_shared::names::{Namespace, GAMESERVER_CONTEXTS};
 use bevygap_shared::nats::*;
-
+use bevygap_shared::protocol::GameTransport;
+use std::sync::Arc;
+use std::time::Duration;
+
+mod beacons;
+mod keys;
+mod lobby;
+mod party;
+mod queue;
+mod reconnect;
+mod session_backend;
 mod session_delete_worker;
 mod session_reaper;
 mod session_service;
-
+mod session_webhooks;
+mod warm_pool;
+
+use beacons::*;
+use keys::Keyring;
+use party::Parties;
+use queue::*;
+use session_backend::*;
 use session_delete_worker::*;
 use session_reaper::*;
 use session_service::*;
+use session_webhooks::*;
+use warm_pool::*;
 
 mod session_request_streamer;
 
 pub const MAX_SESSION_CREATION_SECONDS: u64 = 60;
 
-fn edgegap_configuration(_settings: &Settings) -> Configuration {
+fn edgegap_configuration(settings: &Settings) -> Configuration {
     let key =
         std::env::var("EDGEGAP_API_KEY").expect("EDGEGAP_API_KEY environment variable is not set");
     Configuration {
-        base_path: "https://api.edgegap.com/".to_string(),
+        base_path: settings.edgegap_base_url.clone(),
         api_key: Some(ApiKey { prefix: None, key }),
         ..Default::default()
     }
@@ -39,13 +57,34 @@ fn edgegap_configuration(_settings: &Settings) -> Configuration {
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Settings {
+    /// An app to serve, as NAME:VERSION[:PROTOCOL_ID[:PRIVATE_KEY]]. May be repeated to serve
+    /// several apps or versions. Protocol id and private key default to --lightyear-protocol-id
+    /// and --lightyear-private-key. If not given, --app-name and --app-version are used.
+    #[arg(long = "app")]
+    apps: Vec<String>,
     #[arg(long, default_value = "spacepit_server")]
     app_name: String,
     #[arg(long, default_value = "v0.0.1")]
     app_version: String,
-    /// private key, in format 1,2,3,4..  which should be 32 u8s long (for signing lightyear tokens)
-    #[arg(long, default_value = "")]
+    /// private key for signing lightyear tokens, 32 bytes as hex, base64, or 1,2,3,4..
+    /// Use file:PATH, env:VAR or kv:BUCKET/KEY to load it from a file, env var or NATS KV.
+    #[arg(long, env = "LIGHTYEAR_PRIVATE_KEY", default_value = "")]
     lightyear_private_key: String,
+    /// The key we're rotating away from, in the same formats as --lightyear-private-key.
+    /// Gameservers still using it get tokens signed with it, for --key-rotation-window-seconds.
+    #[arg(long)]
+    lightyear_previous_private_key: Option<String>,
+    /// The key an --app with its own private key is rotating away from, as NAME:VERSION=KEY,
+    /// with KEY in the same formats as --lightyear-private-key. May be repeated.
+    #[arg(long)]
+    app_previous_private_key: Vec<String>,
+    /// How long after a key rotation starts to keep signing tokens with the previous key.
+    /// The start is recorded in NATS KV, so restarting the matchmaker doesn't extend it.
+    #[arg(long, default_value = "3600")]
+    key_rotation_window_seconds: u64,
+    /// Allow the all-zero private key, which anyone can forge tokens with. Only for local dev.
+    #[arg(long)]
+    insecure_dev_key: bool,
     /// The lightyear protocol id (u64)
     #[arg(long, default_value = "1982")]
     lightyear_protocol_id: u64,
@@ -53,39 +92,224 @@ pub struct Settings {
     /// (should write to nats for you, see bevygap_webhook_sink)
     #[arg(long, default_value = None)]
     session_webhook_url: Option<String>,
+    /// How often to poll sessions for readiness when a session webhook url is set, as a
+    /// fallback in case the webhook doesn't arrive. Without a webhook, we poll every 200ms.
+    #[arg(long, default_value = "5000")]
+    session_poll_fallback_ms: u64,
+    /// Players per match. Above 1, requests are queued and matched by rating, game mode and
+    /// region (from the "rating", "mode" and "region" fields of the request).
+    #[arg(long, default_value = "1")]
+    match_size: u8,
+    /// Initial rating difference allowed between queued players in the same match
+    #[arg(long, default_value = "100")]
+    rating_window: f64,
+    /// How much the allowed rating difference grows per second of waiting in the queue
+    #[arg(long, default_value = "25")]
+    rating_window_growth: f64,
+    /// How long a player can wait in the queue before giving up
+    #[arg(long, default_value = "120")]
+    max_queue_seconds: u64,
+    /// How long to keep a session after its last player disconnects, so they can reconnect
+    /// with their reconnect secret. 0 deletes sessions as soon as they're empty.
+    #[arg(long, default_value = "30")]
+    reconnect_grace_seconds: u64,
+    /// Keep idle deployments running so sessions start faster, as NAME:VERSION:REGION:SIZE.
+    /// May be repeated for several apps or regions.
+    #[arg(long)]
+    warm_pool: Vec<String>,
+    /// Which of the app version's port mappings each transport uses, as TRANSPORT=NAME, where
+    /// TRANSPORT is webtransport, udp or websocket. May be repeated. Clients are sent the port
+    /// of the first one. If not given, the deployment's first port is used for WebTransport.
+    #[arg(long)]
+    port_mapping: Vec<String>,
+    /// Where gameservers come from: Edgegap, or child processes on this machine
+    #[arg(long, value_enum, default_value_t = BackendKind::Edgegap)]
+    backend: BackendKind,
+    /// Edgegap API base url, can be pointed at edgegap_fake for testing
+    #[arg(long, default_value = "https://api.edgegap.com")]
+    edgegap_base_url: String,
+    /// Gameserver binary to spawn for each session, when using the local backend
+    #[arg(long)]
+    local_gameserver_bin: Option<std::path::PathBuf>,
+    /// Argument passed to local gameservers, may be repeated. {port} is replaced with the game port.
+    #[arg(long)]
+    local_gameserver_arg: Vec<String>,
+    /// The IP local gameservers report, and clients are told to connect to
+    #[arg(long, default_value = "127.0.0.1")]
+    local_public_ip: String,
+    /// NATS connection settings, which otherwise come from NATS_* env vars
+    #[command(flatten)]
+    nats: NatsArgs,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    Edgegap,
+    Local,
+}
+
+/// An app name and version we create sessions for, along with the lightyear settings
+/// needed to make connect tokens for its gameservers.
+#[derive(Debug, Clone)]
+pub(crate) struct AppEntry {
+    pub app_name: String,
+    pub app_version: String,
+    pub protocol_id: u64,
+    pub keys: Keyring,
+}
+
+impl AppEntry {
+    /// The subject session requests for this app arrive on.
+    pub(crate) fn request_subject(&self, namespace: &Namespace) -> String {
+        namespace.matchmaker_request(&self.app_name, &self.app_version)
+    }
+}
+
+impl std::fmt::Display for AppEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} @ {}", self.app_name, self.app_version)
+    }
 }
 
 impl Settings {
-    fn parse_private_key(&self) -> [u8; PRIVATE_KEY_BYTES] {
-        if self.lightyear_private_key.is_empty() {
-            return [0u8; PRIVATE_KEY_BYTES];
+    /// The keys from --lightyear-private-key and --lightyear-previous-private-key.
+    async fn default_keys(&self, nats: &BevygapNats) -> Keyring {
+        let current = keys::load_private_key(&self.lightyear_private_key, nats)
+            .await
+            .unwrap_or_else(|e| panic!("Invalid --lightyear-private-key: {e}"));
+        let mut keyring = Keyring::new(current);
+        if let Some(previous) = &self.lightyear_previous_private_key {
+            let previous = keys::load_private_key(previous, nats)
+                .await
+                .unwrap_or_else(|e| panic!("Invalid --lightyear-previous-private-key: {e}"));
+            keyring = keyring
+                .with_previous(previous, self.key_rotation_window(), nats)
+                .await
+                .unwrap_or_else(|e| panic!("Can't rotate private keys: {e}"));
         }
-        let private_key: Vec<u8> = self
-            .lightyear_private_key
-            .chars()
-            .filter(|c| c.is_ascii_digit() || *c == ',')
-            .collect::<String>()
-            .split(',')
-            .map(|s| {
-                s.parse::<u8>()
-                    .expect("Failed to parse number in private key")
-            })
-            .collect();
+        keyring
+    }
+
+    fn key_rotation_window(&self) -> Duration {
+        Duration::from_secs(self.key_rotation_window_seconds)
+    }
+
+    /// The --app-previous-private-key for this app, if there is one.
+    fn app_previous_key(&self, app_name: &str, app_version: &str) -> Option<&str> {
+        self.app_previous_private_key.iter().find_map(|arg| {
+            let Some((app, key)) = arg.split_once('=') else {
+                panic!("--app-previous-private-key must be NAME:VERSION=KEY, got '{arg}'");
+            };
+            (app == format!("{app_name}:{app_version}")).then_some(key)
+        })
+    }
 
-        if private_key.len() != PRIVATE_KEY_BYTES {
-            panic!(
-                "Private key must contain exactly {} numbers",
-                PRIVATE_KEY_BYTES
-            );
+    /// The apps to serve, from --app, or --app-name and --app-version if there are none.
+    /// Refuses the all-zero private key, unless --insecure-dev-key.
+    async fn app_entries(&self, nats: &BevygapNats) -> Vec<AppEntry> {
+        let default_keys = self.default_keys(nats).await;
+        let mut apps = Vec::new();
+        if self.apps.is_empty() {
+            apps.push(AppEntry {
+                app_name: self.app_name.clone(),
+                app_version: self.app_version.clone(),
+                protocol_id: self.lightyear_protocol_id,
+                keys: default_keys.clone(),
+            });
         }
+        for app in &self.apps {
+            let mut parts = app.splitn(4, ':');
+            let (Some(app_name), Some(app_version)) = (parts.next(), parts.next()) else {
+                panic!("--app must be NAME:VERSION[:PROTOCOL_ID[:PRIVATE_KEY]], got '{app}'");
+            };
+            let protocol_id = parts
+                .next()
+                .map(|id| {
+                    id.parse::<u64>()
+                        .unwrap_or_else(|_| panic!("Invalid protocol id in --app '{app}'"))
+                })
+                .unwrap_or(self.lightyear_protocol_id);
+            let keys = match parts.next() {
+                Some(key) => {
+                    let keyring =
+                        Keyring::new(keys::load_private_key(key, nats).await.unwrap_or_else(|e| {
+                            panic!("Invalid private key in --app '{app}': {e}")
+                        }));
+                    match self.app_previous_key(app_name, app_version) {
+                        Some(previous) => {
+                            let previous = keys::load_private_key(previous, nats)
+                                .await
+                                .unwrap_or_else(|e| {
+                                    panic!("Invalid previous private key for '{app}': {e}")
+                                });
+                            keyring
+                                .with_previous(previous, self.key_rotation_window(), nats)
+                                .await
+                                .unwrap_or_else(|e| panic!("Can't rotate keys for '{app}': {e}"))
+                        }
+                        None => keyring,
+                    }
+                }
+                None => {
+                    if self.app_previous_key(app_name, app_version).is_some() {
+                        panic!("--app '{app}' uses the default private key, so rotate it with --lightyear-previous-private-key instead");
+                    }
+                    default_keys.clone()
+                }
+            };
+            apps.push(AppEntry {
+                app_name: app_name.to_string(),
+                app_version: app_version.to_string(),
+                protocol_id,
+                keys,
+            });
+        }
+        for app in &apps {
+            if keys::is_zero_key(app.keys.current()) {
+                if !self.insecure_dev_key {
+                    panic!("Refusing to sign tokens for {app} with the all-zero private key. Set --lightyear-private-key, or pass --insecure-dev-key for local dev.");
+                }
+                warn!("Using the all-zero private key for {app}, anyone can forge connect tokens!");
+            }
+        }
+        apps
+    }
 
-        let mut bytes = [0u8; PRIVATE_KEY_BYTES];
-        bytes.copy_from_slice(&private_key);
-        bytes
+    /// The warm pools to keep topped up, from --warm-pool.
+    fn warm_pools(&self) -> Vec<PoolSpec> {
+        self.warm_pool
+            .iter()
+            .map(|pool| {
+                PoolSpec::parse(pool).unwrap_or_else(|e| panic!("Invalid --warm-pool: {e}"))
+            })
+            .collect()
     }
 
-    pub fn protocol_id(&self) -> u64 {
-        self.lightyear_protocol_id
+    /// The port mapping name for each transport, from --port-mapping, in the order given.
+    fn port_mappings(&self) -> Vec<(GameTransport, String)> {
+        let mut mappings: Vec<(GameTransport, String)> = Vec::new();
+        for mapping in &self.port_mapping {
+            let (transport, name) = mapping.split_once('=').unwrap_or_else(|| {
+                panic!("Invalid --port-mapping {mapping}, expected TRANSPORT=NAME")
+            });
+            let transport: GameTransport = transport
+                .parse()
+                .unwrap_or_else(|e| panic!("Invalid --port-mapping {mapping}: {e}"));
+            if mappings.iter().any(|(t, _)| *t == transport) {
+                panic!("--port-mapping given twice for {transport}");
+            }
+            mappings.push((transport, name.trim().to_string()));
+        }
+        mappings
+    }
+
+    /// Time between readiness polls while waiting for a session.
+    pub fn session_poll_interval(&self) -> Duration {
+        if self.session_webhook_url.is_some() {
+            Duration::from_millis(self.session_poll_fallback_ms)
+        } else {
+            Duration::from_millis(200)
+        }
     }
 }
 
@@ -94,10 +318,29 @@ async fn watch_for_gameserver_announcements(
 ) -> Result<(), async_nats::Error> {
     info!("Watching for gameserver announcements");
     let client = state.nats_client();
-    let mut subscriber = client.subscribe("gameserver.contexts").await?;
+    let mut subscriber = client
+        .subscribe(state.nats.namespace().subject(GAMESERVER_CONTEXTS))
+        .await?;
 
     while let Some(message) = subscriber.next().await {
         info!("NEW GAMESERVER: {:?}", message);
+        let context: serde_json::Value = match serde_json::from_slice(&message.payload) {
+            Ok(context) => context,
+            Err(e) => {
+                warn!("Ignoring undecodable gameserver context: {e}");
+                continue;
+            }
+        };
+        if let Some(request_id) = context.get("request_id").and_then(|v| v.as_str()) {
+            // warm pool deployments are ready for sessions once they've announced themselves.
+            let location = context.get("location").and_then(|location| {
+                Some((
+                    location.get("latitude")?.as_f64()?,
+                    location.get("longitude")?.as_f64()?,
+                ))
+            });
+            state.warm_pool.announced(request_id, location);
+        }
     }
     info!("Gameserver announcement watcher exiting");
     Ok(())
@@ -106,20 +349,34 @@ async fn watch_for_gameserver_announcements(
 #[derive(Clone)]
 pub(crate) struct MatchmakerState {
     nats: BevygapNats,
-    api_config: Configuration,
+    backend: Arc<dyn SessionBackend>,
+    session_ready_notifier: SessionReadyNotifier,
+    parties: Parties,
+    queue: MatchQueue,
+    warm_pool: WarmPool,
+    beacons: BeaconCache,
     settings: Settings,
-    lypkey: [u8; PRIVATE_KEY_BYTES],
+    apps: Arc<Vec<AppEntry>>,
+    /// From --port-mapping, see [`Settings::port_mappings`].
+    port_mappings: Arc<Vec<(GameTransport, String)>>,
 }
 
 impl MatchmakerState {
     pub(crate) fn nats_client(&self) -> Client {
         self.nats.client()
     }
-    pub(crate) fn configuration(&self) -> &Configuration {
-        &self.api_config
+    pub(crate) fn backend(&self) -> &dyn SessionBackend {
+        self.backend.as_ref()
+    }
+    pub(crate) fn apps(&self) -> &[AppEntry] {
+        self.apps.as_slice()
+    }
+    pub(crate) fn port_mappings(&self) -> &[(GameTransport, String)] {
+        self.port_mappings.as_slice()
     }
-    pub(crate) fn lightyear_private_key(&self) -> [u8; PRIVATE_KEY_BYTES] {
-        self.lypkey
+    /// The app used for requests that don't say which app they're for.
+    pub(crate) fn default_app(&self) -> &AppEntry {
+        &self.apps[0]
     }
 }
 
@@ -127,19 +384,41 @@ impl MatchmakerState {
 async fn main() -> Result<(), async_nats::Error> {
     setup_logging();
     info!("Starting Edgegap Matchmaker");
-    let bgnats = BevygapNats::new_and_connect("matchmaker").await.unwrap();
     let settings = Settings::parse();
-    let lypkey = settings.parse_private_key();
-    let api_config = edgegap_configuration(&settings);
+    let bgnats = BevygapNats::connect(&settings.nats.config("matchmaker")?).await?;
+    let apps = Arc::new(settings.app_entries(&bgnats).await);
+    let port_mappings = Arc::new(settings.port_mappings());
+    let backend: Arc<dyn SessionBackend> = match settings.backend {
+        BackendKind::Edgegap => Arc::new(EdgegapBackend::new(edgegap_configuration(&settings))),
+        BackendKind::Local => Arc::new(LocalProcessBackend::new(
+            settings
+                .local_gameserver_bin
+                .clone()
+                .expect("--local-gameserver-bin is required for the local backend"),
+            settings.local_gameserver_arg.clone(),
+            settings.local_public_ip.clone(),
+            port_mappings
+                .first()
+                .map_or("server".to_string(), |(_, name)| name.clone()),
+            bgnats.clone(),
+        )),
+    };
+    info!("Using session backend: {}", backend.name());
     let mm_state = MatchmakerState {
         nats: bgnats,
-        api_config,
+        backend,
+        session_ready_notifier: SessionReadyNotifier::default(),
+        parties: Parties::default(),
+        queue: MatchQueue::default(),
+        warm_pool: WarmPool::new(settings.warm_pools()),
+        beacons: BeaconCache::default(),
         settings,
-        lypkey,
+        apps,
+        port_mappings,
     };
 
-    // ensure the specified app and version are valid and ready for players.
-    verify_application(&mm_state).await?;
+    // ensure the specified apps and versions are valid and ready for players.
+    verify_applications(&mm_state).await?;
 
     let state = mm_state.clone();
     let _a = tokio::spawn(async move {
@@ -151,6 +430,34 @@ async fn main() -> Result<(), async_nats::Error> {
     let state = mm_state.clone();
     let _b = tokio::spawn(async move { delete_session_worker_supervisor(&state).await });
 
+    if mm_state.settings.match_size > 1 {
+        let state = mm_state.clone();
+        let _matching = tokio::spawn(async move {
+            match matching_loop(&state).await {
+                Ok(_) => info!("Matching loop completed"),
+                Err(e) => error!("Error in matching loop: {}", e),
+            }
+        });
+    }
+
+    if !mm_state.warm_pool.is_empty() {
+        let state = mm_state.clone();
+        let _warm_pool = tokio::spawn(async move {
+            match warm_pool_manager(&state).await {
+                Ok(_) => info!("Warm pool manager completed"),
+                Err(e) => error!("Error in warm pool manager: {}", e),
+            }
+        });
+    }
+
+    let state = mm_state.clone();
+    let _webhooks = tokio::spawn(async move {
+        match session_webhook_listener(&state).await {
+            Ok(_) => info!("Session webhook listener completed"),
+            Err(e) => error!("Error in session webhook listener: {}", e),
+        }
+    });
+
     let state = mm_state.clone();
     let _watcher = tokio::spawn(async move {
         match watch_for_gameserver_announcements(&state).await {
@@ -159,6 +466,14 @@ async fn main() -> Result<(), async_nats::Error> {
         }
     });
 
+    let state = mm_state.clone();
+    let _beacons = tokio::spawn(async move {
+        match beacon_list_responder(&state).await {
+            Ok(_) => info!("Beacon list responder completed"),
+            Err(e) => error!("Error in beacon list responder: {}", e),
+        }
+    });
+
     let state = mm_state.clone();
     let session_service = tokio::spawn(async move {
         match session_request_supervisor(&state).await {
@@ -176,38 +491,19 @@ async fn main() -> Result<(), async_nats::Error> {
     // dbg!(deployments);
 }
 
-async fn verify_application(state: &MatchmakerState) -> Result<(), async_nats::Error> {
-    let config = state.configuration();
-    let settings = &state.settings;
-
-    let app = application_get(config, settings.app_name.as_str())
-        .await
-        .unwrap_or_else(|e| panic!("Edgegap API doesn't know this application name: {e}"));
-
-    info!(
-        "🟢 Application '{}', active: {}, last_updated: {}",
-        app.name, app.is_active, app.last_updated
-    );
-
-    let app_version = app_version_get(
-        config,
-        settings.app_name.as_str(),
-        settings.app_version.as_str(),
-    )
-    .await
-    .unwrap_or_else(|e| panic!("Edgegap API doesn't know this application version: {e}"));
-
-    if app_version.is_active.unwrap_or(false) {
-        info!("🟢 Application version '{}' is active.", app_version.name);
-    } else {
-        error!(
-            "🔴 Application version '{}' is not active, won't be able to create sessions.",
-            app_version.name
-        );
-        // std::process::exit(1);
-    }
-
-    // info!("✅ {} @ {}", settings.app_name, settings.app_version);
+async fn verify_applications(state: &MatchmakerState) -> Result<(), async_nats::Error> {
+    for app in state.apps() {
+        state
+            .backend()
+            .verify_application(app.app_name.as_str(), app.app_version.as_str())
+            .await
+            .unwrap_or_else(|e| panic!("Unable to verify application {app}: {e}"));
+    }
+
+    match state.backend().list_sessions().await {
+        Ok(sessions) => info!("Backend reports {} existing sessions", sessions.len()),
+        Err(e) => warn!("Unable to list existing sessions: {e}"),
+    }
 
     Ok(())
 }
@@ -0,0 +1,244 @@
+//! Parties let several clients get a session together, on the same server.
+//!
+//! The leader's request creates a party and is given a code to share. Other clients send
+//! requests with that code to join. Each request task waits here until the party is full,
+//! then the task of whoever filled it creates one session with every member's IP, and hands
+//! it to all the members. Each member then gets their own connect token as usual.
+//!
+//! Members whose websocket closes before the party is full leave it, when matchmaker_httpd
+//! sends a cancel on their control subject, and everyone else is told.
+use crate::session_backend::ClientLocation;
+use crate::session_request_streamer::*;
+use crate::{AppEntry, MatchmakerState};
+use bevygap_shared::protocol::*;
+use log::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Largest party we'll create a session for.
+pub const MAX_PARTY_SIZE: u8 = 8;
+/// How long members will wait for a party to fill up.
+pub const PARTY_FORM_SECONDS: u64 = 120;
+/// Party codes are typed in by players, so avoid lookalikes like O/0 and I/1.
+const PARTY_CODE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const PARTY_CODE_LEN: usize = 6;
+
+struct Party {
+    /// The app name and version this party is for, members must be requesting the same one.
+    app: (String, String),
+    size: u8,
+    members: Vec<GroupMember>,
+}
+
+impl Party {
+    fn update(&self, code: &str) -> SessionRequestFeedback {
+        SessionRequestFeedback::PartyUpdate {
+            code: code.to_string(),
+            members: self.members.len() as u8,
+            size: self.size,
+        }
+    }
+
+    fn responders(&self) -> Vec<ChunkResponder> {
+        self.members.iter().map(|m| m.responder.clone()).collect()
+    }
+}
+
+/// Parties that are still waiting for members, by party code.
+#[derive(Clone, Default)]
+pub(crate) struct Parties {
+    forming: Arc<Mutex<HashMap<String, Party>>>,
+}
+
+impl Parties {
+    fn new_code(forming: &HashMap<String, Party>) -> String {
+        loop {
+            let code: String = (0..PARTY_CODE_LEN)
+                .map(|_| {
+                    let i = rand::random::<usize>() % PARTY_CODE_CHARS.len();
+                    PARTY_CODE_CHARS[i] as char
+                })
+                .collect();
+            if !forming.contains_key(&code) {
+                return code;
+            }
+        }
+    }
+
+    fn create(&self, app: &AppEntry, size: u8, leader: GroupMember) -> String {
+        let mut forming = self.forming.lock().unwrap();
+        let code = Self::new_code(&forming);
+        let party = Party {
+            app: (app.app_name.clone(), app.app_version.clone()),
+            size,
+            members: vec![leader],
+        };
+        forming.insert(code.clone(), party);
+        code
+    }
+
+    /// Adds a member. Returns the feedback to send everyone, who to send it to, and the
+    /// whole party if it's now full (in which case it's no longer forming).
+    fn join(
+        &self,
+        code: &str,
+        app: &AppEntry,
+        member: GroupMember,
+    ) -> Result<(SessionRequestFeedback, Vec<ChunkResponder>, Option<Party>), MyError> {
+        let mut forming = self.forming.lock().unwrap();
+        let Some(party) = forming.get_mut(code) else {
+            return Err(MyError::Bevygap(
+                MatchmakerErrorKind::BadRequest,
+                format!("No party with code {code}"),
+            ));
+        };
+        if party.app.0 != app.app_name || party.app.1 != app.app_version {
+            return Err(MyError::Bevygap(
+                MatchmakerErrorKind::BadRequest,
+                format!("Party {code} is for {} @ {}", party.app.0, party.app.1),
+            ));
+        }
+        party.members.push(member);
+        let update = party.update(code);
+        let responders = party.responders();
+        let full = if party.members.len() >= party.size as usize {
+            forming.remove(code)
+        } else {
+            None
+        };
+        Ok((update, responders, full))
+    }
+
+    /// Removes a member who went away from a party that's still forming, so the session isn't
+    /// created for someone who won't get it. Returns the feedback to send everyone left, and
+    /// who to send it to, or None if the party isn't forming, ie it's full and has a session on
+    /// the way.
+    fn leave(
+        &self,
+        code: &str,
+        responder: &ChunkResponder,
+    ) -> Option<(SessionRequestFeedback, Vec<ChunkResponder>)> {
+        let mut forming = self.forming.lock().unwrap();
+        let party = forming.get_mut(code)?;
+        party
+            .members
+            .retain(|m| m.responder.reply_subject() != responder.reply_subject());
+        let left = (party.update(code), party.responders());
+        if party.members.is_empty() {
+            info!("Party {code} is empty");
+            forming.remove(code);
+        }
+        Some(left)
+    }
+
+    /// Gives up on a party, if it's still forming. Members still waiting are told it was
+    /// disbanded, when their result senders are dropped.
+    /// Returns false if the party isn't forming, ie it filled up and has a session on the way.
+    fn disband(&self, code: &str) -> bool {
+        let disbanded = self.forming.lock().unwrap().remove(code).is_some();
+        if disbanded {
+            info!("Party {code} disbanded");
+        }
+        disbanded
+    }
+}
+
+/// Tells every member about a change to the party.
+async fn send_update(code: &str, responders: &[ChunkResponder], update: SessionRequestFeedback) {
+    for responder in responders {
+        if let Err(e) = responder.send(update.clone()).await {
+            warn!("Unable to send party {code} update: {e}");
+        }
+    }
+}
+
+/// Handles the party part of a session request: creates or joins the party, and waits
+/// until the party has a ready session.
+pub(crate) async fn party_session(
+    state: &MatchmakerState,
+    app: &AppEntry,
+    request: PartyRequest,
+    client_ip: String,
+    location: Option<ClientLocation>,
+    responder: &ChunkResponder,
+) -> Result<ReadySession, MyError> {
+    let mut control = responder.subscribe_control().await?;
+    let (result_tx, mut result_rx) = oneshot::channel();
+    let member = GroupMember {
+        client_ip,
+        location,
+        responder: responder.clone(),
+        result_tx,
+    };
+
+    let code = match request {
+        PartyRequest::Create { size } => {
+            if !(2..=MAX_PARTY_SIZE).contains(&size) {
+                return Err(MyError::Bevygap(
+                    MatchmakerErrorKind::BadRequest,
+                    format!("Party size must be between 2 and {MAX_PARTY_SIZE}"),
+                ));
+            }
+            let code = state.parties.create(app, size, member);
+            info!("Party {code} created for {app}, size {size}");
+            let update = SessionRequestFeedback::PartyUpdate {
+                code: code.clone(),
+                members: 1,
+                size,
+            };
+            if let Err(e) = responder.send(update).await {
+                state.parties.disband(&code);
+                return Err(e.into());
+            }
+            code
+        }
+        PartyRequest::Join { code } => {
+            let code = code.trim().to_uppercase();
+            let (update, responders, full) = state.parties.join(&code, app, member)?;
+            info!("Joined party {code}: {update}");
+            send_update(&code, &responders, update).await;
+            if let Some(party) = full {
+                let label = format!("party {code}");
+                start_group_session(state, app, &label, None, party.members).await;
+            }
+            code
+        }
+    };
+
+    let form_timeout = Duration::from_secs(PARTY_FORM_SECONDS);
+    let result = tokio::select! {
+        result = &mut result_rx => result,
+        _ = tokio::time::sleep(form_timeout) => {
+            if state.parties.disband(&code) {
+                return Err(MyError::Bevygap(
+                    MatchmakerErrorKind::Timeout,
+                    format!("Party {code} didn't fill up in time"),
+                ));
+            }
+            // it filled up just in time, and the session is being created.
+            result_rx.await
+        }
+        _ = cancelled(&mut control) => {
+            if let Some((update, responders)) = state.parties.leave(&code, responder) {
+                info!("Left party {code}, client went away: {update}");
+                send_update(&code, &responders, update).await;
+                return Err(MyError::Bevygap(
+                    MatchmakerErrorKind::BadRequest,
+                    format!("Left party {code}"),
+                ));
+            }
+            // it filled up just before, and the session is being created.
+            result_rx.await
+        }
+    };
+    match result {
+        Ok(Ok(session)) => Ok(session),
+        Ok(Err((kind, msg))) => Err(MyError::Bevygap(kind, msg)),
+        Err(_) => Err(MyError::Bevygap(
+            MatchmakerErrorKind::BadRequest,
+            format!("Party {code} was disbanded"),
+        )),
+    }
+}
@@ -0,0 +1,455 @@
+//! Ticket-based matchmaking queue.
+//!
+//! When --match-size is more than 1, session requests (that aren't for a party) become tickets
+//! in a queue instead of getting a session straight away. A matching loop groups compatible
+//! tickets into matches, and only a complete match gets a session.
+//!
+//! Tickets are compatible when they're for the same app and game mode, their region hints
+//! don't conflict, and their ratings are within each other's rating window. The window starts
+//! at --rating-window and widens by --rating-window-growth per second of waiting, so players
+//! eventually get a match even if nobody with a similar rating is around.
+//!
+//! Clients whose websocket closes are taken out of the queue, when matchmaker_httpd sends a
+//! cancel on their control subject, so nobody is matched with a player who's gone.
+use crate::session_request_streamer::*;
+use crate::{AppEntry, MatchmakerState, Settings};
+use bevygap_shared::protocol::*;
+use log::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// Rating used for tickets that don't specify one.
+pub const DEFAULT_RATING: f64 = 1000.0;
+/// Game mode used for tickets that don't specify one.
+pub const DEFAULT_MODE: &str = "default";
+/// How often the matching loop runs, and queued clients get a progress report.
+const MATCHING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The attributes of a ticket, taken from the free-form part of the session request.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TicketAttributes {
+    pub rating: f64,
+    pub mode: String,
+    /// Players with different region hints won't be matched together.
+    pub region: Option<String>,
+}
+
+impl TicketAttributes {
+    pub(crate) fn from_obj(
+        obj: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<Self, String> {
+        let rating = match obj.get("rating") {
+            None | Some(serde_json::Value::Null) => DEFAULT_RATING,
+            Some(v) => v
+                .as_f64()
+                .filter(|r| r.is_finite())
+                .ok_or_else(|| format!("rating must be a number, got {v}"))?,
+        };
+        let mode = match obj.get("mode") {
+            None | Some(serde_json::Value::Null) => DEFAULT_MODE.to_string(),
+            Some(v) => v
+                .as_str()
+                .ok_or_else(|| format!("mode must be a string, got {v}"))?
+                .to_string(),
+        };
+        let region = match obj.get("region") {
+            None | Some(serde_json::Value::Null) => None,
+            Some(v) => Some(
+                v.as_str()
+                    .ok_or_else(|| format!("region must be a string, got {v}"))?
+                    .to_string(),
+            ),
+        };
+        Ok(Self {
+            rating,
+            mode,
+            region,
+        })
+    }
+}
+
+/// How tickets are matched, from the settings.
+#[derive(Debug, Clone, Copy)]
+struct MatchRules {
+    match_size: usize,
+    rating_window: f64,
+    rating_window_growth: f64,
+}
+
+impl MatchRules {
+    fn new(settings: &Settings) -> Self {
+        Self {
+            match_size: settings.match_size as usize,
+            rating_window: settings.rating_window,
+            rating_window_growth: settings.rating_window_growth,
+        }
+    }
+}
+
+/// A queued client. Matching only looks at the app and attributes, so tests can use
+/// tickets without a real `GroupMember`.
+struct Ticket<M = GroupMember> {
+    /// Identifies the ticket while the queue isn't locked.
+    id: u64,
+    app: AppEntry,
+    attributes: TicketAttributes,
+    enqueued: Instant,
+    member: M,
+}
+
+impl<M> Ticket<M> {
+    /// How far away another player's rating can be, for this ticket to accept them.
+    fn rating_window(&self, rules: &MatchRules) -> f64 {
+        rules.rating_window + rules.rating_window_growth * self.enqueued.elapsed().as_secs_f64()
+    }
+
+    fn compatible_with(&self, other: &Ticket<M>, rules: &MatchRules) -> bool {
+        let (a, b) = (&self.attributes, &other.attributes);
+        let same_app = self.app.app_name == other.app.app_name
+            && self.app.app_version == other.app.app_version;
+        let regions_ok = match (&a.region, &b.region) {
+            (Some(ra), Some(rb)) => ra == rb,
+            _ => true,
+        };
+        let window = self.rating_window(rules).min(other.rating_window(rules));
+        same_app && a.mode == b.mode && regions_ok && (a.rating - b.rating).abs() <= window
+    }
+}
+
+/// Tickets waiting for a match, oldest first.
+#[derive(Clone, Default)]
+pub(crate) struct MatchQueue {
+    tickets: Arc<Mutex<Vec<Ticket>>>,
+}
+
+/// Puts the client in the queue, and waits until their match has a ready session.
+pub(crate) async fn queue_session(
+    state: &MatchmakerState,
+    app: &AppEntry,
+    session_request: &SessionRequest,
+    responder: &ChunkResponder,
+) -> Result<ReadySession, MyError> {
+    let attributes = TicketAttributes::from_obj(&session_request.obj).map_err(|e| {
+        MyError::Bevygap(
+            MatchmakerErrorKind::BadRequest,
+            format!("Invalid ticket: {e}"),
+        )
+    })?;
+    info!("Queueing ticket for {app}: {attributes:?}");
+    let location = crate::beacons::client_location(state, session_request).await;
+    let mut control = responder.subscribe_control().await?;
+    let (result_tx, mut result_rx) = oneshot::channel();
+    let id = rand::random();
+    let ticket = Ticket {
+        id,
+        app: app.clone(),
+        attributes,
+        enqueued: Instant::now(),
+        member: GroupMember {
+            client_ip: session_request.client_ip.clone(),
+            location,
+            responder: responder.clone(),
+            result_tx,
+        },
+    };
+    state.queue.tickets.lock().unwrap().push(ticket);
+
+    // the matching loop always resolves tickets: with a session, an error, or by
+    // expiring them after --max-queue-seconds.
+    let result = tokio::select! {
+        result = &mut result_rx => result,
+        _ = cancelled(&mut control) => {
+            if cancel_ticket(&mut state.queue.tickets.lock().unwrap(), id) {
+                info!("Ticket cancelled, client went away");
+                return Err(MyError::Bevygap(
+                    MatchmakerErrorKind::BadRequest,
+                    "Ticket cancelled".into(),
+                ));
+            }
+            // it was matched just before, and the session is being created.
+            result_rx.await
+        }
+    };
+    match result {
+        Ok(Ok(session)) => Ok(session),
+        Ok(Err((kind, msg))) => Err(MyError::Bevygap(kind, msg)),
+        Err(_) => Err(MyError::Bevygap(
+            MatchmakerErrorKind::Internal,
+            "Ticket dropped from queue".into(),
+        )),
+    }
+}
+
+/// Periodically groups compatible tickets into matches, and creates sessions for them.
+pub(crate) async fn matching_loop(state: &MatchmakerState) -> Result<(), async_nats::Error> {
+    info!(
+        "Matching loop started, match size {}",
+        state.settings.match_size
+    );
+    let rules = MatchRules::new(&state.settings);
+    let mut interval = tokio::time::interval(MATCHING_INTERVAL);
+    loop {
+        interval.tick().await;
+        let (matches, progress) = {
+            let mut tickets = state.queue.tickets.lock().unwrap();
+            expire_tickets(state, &mut tickets);
+            let matches = find_matches(&rules, &mut tickets);
+            let progress = progress_reports(&rules, &tickets);
+            (matches, progress)
+        };
+
+        for found in matches {
+            let app = found[0].app.clone();
+            // everyone in a match has the same region hint, or none.
+            let region = found.iter().find_map(|t| t.attributes.region.clone());
+            let members = found.into_iter().map(|t| t.member).collect();
+            let state = state.clone();
+            tokio::spawn(async move {
+                start_group_session(&state, &app, "match", region.as_deref(), members).await;
+            });
+        }
+
+        for (responder, feedback) in progress {
+            if let Err(e) = responder.send(feedback).await {
+                warn!("Unable to send queue progress: {e}");
+            }
+        }
+    }
+}
+
+/// Removes a ticket from the queue. Returns false if it's not there, ie it's been matched or
+/// expired already.
+fn cancel_ticket<M>(tickets: &mut Vec<Ticket<M>>, id: u64) -> bool {
+    let queued = tickets.len();
+    tickets.retain(|t| t.id != id);
+    tickets.len() < queued
+}
+
+fn expire_tickets(state: &MatchmakerState, tickets: &mut Vec<Ticket>) {
+    let max_wait = Duration::from_secs(state.settings.max_queue_seconds);
+    let (expired, waiting): (Vec<_>, Vec<_>) = std::mem::take(tickets)
+        .into_iter()
+        .partition(|t| t.enqueued.elapsed() > max_wait);
+    *tickets = waiting;
+    for ticket in expired {
+        info!("Ticket expired in queue: {:?}", ticket.attributes);
+        let _ = ticket.member.result_tx.send(Err((
+            MatchmakerErrorKind::Timeout,
+            "No match found in time".to_string(),
+        )));
+    }
+}
+
+/// Greedily builds matches, giving the longest-waiting tickets first pick.
+/// Matched tickets are removed from the queue.
+fn find_matches<M>(rules: &MatchRules, tickets: &mut Vec<Ticket<M>>) -> Vec<Vec<Ticket<M>>> {
+    let match_size = rules.match_size;
+    // ticket index -> which match it's in
+    let mut assigned: HashMap<usize, usize> = HashMap::new();
+    let mut num_matches = 0;
+    for anchor in 0..tickets.len() {
+        if assigned.contains_key(&anchor) {
+            continue;
+        }
+        let mut group = vec![anchor];
+        for candidate in anchor + 1..tickets.len() {
+            if group.len() == match_size {
+                break;
+            }
+            if assigned.contains_key(&candidate) {
+                continue;
+            }
+            if group
+                .iter()
+                .all(|&i| tickets[i].compatible_with(&tickets[candidate], rules))
+            {
+                group.push(candidate);
+            }
+        }
+        if group.len() == match_size {
+            for i in group {
+                assigned.insert(i, num_matches);
+            }
+            num_matches += 1;
+        }
+    }
+
+    let mut matches: Vec<Vec<Ticket<M>>> = (0..num_matches).map(|_| Vec::new()).collect();
+    let mut waiting = Vec::new();
+    for (i, ticket) in std::mem::take(tickets).into_iter().enumerate() {
+        match assigned.get(&i) {
+            Some(&m) => matches[m].push(ticket),
+            None => waiting.push(ticket),
+        }
+    }
+    *tickets = waiting;
+    matches
+}
+
+fn progress_reports(
+    rules: &MatchRules,
+    tickets: &[Ticket],
+) -> Vec<(ChunkResponder, SessionRequestFeedback)> {
+    tickets
+        .iter()
+        .map(|t| {
+            let feedback = SessionRequestFeedback::ProgressReport(format!(
+                "In queue for {}s, {} players waiting, rating window ±{:.0}",
+                t.enqueued.elapsed().as_secs(),
+                tickets.len(),
+                t.rating_window(rules)
+            ));
+            (t.member.responder.clone(), feedback)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::Keyring;
+
+    const RULES: MatchRules = MatchRules {
+        match_size: 2,
+        rating_window: 100.0,
+        rating_window_growth: 0.0,
+    };
+
+    fn app(version: &str) -> AppEntry {
+        AppEntry {
+            app_name: "spacepit_server".to_string(),
+            app_version: version.to_string(),
+            protocol_id: 1982,
+            keys: Keyring::new([0; 32]),
+        }
+    }
+
+    fn attributes(rating: f64, mode: &str, region: Option<&str>) -> TicketAttributes {
+        TicketAttributes {
+            rating,
+            mode: mode.to_string(),
+            region: region.map(str::to_string),
+        }
+    }
+
+    fn ticket(id: u64, attributes: TicketAttributes) -> Ticket<()> {
+        Ticket {
+            id,
+            app: app("v1"),
+            attributes,
+            enqueued: Instant::now(),
+            member: (),
+        }
+    }
+
+    fn ids(matches: &[Vec<Ticket<()>>]) -> Vec<Vec<u64>> {
+        matches
+            .iter()
+            .map(|m| m.iter().map(|t| t.id).collect())
+            .collect()
+    }
+
+    fn parse(json: serde_json::Value) -> Result<TicketAttributes, String> {
+        TicketAttributes::from_obj(json.as_object().unwrap())
+    }
+
+    #[test]
+    fn attributes_default() {
+        assert_eq!(
+            parse(serde_json::json!({})).unwrap(),
+            attributes(DEFAULT_RATING, DEFAULT_MODE, None)
+        );
+        assert_eq!(
+            parse(serde_json::json!({"rating": null, "mode": null, "region": null})).unwrap(),
+            attributes(DEFAULT_RATING, DEFAULT_MODE, None)
+        );
+    }
+
+    #[test]
+    fn attributes_given() {
+        let json = serde_json::json!({"rating": 1500, "mode": "ctf", "region": "Europe"});
+        assert_eq!(
+            parse(json).unwrap(),
+            attributes(1500.0, "ctf", Some("Europe"))
+        );
+    }
+
+    #[test]
+    fn attributes_invalid() {
+        assert!(parse(serde_json::json!({"rating": "high"})).is_err());
+        assert!(parse(serde_json::json!({"mode": 3})).is_err());
+        assert!(parse(serde_json::json!({"region": ["Europe"]})).is_err());
+    }
+
+    #[test]
+    fn matches_oldest_compatible_tickets() {
+        let mut tickets = vec![
+            ticket(1, attributes(1000.0, "default", None)),
+            ticket(2, attributes(1500.0, "default", None)),
+            ticket(3, attributes(1050.0, "default", None)),
+            ticket(4, attributes(1450.0, "default", None)),
+            ticket(5, attributes(1000.0, "default", None)),
+        ];
+        let matches = find_matches(&RULES, &mut tickets);
+        assert_eq!(ids(&matches), vec![vec![1, 3], vec![2, 4]]);
+        assert_eq!(tickets.len(), 1);
+        assert_eq!(tickets[0].id, 5);
+    }
+
+    #[test]
+    fn only_matches_compatible_tickets() {
+        let mut other_version = ticket(4, attributes(1000.0, "default", None));
+        other_version.app = app("v2");
+        let mut tickets = vec![
+            ticket(1, attributes(1000.0, "default", Some("Europe"))),
+            ticket(2, attributes(1000.0, "ctf", None)),
+            ticket(3, attributes(1000.0, "default", Some("Asia"))),
+            other_version,
+        ];
+        assert!(find_matches(&RULES, &mut tickets).is_empty());
+        assert_eq!(tickets.len(), 4);
+
+        // no region hint is compatible with any region
+        tickets.push(ticket(5, attributes(1000.0, "default", None)));
+        let matches = find_matches(&RULES, &mut tickets);
+        assert_eq!(ids(&matches), vec![vec![1, 5]]);
+    }
+
+    #[test]
+    fn cancelled_tickets_are_not_matched() {
+        let mut tickets = vec![
+            ticket(1, attributes(1000.0, "default", None)),
+            ticket(2, attributes(1000.0, "default", None)),
+            ticket(3, attributes(1000.0, "default", None)),
+        ];
+        assert!(cancel_ticket(&mut tickets, 2));
+        assert!(!cancel_ticket(&mut tickets, 2));
+        let matches = find_matches(&RULES, &mut tickets);
+        assert_eq!(ids(&matches), vec![vec![1, 3]]);
+        // already matched
+        assert!(!cancel_ticket(&mut tickets, 1));
+    }
+
+    #[test]
+    fn rating_window_grows_while_waiting() {
+        let rules = MatchRules {
+            rating_window_growth: 25.0,
+            ..RULES
+        };
+        let mut tickets = vec![
+            ticket(1, attributes(1000.0, "default", None)),
+            ticket(2, attributes(1200.0, "default", None)),
+        ];
+        assert!(find_matches(&rules, &mut tickets).is_empty());
+
+        // both have waited 10s, so their windows are 100 + 250
+        for t in tickets.iter_mut() {
+            t.enqueued -= Duration::from_secs(10);
+        }
+        let matches = find_matches(&rules, &mut tickets);
+        assert_eq!(ids(&matches), vec![vec![1, 2]]);
+        assert!(tickets.is_empty());
+    }
+}
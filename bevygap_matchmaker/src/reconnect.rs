@@ -0,0 +1,171 @@
+//! Lets a player who dropped out of a game get back into the same server.
+//!
+//! Every SessionReady comes with a reconnect secret, stored in the reconnect_secrets KV along
+//! with the session and client_id it was issued for. If the client is disconnected, it can send
+//! a new session request with that secret, and gets a fresh connect token for the same server,
+//! with the same client_id.
+//!
+//! When the last player leaves a session, it's kept for --reconnect-grace-seconds (see the
+//! session_reaper) so there's still a server to come back to.
+use crate::session_request_streamer::*;
+use crate::{AppEntry, MatchmakerState};
+use bevygap_shared::protocol::{GameTransport, MatchmakerErrorKind, PlayerIdentity};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+/// What a reconnect secret lets you rejoin.
+#[derive(Serialize, Deserialize, Debug)]
+struct ReconnectInfo {
+    session_id: String,
+    client_id: u64,
+    app_name: String,
+    app_version: String,
+    ip: IpAddr,
+    #[serde(default)]
+    ports: BTreeMap<GameTransport, u16>,
+    cert_digest: String,
+    #[serde(default)]
+    deployment: Option<String>,
+    /// Kept from the original request, so the new token carries the same identity.
+    #[serde(default)]
+    identity: Option<PlayerIdentity>,
+}
+
+fn new_secret() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// Makes a new reconnect secret for this client in this session.
+pub(crate) async fn store_reconnect_secret(
+    state: &MatchmakerState,
+    app: &AppEntry,
+    session: &ReadySession,
+    client_id: u64,
+    identity: Option<&PlayerIdentity>,
+) -> Result<String, MyError> {
+    let secret = new_secret();
+    let info = ReconnectInfo {
+        session_id: session.session_id.clone(),
+        client_id,
+        app_name: app.app_name.clone(),
+        app_version: app.app_version.clone(),
+        ip: session.ip,
+        ports: session.ports.clone(),
+        cert_digest: session.cert_digest.clone(),
+        deployment: session.deployment.clone(),
+        identity: identity.cloned(),
+    };
+    let payload = serde_json::to_vec(&info).unwrap();
+    state
+        .nats
+        .kv_reconnect_secrets()
+        .put(secret.as_str(), payload.into())
+        .await
+        .map_err(|e| {
+            MyError::Bevygap(
+                MatchmakerErrorKind::Internal,
+                format!("Failed to store reconnect secret: {e}"),
+            )
+        })?;
+    Ok(secret)
+}
+
+/// Sends the client a new connect token for the session their secret was issued for,
+/// if it's still running.
+pub(crate) async fn reconnect_session(
+    state: &MatchmakerState,
+    app: &AppEntry,
+    secret: &str,
+    responder: &ChunkResponder,
+) -> Result<(), MyError> {
+    let entry = state
+        .nats
+        .kv_reconnect_secrets()
+        .get(secret)
+        .await
+        .map_err(|e| {
+            MyError::Bevygap(
+                MatchmakerErrorKind::Internal,
+                format!("Failed to look up reconnect secret: {e}"),
+            )
+        })?;
+    let Some(payload) = entry else {
+        return Err(MyError::Bevygap(
+            MatchmakerErrorKind::BadRequest,
+            "Unknown reconnect secret".into(),
+        ));
+    };
+    let info: ReconnectInfo = serde_json::from_slice(&payload).map_err(|e| {
+        MyError::Bevygap(
+            MatchmakerErrorKind::Internal,
+            format!("Bad reconnect secret entry: {e}"),
+        )
+    })?;
+    if info.app_name != app.app_name || info.app_version != app.app_version {
+        return Err(MyError::Bevygap(
+            MatchmakerErrorKind::BadRequest,
+            format!(
+                "Reconnect secret is for {} @ {}",
+                info.app_name, info.app_version
+            ),
+        ));
+    }
+    if !session_is_alive(state, &info.session_id).await? {
+        let _ = state.nats.kv_reconnect_secrets().delete(secret).await;
+        return Err(MyError::Bevygap(
+            MatchmakerErrorKind::BadRequest,
+            format!("Session {} has ended", info.session_id),
+        ));
+    }
+    if let Some(request_id) = &info.deployment {
+        if deployment_is_full(state, request_id).await {
+            return Err(MyError::Bevygap(
+                MatchmakerErrorKind::NoCapacity,
+                "Server is full".into(),
+            ));
+        }
+    }
+    info!(
+        "Reconnecting client {} to session {}",
+        info.client_id, info.session_id
+    );
+    let session = ReadySession {
+        session_id: info.session_id,
+        ip: info.ip,
+        ports: info.ports,
+        cert_digest: info.cert_digest,
+        deployment: info.deployment,
+    };
+    send_token_for_client(
+        state,
+        app,
+        &session,
+        info.client_id,
+        info.identity.as_ref(),
+        secret.to_string(),
+        responder,
+    )
+    .await
+}
+
+/// A session is still there to reconnect to if it has players, or is in its grace period.
+async fn session_is_alive(state: &MatchmakerState, session_id: &str) -> Result<bool, MyError> {
+    let in_grace = state
+        .nats
+        .kv_session_grace()
+        .get(session_id)
+        .await
+        .map_err(|e| {
+            MyError::Bevygap(
+                MatchmakerErrorKind::Internal,
+                format!("Failed to check session grace: {e}"),
+            )
+        })?
+        .is_some();
+    if in_grace {
+        return Ok(true);
+    }
+    Ok(crate::session_reaper::session_has_connections(state, session_id).await?)
+}
@@ -0,0 +1,173 @@
+//! Abstraction over whatever actually provides gameservers for our sessions.
+//!
+//! In production this is Edgegap, but the matchmaker only talks to the [`SessionBackend`]
+//! trait, so other implementations can be swapped in for local development and tests.
+use async_trait::async_trait;
+use bevygap_shared::protocol::{Beacon, MatchmakerErrorKind};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+mod edgegap;
+mod local;
+
+pub(crate) use edgegap::EdgegapBackend;
+pub(crate) use local::LocalProcessBackend;
+
+/// What we ask the backend for when clients want to play.
+#[derive(Debug, Clone)]
+pub(crate) struct SessionSpec {
+    pub app_name: String,
+    pub app_version: String,
+    /// IPs of the clients that will join this session, used for server placement.
+    pub ip_list: Vec<String>,
+    /// Where the backend should send session status callbacks, if it supports them.
+    pub webhook_url: Option<String>,
+    /// An already running deployment to put the session on, eg one from the warm pool.
+    pub deployment: Option<String>,
+    /// Where clients that probed the beacons seem to be. Used for placement instead of
+    /// geolocating their IP, which might be a proxy or VPN.
+    pub locations: Vec<ClientLocation>,
+}
+
+/// Where a client seems to be: the beacon they had the lowest latency to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ClientLocation {
+    /// As in [`SessionSpec::ip_list`]
+    pub ip: String,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// What we ask the backend for when starting a gameserver ahead of time, for the warm pool.
+#[derive(Debug, Clone)]
+pub(crate) struct DeploySpec {
+    pub app_name: String,
+    pub app_version: String,
+    pub region: String,
+}
+
+/// Backend-agnostic view of a session, as returned when polling.
+#[derive(Debug, Clone)]
+pub(crate) struct SessionInfo {
+    pub session_id: String,
+    /// Human readable status, eg "Ready" or "Waiting"
+    pub status: String,
+    pub ready: bool,
+    /// Seconds since the session was created
+    pub elapsed: i32,
+    /// Present once the session is linked to a gameserver
+    pub deployment: Option<DeploymentInfo>,
+}
+
+/// The gameserver a session is running on.
+#[derive(Debug, Clone)]
+pub(crate) struct DeploymentInfo {
+    /// The deployment's request id, which gameservers report their capacity under.
+    pub request_id: Option<String>,
+    pub public_ip: String,
+    /// Keyed by port mapping name
+    pub ports: HashMap<String, PortInfo>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct PortInfo {
+    pub internal: Option<u16>,
+    pub external: Option<u16>,
+}
+
+#[derive(Debug)]
+pub(crate) enum BackendError {
+    /// The backend refused the request, with what kind of error it was and a message
+    Rejected(MatchmakerErrorKind, String),
+    /// The session doesn't exist, or was already deleted.
+    NotFound(String),
+    /// The session existed, but has already been terminated.
+    Gone(String),
+    /// Transport errors, decoding errors, etc.
+    Other(String),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::Rejected(kind, msg) => write!(f, "rejected ({kind:?}): {msg}"),
+            BackendError::NotFound(msg) => write!(f, "not found: {msg}"),
+            BackendError::Gone(msg) => write!(f, "gone: {msg}"),
+            BackendError::Other(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+#[async_trait]
+pub(crate) trait SessionBackend: Send + Sync + 'static {
+    /// Short name, for logging.
+    fn name(&self) -> &'static str;
+
+    /// Check the app and version exist and can accept sessions.
+    /// Called once on startup.
+    async fn verify_application(
+        &self,
+        _app_name: &str,
+        _app_version: &str,
+    ) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    /// Request a new session, returning its session id.
+    /// The session probably isn't ready yet – poll with [`SessionBackend::poll_session`].
+    async fn create_session(&self, spec: SessionSpec) -> Result<String, BackendError>;
+
+    /// Fetch the current state of a session. Callers poll this until `ready` is true.
+    async fn poll_session(&self, session_id: &str) -> Result<SessionInfo, BackendError>;
+
+    /// Delete a session. Returns NotFound or Gone if there was nothing to delete.
+    async fn delete_session(&self, session_id: &str) -> Result<(), BackendError>;
+
+    /// List sessions known to the backend. Deployment details may be omitted.
+    async fn list_sessions(&self) -> Result<Vec<SessionInfo>, BackendError>;
+
+    /// Start a gameserver with no session yet, returning its deployment id.
+    /// Sessions can be put on it later with [`SessionSpec::deployment`].
+    async fn deploy(&self, _spec: DeploySpec) -> Result<String, BackendError> {
+        Err(self.unsupported("warm pools"))
+    }
+
+    /// Stop a deployment, eg a warm pool gameserver that was never used.
+    /// Returns NotFound or Gone if there was nothing to stop.
+    async fn stop_deployment(&self, _request_id: &str) -> Result<(), BackendError> {
+        Err(self.unsupported("warm pools"))
+    }
+
+    /// Registers a named lobby with the backend's own lobby service, if it has one.
+    /// Members and starting are handled by the matchmaker either way, so this does nothing
+    /// by default.
+    async fn create_lobby(&self, _name: &str) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    /// Deploys the backend's side of a lobby, when the host starts it.
+    async fn deploy_lobby(&self, _name: &str) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    /// Terminates a lobby, once its server empties or it's abandoned.
+    /// Returns NotFound if there was nothing to terminate.
+    async fn terminate_lobby(&self, _name: &str) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    /// Beacons clients can measure their latency to, so sessions can be placed near them.
+    async fn beacons(&self) -> Result<Vec<Beacon>, BackendError> {
+        Ok(Vec::new())
+    }
+
+    fn unsupported(&self, feature: &str) -> BackendError {
+        BackendError::Rejected(
+            MatchmakerErrorKind::BadRequest,
+            format!("The {} backend doesn't support {feature}", self.name()),
+        )
+    }
+}
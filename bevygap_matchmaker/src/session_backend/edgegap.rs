@@ -0,0 +1,497 @@
+use super::*;
+use edgegap_async::apis::applications_api::*;
+use edgegap_async::apis::configuration::Configuration;
+use edgegap_async::apis::deployments_api::{
+    deploy as deployment_create, deployment_delete, DeployError,
+};
+use edgegap_async::apis::lobbies_api::*;
+use edgegap_async::apis::locations_api::location_beacon_list;
+use edgegap_async::apis::sessions_api::*;
+use edgegap_async::apis::Error as EdgegapError;
+use edgegap_async::models::api_model_deploymentfilter::{Field, FilterType};
+use edgegap_async::models::{
+    ApiModelDeploymentfilter, DeployModel, Deployment, GeoIpListModel, LobbyCreatePayload,
+    LobbyDeployPayload, LobbyTerminatePayload, SessionModel,
+};
+use log::*;
+
+/// Creates sessions via the Edgegap API.
+pub(crate) struct EdgegapBackend {
+    config: Configuration,
+}
+
+impl EdgegapBackend {
+    pub(crate) fn new(config: Configuration) -> Self {
+        Self { config }
+    }
+
+    /// Like [`rejected`], but asks Edgegap whether the app version is active when a request
+    /// for it is refused, since the error doesn't say so in any structured way.
+    async fn rejected(
+        &self,
+        status: u16,
+        msg: String,
+        app_name: &str,
+        app_version: &str,
+    ) -> BackendError {
+        if matches!(status, 400 | 409) {
+            match app_version_get(&self.config, app_name, app_version).await {
+                Ok(version) if version.is_active == Some(false) => {
+                    return BackendError::Rejected(MatchmakerErrorKind::AppVersionInactive, msg);
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Unable to check if {app_name} {app_version} is active: {e}"),
+            }
+        }
+        rejected(status, msg)
+    }
+}
+
+#[async_trait]
+impl SessionBackend for EdgegapBackend {
+    fn name(&self) -> &'static str {
+        "edgegap"
+    }
+
+    async fn verify_application(
+        &self,
+        app_name: &str,
+        app_version: &str,
+    ) -> Result<(), BackendError> {
+        let app = application_get(&self.config, app_name).await.map_err(|e| {
+            BackendError::Other(format!(
+                "Edgegap API doesn't know this application name: {e}"
+            ))
+        })?;
+
+        info!(
+            "🟢 Application '{}', active: {}, last_updated: {}",
+            app.name, app.is_active, app.last_updated
+        );
+
+        let app_version = app_version_get(&self.config, app_name, app_version)
+            .await
+            .map_err(|e| {
+                BackendError::Other(format!(
+                    "Edgegap API doesn't know this application version: {e}"
+                ))
+            })?;
+
+        if app_version.is_active.unwrap_or(false) {
+            info!("🟢 Application version '{}' is active.", app_version.name);
+        } else {
+            error!(
+                "🔴 Application version '{}' is not active, won't be able to create sessions.",
+                app_version.name
+            );
+        }
+        Ok(())
+    }
+
+    async fn create_session(&self, spec: SessionSpec) -> Result<String, BackendError> {
+        let mut session_model = SessionModel::new(spec.app_name.clone());
+        session_model.version_name = Some(spec.app_version.clone());
+        session_model.ip_list = Some(spec.ip_list);
+        session_model.webhook_url = spec.webhook_url;
+        session_model.deployment_request_id = spec.deployment;
+        // edgegap places sessions using these coordinates, for these IPs, instead of geolocating.
+        if !spec.locations.is_empty() {
+            session_model.geo_ip_list = Some(
+                spec.locations
+                    .into_iter()
+                    .map(|l| GeoIpListModel::new(l.ip, l.latitude, l.longitude))
+                    .collect(),
+            );
+        }
+        match session_post(&self.config, session_model).await {
+            Ok(post_session) => Ok(post_session.session_id),
+            Err(EdgegapError::ResponseError(e)) => {
+                let status = e.status.as_u16();
+                let msg = match e.entity {
+                    Some(SessionPostError::Status400(ee)) => ee.message,
+                    Some(SessionPostError::Status401(ee)) => ee.message,
+                    Some(SessionPostError::Status409(ee)) => ee.message,
+                    _ => e.content,
+                };
+                Err(self
+                    .rejected(status, msg, &spec.app_name, &spec.app_version)
+                    .await)
+            }
+            Err(e) => Err(BackendError::Other(format!("session_post error: {e}"))),
+        }
+    }
+
+    async fn poll_session(&self, session_id: &str) -> Result<SessionInfo, BackendError> {
+        let session_get = get_session(&self.config, session_id)
+            .await
+            .map_err(|e| BackendError::Other(format!("get session error: {e}")))?;
+        Ok(SessionInfo {
+            session_id: session_get.session_id,
+            status: session_get.status,
+            ready: session_get.ready,
+            elapsed: session_get.elapsed,
+            deployment: session_get.deployment.map(|d| deployment_info(*d)),
+        })
+    }
+
+    async fn delete_session(&self, session_id: &str) -> Result<(), BackendError> {
+        match session_delete(&self.config, session_id).await {
+            Ok(session_delete_response) => {
+                debug!("session_delete response: {:?}", session_delete_response);
+                Ok(())
+            }
+            Err(EdgegapError::ResponseError(resp_content)) => match resp_content.status.as_u16() {
+                404 => Err(BackendError::NotFound(resp_content.content)),
+                // "instance already terminated"
+                410 => Err(BackendError::Gone(resp_content.content)),
+                code => Err(rejected(code, resp_content.content)),
+            },
+            Err(e) => Err(BackendError::Other(format!("session_delete error: {e}"))),
+        }
+    }
+
+    async fn list_sessions(&self) -> Result<Vec<SessionInfo>, BackendError> {
+        let sessions = list_sessions(&self.config)
+            .await
+            .map_err(|e| BackendError::Other(format!("list sessions error: {e}")))?;
+        Ok(sessions
+            .data
+            .unwrap_or_default()
+            .into_iter()
+            .map(|s| SessionInfo {
+                session_id: s.session_id,
+                status: s.status,
+                ready: s.ready,
+                elapsed: 0,
+                deployment: None,
+            })
+            .collect())
+    }
+
+    async fn deploy(&self, spec: DeploySpec) -> Result<String, BackendError> {
+        let mut deploy_model = DeployModel::new(spec.app_name.clone());
+        deploy_model.version_name = Some(spec.app_version.clone());
+        deploy_model.filters = Some(vec![ApiModelDeploymentfilter::new(
+            Field::Region,
+            vec![spec.region],
+            FilterType::Any,
+        )]);
+        deploy_model.tags = Some(vec!["warm-pool".to_string()]);
+        match deployment_create(&self.config, deploy_model).await {
+            Ok(request) => Ok(request.request_id),
+            Err(EdgegapError::ResponseError(e)) => {
+                let status = e.status.as_u16();
+                let msg = match e.entity {
+                    Some(DeployError::Status400(ee)) => ee.message,
+                    Some(DeployError::Status401(ee)) => ee.message,
+                    Some(DeployError::Status409(ee)) => ee.message,
+                    Some(DeployError::Status422(ee)) => ee.message,
+                    _ => e.content,
+                };
+                Err(self
+                    .rejected(status, msg, &spec.app_name, &spec.app_version)
+                    .await)
+            }
+            Err(e) => Err(BackendError::Other(format!("deploy error: {e}"))),
+        }
+    }
+
+    async fn stop_deployment(&self, request_id: &str) -> Result<(), BackendError> {
+        match deployment_delete(&self.config, request_id, None).await {
+            Ok(_) => Ok(()),
+            Err(EdgegapError::ResponseError(resp_content)) => match resp_content.status.as_u16() {
+                404 => Err(BackendError::NotFound(resp_content.content)),
+                410 => Err(BackendError::Gone(resp_content.content)),
+                code => Err(rejected(code, resp_content.content)),
+            },
+            Err(e) => Err(BackendError::Other(format!("deployment_delete error: {e}"))),
+        }
+    }
+
+    async fn beacons(&self) -> Result<Vec<Beacon>, BackendError> {
+        let list = location_beacon_list(&self.config)
+            .await
+            .map_err(|e| BackendError::Other(format!("location_beacon_list error: {e}")))?;
+        Ok(list
+            .locations
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|b| {
+                let port = u16::try_from(b.tcp_port?).ok()?;
+                Some(Beacon {
+                    host: b.host,
+                    port,
+                    city: b.location.city,
+                    country: b.location.country,
+                    latitude: b.location.latitude,
+                    longitude: b.location.longitude,
+                })
+            })
+            .collect())
+    }
+
+    async fn create_lobby(&self, name: &str) -> Result<(), BackendError> {
+        let lobby = lobby_create(&self.config, LobbyCreatePayload::new(name.to_string()))
+            .await
+            .map_err(|e| lobby_error(e, "lobby_create"))?;
+        info!("Created Edgegap lobby {}: {}", lobby.name, lobby.status);
+        Ok(())
+    }
+
+    async fn deploy_lobby(&self, name: &str) -> Result<(), BackendError> {
+        let lobby = lobby_deploy(&self.config, LobbyDeployPayload::new(name.to_string()))
+            .await
+            .map_err(|e| lobby_error(e, "lobby_deploy"))?;
+        info!("Deploying Edgegap lobby {}: {}", lobby.name, lobby.status);
+        Ok(())
+    }
+
+    async fn terminate_lobby(&self, name: &str) -> Result<(), BackendError> {
+        let lobby = lobby_terminate(&self.config, LobbyTerminatePayload::new(name.to_string()))
+            .await
+            .map_err(|e| lobby_error(e, "lobby_terminate"))?;
+        debug!("lobby_terminate response: {lobby:?}");
+        Ok(())
+    }
+}
+
+fn lobby_error<T>(e: EdgegapError<T>, what: &str) -> BackendError {
+    match e {
+        EdgegapError::ResponseError(resp_content) => match resp_content.status.as_u16() {
+            404 => BackendError::NotFound(resp_content.content),
+            code => rejected(code, resp_content.content),
+        },
+        e => BackendError::Other(format!("{what} error: {e}")),
+    }
+}
+
+/// What an error status from the Edgegap API means for the client.
+/// Auth failures are about our API token, not the client, so they count as upstream errors.
+fn rejected(status: u16, msg: String) -> BackendError {
+    let kind = match status {
+        400 | 404 | 409 | 422 => MatchmakerErrorKind::BadRequest,
+        429 => MatchmakerErrorKind::RateLimited,
+        503 => MatchmakerErrorKind::NoCapacity,
+        _ => MatchmakerErrorKind::UpstreamEdgegap,
+    };
+    BackendError::Rejected(kind, msg)
+}
+
+fn deployment_info(deployment: Deployment) -> DeploymentInfo {
+    let ports = deployment
+        .ports
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, port)| {
+            (
+                name,
+                PortInfo {
+                    internal: port.internal.map(|p| p as u16),
+                    external: port.external.map(|p| p as u16),
+                },
+            )
+        })
+        .collect();
+    DeploymentInfo {
+        request_id: Some(deployment.request_id),
+        public_ip: deployment.public_ip,
+        ports,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use edgegap_fake::{FakeEdgegap, FakeEdgegapBuilder};
+    use std::time::Duration;
+
+    const APP: &str = "spacepit_server";
+    const VERSION: &str = "v0.0.1";
+
+    fn spec() -> SessionSpec {
+        SessionSpec {
+            app_name: APP.to_string(),
+            app_version: VERSION.to_string(),
+            ip_list: vec!["81.128.157.123".to_string()],
+            webhook_url: None,
+            deployment: None,
+            locations: Vec::new(),
+        }
+    }
+
+    async fn start(builder: FakeEdgegapBuilder) -> (FakeEdgegap, EdgegapBackend) {
+        let fake = builder.app(APP, VERSION).start().await.unwrap();
+        let backend = EdgegapBackend::new(fake.configuration());
+        (fake, backend)
+    }
+
+    #[tokio::test]
+    async fn session_becomes_ready_and_is_deleted() {
+        let builder = FakeEdgegap::builder()
+            .ready_after(Duration::from_millis(200))
+            .port("server", 6420, 31337);
+        let (fake, backend) = start(builder).await;
+        backend.verify_application(APP, VERSION).await.unwrap();
+
+        let session_id = backend.create_session(spec()).await.unwrap();
+        let session = backend.poll_session(&session_id).await.unwrap();
+        assert!(!session.ready);
+        assert!(session.deployment.is_none());
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let session = backend.poll_session(&session_id).await.unwrap();
+        assert!(session.ready);
+        let deployment = session.deployment.unwrap();
+        assert_eq!(deployment.public_ip, "127.0.0.1");
+        assert_eq!(deployment.ports["server"].internal, Some(6420));
+        assert_eq!(deployment.ports["server"].external, Some(31337));
+        assert!(deployment.request_id.is_some());
+
+        let listed = backend.list_sessions().await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].session_id, session_id);
+
+        backend.delete_session(&session_id).await.unwrap();
+        assert!(fake.is_deleted(&session_id));
+        assert!(backend.list_sessions().await.unwrap().is_empty());
+        assert!(matches!(
+            backend.delete_session(&session_id).await,
+            Err(BackendError::Gone(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn session_never_ready() {
+        let (fake, backend) = start(FakeEdgegap::builder().never_ready()).await;
+        let session_id = backend.create_session(spec()).await.unwrap();
+        for _ in 0..3 {
+            let session = backend.poll_session(&session_id).await.unwrap();
+            assert!(!session.ready);
+            assert!(session.deployment.is_none());
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(fake.make_ready(&session_id));
+        assert!(backend.poll_session(&session_id).await.unwrap().ready);
+    }
+
+    #[tokio::test]
+    async fn session_create_errors() {
+        let (fake, backend) =
+            start(FakeEdgegap::builder().session_post_error(409, "Conflict")).await;
+        let Err(BackendError::Rejected(kind, msg)) = backend.create_session(spec()).await else {
+            panic!("expected a rejection");
+        };
+        assert_eq!(kind, MatchmakerErrorKind::BadRequest);
+        assert_eq!(msg, "Conflict");
+
+        fake.update_behaviour(|b| b.session_post_error = Some((503, "No capacity".into())));
+        assert!(matches!(
+            backend.create_session(spec()).await,
+            Err(BackendError::Rejected(MatchmakerErrorKind::NoCapacity, _))
+        ));
+
+        fake.update_behaviour(|b| b.session_post_error = None);
+        let mut unknown = spec();
+        unknown.app_version = "v9.9.9".to_string();
+        assert!(matches!(
+            backend.create_session(unknown).await,
+            Err(BackendError::Rejected(MatchmakerErrorKind::BadRequest, _))
+        ));
+        assert!(fake.session_ids().is_empty());
+    }
+
+    #[tokio::test]
+    async fn inactive_app_version() {
+        let fake = FakeEdgegap::builder()
+            .inactive_app(APP, VERSION)
+            .start()
+            .await
+            .unwrap();
+        let backend = EdgegapBackend::new(fake.configuration());
+        assert!(matches!(
+            backend.create_session(spec()).await,
+            Err(BackendError::Rejected(
+                MatchmakerErrorKind::AppVersionInactive,
+                _
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn stop_deployment() {
+        let (fake, backend) = start(FakeEdgegap::builder()).await;
+        let session_id = backend.create_session(spec()).await.unwrap();
+        let session = backend.poll_session(&session_id).await.unwrap();
+        let request_id = session.deployment.unwrap().request_id.unwrap();
+
+        backend.stop_deployment(&request_id).await.unwrap();
+        assert!(fake.is_deleted(&session_id));
+        assert!(matches!(
+            backend.stop_deployment(&request_id).await,
+            Err(BackendError::Gone(_))
+        ));
+        assert!(matches!(
+            backend.stop_deployment("nope").await,
+            Err(BackendError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn lobby_lifecycle() {
+        let (fake, backend) = start(FakeEdgegap::builder()).await;
+        backend.create_lobby("ABCD").await.unwrap();
+        assert_eq!(fake.lobby_status("ABCD").as_deref(), Some("WAITING"));
+        assert!(matches!(
+            backend.create_lobby("ABCD").await,
+            Err(BackendError::Rejected(MatchmakerErrorKind::BadRequest, _))
+        ));
+
+        backend.deploy_lobby("ABCD").await.unwrap();
+        assert_eq!(fake.lobby_status("ABCD").as_deref(), Some("READY"));
+
+        backend.terminate_lobby("ABCD").await.unwrap();
+        assert!(fake.lobby_status("ABCD").is_none());
+        assert!(matches!(
+            backend.terminate_lobby("ABCD").await,
+            Err(BackendError::NotFound(_))
+        ));
+        assert!(matches!(
+            backend.deploy_lobby("WXYZ").await,
+            Err(BackendError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn bad_api_key_is_an_upstream_error() {
+        let (fake, _) = start(FakeEdgegap::builder().api_key("right")).await;
+        let mut config = fake.configuration();
+        config.api_key.as_mut().unwrap().key = "wrong".to_string();
+        let backend = EdgegapBackend::new(config);
+        assert!(matches!(
+            backend.create_session(spec()).await,
+            Err(BackendError::Rejected(
+                MatchmakerErrorKind::UpstreamEdgegap,
+                _
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn delete_errors() {
+        let (fake, backend) = start(FakeEdgegap::builder()).await;
+        assert!(matches!(
+            backend.delete_session("nope-S").await,
+            Err(BackendError::NotFound(_))
+        ));
+        let session_id = backend.create_session(spec()).await.unwrap();
+        fake.update_behaviour(|b| b.session_delete_error = Some((500, "Oops".into())));
+        assert!(matches!(
+            backend.delete_session(&session_id).await,
+            Err(BackendError::Rejected(
+                MatchmakerErrorKind::UpstreamEdgegap,
+                _
+            ))
+        ));
+        assert!(!fake.is_deleted(&session_id));
+    }
+}
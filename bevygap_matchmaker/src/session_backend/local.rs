@@ -0,0 +1,292 @@
+//! Runs gameservers as child processes on this machine, for local development.
+//!
+//! Each session gets its own gameserver process, with fake ARBITRIUM_* env vars
+//! (like utils/set-mock-arbitrium-envs.sh) and a free port. The context that the server
+//! plugin would normally fetch from the Edgegap API is written to a file, and
+//! ARBITRIUM_CONTEXT_URL points at it with a file:// url.
+//!
+//! Any `{port}` in the gameserver args is replaced with the allocated port, so the gameserver
+//! can be told where to listen, eg `--local-gameserver-arg=--port={port}`. Gameservers can
+//! also read it from ARBITRIUM_PORTS_MAPPING.
+//!
+//! Sessions are ready once the gameserver reports its transports to NATS, which the server
+//! plugin does after loading its context. Cert digests are keyed by IP though, and every
+//! local gameserver shares the same IP, so with WebTransport only one session should be
+//! starting up at a time.
+use super::*;
+use bevygap_shared::nats::BevygapNats;
+use log::*;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Instant;
+use time::OffsetDateTime;
+use tokio::process::{Child, Command};
+use tokio::sync::Mutex;
+
+/// ARBITRIUM_PORTS_MAPPING for a local gameserver. Nothing is in the way, so the internal
+/// and external ports are the same.
+fn ports_mapping(port_name: &str, port: u16) -> serde_json::Value {
+    serde_json::json!({
+        "ports": {
+            port.to_string(): {
+                "name": port_name,
+                "internal": port,
+                "external": port,
+                "protocol": "UDP",
+            }
+        }
+    })
+}
+
+pub(crate) struct LocalProcessBackend {
+    bin: PathBuf,
+    args: Vec<String>,
+    public_ip: String,
+    /// Named after the first --port-mapping, so the matchmaker finds it.
+    port_name: String,
+    nats: BevygapNats,
+    sessions: Mutex<HashMap<String, LocalSession>>,
+}
+
+struct LocalSession {
+    request_id: String,
+    child: Child,
+    port: u16,
+    spawned_at: OffsetDateTime,
+    started: Instant,
+    context_dir: PathBuf,
+}
+
+impl LocalProcessBackend {
+    pub(crate) fn new(
+        bin: PathBuf,
+        args: Vec<String>,
+        public_ip: String,
+        port_name: String,
+        nats: BevygapNats,
+    ) -> Self {
+        Self {
+            bin,
+            args,
+            public_ip,
+            port_name,
+            nats,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Asks the OS for an unused UDP port. There's a small race between us releasing it
+    /// and the gameserver binding it, which is fine for local development.
+    fn free_port() -> Result<u16, BackendError> {
+        let socket = std::net::UdpSocket::bind("0.0.0.0:0")
+            .map_err(|e| BackendError::Other(format!("unable to find a free port: {e}")))?;
+        let port = socket
+            .local_addr()
+            .map_err(|e| BackendError::Other(format!("unable to find a free port: {e}")))?
+            .port();
+        Ok(port)
+    }
+
+    /// Writes the fake deployment context, returning the directory it's in.
+    fn write_context(&self, request_id: &str, port: u16) -> Result<PathBuf, BackendError> {
+        let context_dir = std::env::temp_dir().join(format!("bevygap-local-{request_id}"));
+        let context = serde_json::json!({
+            "request_id": request_id,
+            "public_ip": self.public_ip,
+            "fqdn": "localhost",
+            "sockets": 100,
+            "location": {
+                "city": "Localhost",
+                "country": "Local",
+                "continent": "Local",
+                "administrative_division": "Local",
+                "timezone": "UTC",
+                "latitude": 0.0,
+                "longitude": 0.0,
+            },
+            "ports": ports_mapping(&self.port_name, port)["ports"],
+        });
+        std::fs::create_dir_all(&context_dir)
+            .and_then(|_| std::fs::write(context_dir.join("context.json"), context.to_string()))
+            .map_err(|e| BackendError::Other(format!("unable to write context file: {e}")))?;
+        Ok(context_dir)
+    }
+
+    fn spawn_gameserver(
+        &self,
+        request_id: &str,
+        port: u16,
+        context_dir: &std::path::Path,
+    ) -> Result<Child, BackendError> {
+        let location = serde_json::json!({
+            "city": "Localhost",
+            "country": "Local",
+            "continent": "Local",
+            "administrative_division": "Local",
+            "timezone": "UTC",
+            "latitude": 0.0,
+            "longitude": 0.0,
+        });
+        let context_url = format!("file://{}", context_dir.join("context.json").display());
+
+        info!(
+            "Spawning local gameserver {} for request {request_id} on port {port}",
+            self.bin.display()
+        );
+        Command::new(&self.bin)
+            .args(
+                self.args
+                    .iter()
+                    .map(|arg| arg.replace("{port}", &port.to_string())),
+            )
+            .env("ARBITRIUM_REQUEST_ID", request_id)
+            .env(
+                "ARBITRIUM_DELETE_URL",
+                format!("http://localhost/v1/self/stop/{request_id}/0"),
+            )
+            .env("ARBITRIUM_DELETE_TOKEN", "local")
+            .env("ARBITRIUM_DEPLOYMENT_LOCATION", location.to_string())
+            .env("ARBITRIUM_CONTEXT_URL", context_url)
+            .env("ARBITRIUM_CONTEXT_TOKEN", "local")
+            .env("ARBITRIUM_PUBLIC_IP", &self.public_ip)
+            .env(
+                "ARBITRIUM_PORTS_MAPPING",
+                ports_mapping(&self.port_name, port).to_string(),
+            )
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| {
+                BackendError::Other(format!(
+                    "unable to spawn gameserver {}: {e}",
+                    self.bin.display()
+                ))
+            })
+    }
+
+    /// The gameserver reports its transports once it has loaded its context, so we use a
+    /// fresh report for its deployment as the signal that it's ready for players.
+    async fn has_reported_in(&self, request_id: &str, since: OffsetDateTime) -> bool {
+        match self.nats.kv_server_transports().entry(request_id).await {
+            Ok(Some(entry)) => entry.created >= since,
+            _ => false,
+        }
+    }
+}
+
+#[async_trait]
+impl SessionBackend for LocalProcessBackend {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn create_session(&self, spec: SessionSpec) -> Result<String, BackendError> {
+        let request_id = format!("{:012x}", rand::random::<u64>() & 0xffff_ffff_ffff);
+        let session_id = format!("{request_id}-S");
+        let port = Self::free_port()?;
+        let context_dir = self.write_context(&request_id, port)?;
+        let child = self.spawn_gameserver(&request_id, port, &context_dir)?;
+        info!(
+            "Local session {session_id} for {} {} from {:?}",
+            spec.app_name, spec.app_version, spec.ip_list
+        );
+        self.sessions.lock().await.insert(
+            session_id.clone(),
+            LocalSession {
+                request_id,
+                child,
+                port,
+                spawned_at: OffsetDateTime::now_utc(),
+                started: Instant::now(),
+                context_dir,
+            },
+        );
+        Ok(session_id)
+    }
+
+    async fn poll_session(&self, session_id: &str) -> Result<SessionInfo, BackendError> {
+        let mut sessions = self.sessions.lock().await;
+        let Some(session) = sessions.get_mut(session_id) else {
+            return Err(BackendError::NotFound(session_id.to_string()));
+        };
+        if let Ok(Some(exit_status)) = session.child.try_wait() {
+            let session = sessions.remove(session_id).unwrap();
+            let _ = std::fs::remove_dir_all(&session.context_dir);
+            return Err(BackendError::Gone(format!(
+                "gameserver for {session_id} exited: {exit_status}"
+            )));
+        }
+        let ready = self
+            .has_reported_in(&session.request_id, session.spawned_at)
+            .await;
+        let mut ports = HashMap::new();
+        ports.insert(
+            self.port_name.clone(),
+            PortInfo {
+                internal: Some(session.port),
+                external: Some(session.port),
+            },
+        );
+        Ok(SessionInfo {
+            session_id: session_id.to_string(),
+            status: if ready { "Ready" } else { "Starting" }.to_string(),
+            ready,
+            elapsed: session.started.elapsed().as_secs() as i32,
+            deployment: ready.then(|| DeploymentInfo {
+                request_id: Some(session.request_id.clone()),
+                public_ip: self.public_ip.clone(),
+                ports,
+            }),
+        })
+    }
+
+    async fn delete_session(&self, session_id: &str) -> Result<(), BackendError> {
+        let Some(mut session) = self.sessions.lock().await.remove(session_id) else {
+            return Err(BackendError::NotFound(session_id.to_string()));
+        };
+        let _ = std::fs::remove_dir_all(&session.context_dir);
+        if let Ok(Some(_)) = session.child.try_wait() {
+            return Err(BackendError::Gone(format!(
+                "gameserver for {session_id} already exited"
+            )));
+        }
+        info!(
+            "Killing local gameserver for session {session_id} (request {})",
+            session.request_id
+        );
+        session
+            .child
+            .kill()
+            .await
+            .map_err(|e| BackendError::Other(format!("unable to kill gameserver: {e}")))
+    }
+
+    /// Local gameservers only exist for sessions, so this kills the session's gameserver.
+    async fn stop_deployment(&self, request_id: &str) -> Result<(), BackendError> {
+        let session_id = self
+            .sessions
+            .lock()
+            .await
+            .iter()
+            .find(|(_, session)| session.request_id == request_id)
+            .map(|(session_id, _)| session_id.clone());
+        match session_id {
+            Some(session_id) => self.delete_session(&session_id).await,
+            None => Err(BackendError::NotFound(request_id.to_string())),
+        }
+    }
+
+    async fn list_sessions(&self) -> Result<Vec<SessionInfo>, BackendError> {
+        let sessions = self.sessions.lock().await;
+        Ok(sessions
+            .iter()
+            .map(|(session_id, session)| SessionInfo {
+                session_id: session_id.clone(),
+                status: "Running".to_string(),
+                ready: true,
+                elapsed: session.started.elapsed().as_secs() as i32,
+                deployment: None,
+            })
+            .collect())
+    }
+}
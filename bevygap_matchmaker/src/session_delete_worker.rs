@@ -1,6 +1,6 @@
+use crate::session_backend::BackendError;
 use crate::MatchmakerState;
 use async_nats::jetstream::{self};
-use edgegap_async::apis::sessions_api::*;
 use futures::StreamExt;
 use log::*;
 
@@ -28,7 +28,7 @@ async fn delete_session_worker(state: &MatchmakerState) -> Result<(), async_nats
     let consumer = stream
         .create_consumer(jetstream::consumer::pull::Config {
             durable_name: Some("api-deleter-1".to_string()),
-            description: Some("Calls edgegap session delete api".to_string()),
+            description: Some("Calls session backend delete api".to_string()),
             ack_policy: jetstream::consumer::AckPolicy::Explicit,
             ..Default::default()
         })
@@ -38,29 +38,26 @@ async fn delete_session_worker(state: &MatchmakerState) -> Result<(), async_nats
         let mut messages = consumer.fetch().max_messages(100).messages().await?;
         while let Some(Ok(message)) = messages.next().await {
             let session_id = String::from_utf8(message.payload.to_vec())?;
-            match session_delete(state.configuration(), session_id.as_str()).await {
-                Ok(session_delete_response) => {
-                    info!("session_delete ok: {:?}", session_delete_response);
+            match state.backend().delete_session(session_id.as_str()).await {
+                Ok(()) => {
+                    info!("session_delete ok: {session_id}");
+                    crate::lobby::session_ended(state, &session_id).await;
                     message.ack().await?;
                 }
-                Err(edgegap_async::apis::Error::ResponseError(resp_content)) => {
-                    match resp_content.status.as_u16() {
-                        404 => {
-                            // session already deleted or never existed.
-                            warn!(
-                                "session_delete 404: {session_id} - already deleted or not found?"
-                            );
-                            message.ack().await?;
-                        }
-                        410 => {
-                            // "instance already terminated"
-                            warn!("session_delete 410 'instance already terminated': {session_id}");
-                            message.ack().await?;
-                        }
-                        code => {
-                            error!("session_delete error status = {code} for {session_id} {resp_content:?}");
-                        }
-                    }
+                Err(BackendError::NotFound(_)) => {
+                    // session already deleted or never existed.
+                    warn!("session_delete 404: {session_id} - already deleted or not found?");
+                    crate::lobby::session_ended(state, &session_id).await;
+                    message.ack().await?;
+                }
+                Err(BackendError::Gone(_)) => {
+                    // "instance already terminated"
+                    warn!("session_delete 410 'instance already terminated': {session_id}");
+                    crate::lobby::session_ended(state, &session_id).await;
+                    message.ack().await?;
+                }
+                Err(BackendError::Rejected(kind, msg)) => {
+                    error!("session_delete error {kind:?} for {session_id} {msg}");
                 }
                 Err(e) => {
                     // TODO What to do about junk data on queue that can never be deleted?
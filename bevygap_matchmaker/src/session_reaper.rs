@@ -24,7 +24,14 @@ pub(crate) async fn session_cleanup_supervisor(
             error!("unclaimed_session_reaper exited, restarting");
         }
     });
-    futures::future::join_all([handle1, handle2]).await;
+    let state = orig_state.clone();
+    let handle3 = tokio::spawn(async move {
+        loop {
+            let _ = grace_period_reaper(&state).await;
+            error!("grace_period_reaper exited, restarting");
+        }
+    });
+    futures::future::join_all([handle1, handle2, handle3]).await;
     Ok(())
 }
 
@@ -60,8 +67,41 @@ async fn unclaimed_session_reaper(state: &MatchmakerState) -> Result<(), async_n
     // Ok(())
 }
 
-/// Deletes sessions once a gameserver removes the active_sessions KV entry.
+/// Sessions with no players left are kept in session_grace for --reconnect-grace-seconds,
+/// in case someone reconnects. Once the grace period is over, delete the ones still empty.
+async fn grace_period_reaper(state: &MatchmakerState) -> Result<(), async_nats::Error> {
+    let mut interval = time::interval(Duration::from_millis(5000));
+    let grace = Duration::from_secs(state.settings.reconnect_grace_seconds);
+    let kv = state.nats.kv_session_grace();
+    loop {
+        interval.tick().await;
+        let mut keys = kv.keys().await?.boxed();
+        while let Some(session_id) = keys.try_next().await? {
+            let Ok(Some(entry)) = kv.entry(&session_id).await else {
+                continue;
+            };
+            let age = OffsetDateTime::now_utc() - entry.created;
+            if age < grace {
+                continue;
+            }
+            if session_has_connections(state, session_id.as_str()).await? {
+                info!("Session {session_id} has players again, ending its grace period");
+            } else {
+                info!("Grace period over for empty session {session_id}, deleting");
+                state
+                    .nats
+                    .enqueue_session_delete(session_id.clone())
+                    .await?;
+            }
+            kv.delete(&session_id).await?;
+        }
+    }
+}
+
+/// Deletes sessions once a gameserver removes the last active_connections KV entry for it.
 ///  this is the happy path, where there were no orphans..
+///
+/// Keys are "{session_id}.{client_id}", since several clients can share a session.
 async fn session_cleanup_watcher(state: &MatchmakerState) -> Result<(), async_nats::Error> {
     let kv = state.nats.kv_active_connections();
     let mut watcher = kv.watch(">").await?;
@@ -69,13 +109,31 @@ async fn session_cleanup_watcher(state: &MatchmakerState) -> Result<(), async_na
         info!("{event:?}");
         match event {
             Ok(event) => {
-                let session_id = event.key;
+                let session_id = event
+                    .key
+                    .split_once('.')
+                    .map(|(session_id, _client_id)| session_id)
+                    .unwrap_or(event.key.as_str())
+                    .to_string();
                 if event.operation == Operation::Delete {
-                    info!("active_connection deleted, deleting session {session_id}",);
-                    state
-                        .nats
-                        .enqueue_session_delete(session_id.clone())
-                        .await?;
+                    if session_has_connections(state, session_id.as_str()).await? {
+                        info!("active_connection {} deleted, but session {session_id} still has players", event.key);
+                        continue;
+                    }
+                    if state.settings.reconnect_grace_seconds == 0 {
+                        info!("last active_connection deleted, deleting session {session_id}",);
+                        state
+                            .nats
+                            .enqueue_session_delete(session_id.clone())
+                            .await?;
+                    } else {
+                        info!("last active_connection deleted, session {session_id} is in its reconnect grace period");
+                        state
+                            .nats
+                            .kv_session_grace()
+                            .put(session_id.as_str(), session_id.clone().into())
+                            .await?;
+                    }
                 }
                 if event.operation == Operation::Put {
                     info!("New Session put {session_id}, deleting from unclaimed_sessions ");
@@ -85,6 +143,12 @@ async fn session_cleanup_watcher(state: &MatchmakerState) -> Result<(), async_na
                         .kv_unclaimed_sessions()
                         .delete(session_id.as_str())
                         .await;
+                    // and if someone reconnected during the grace period, it's no longer empty.
+                    let _ = state
+                        .nats
+                        .kv_session_grace()
+                        .delete(session_id.as_str())
+                        .await;
                 }
             }
             Err(e) => {
@@ -94,3 +158,18 @@ async fn session_cleanup_watcher(state: &MatchmakerState) -> Result<(), async_na
     }
     Ok(())
 }
+
+/// Are there any clients still connected to this session?
+pub(crate) async fn session_has_connections(
+    state: &MatchmakerState,
+    session_id: &str,
+) -> Result<bool, async_nats::Error> {
+    let prefix = format!("{session_id}.");
+    let mut keys = state.nats.kv_active_connections().keys().await?.boxed();
+    while let Some(key) = keys.try_next().await? {
+        if key.starts_with(prefix.as_str()) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
@@ -1,15 +1,18 @@
-use crate::MatchmakerState;
+use crate::session_backend::*;
+use crate::warm_pool::ClaimedDeployment;
+use crate::{AppEntry, MatchmakerState};
 use async_nats::error::Error as NatsError;
-use async_nats::{Client, Subject};
+use async_nats::{Client, Subject, Subscriber};
 use base64::prelude::*;
 use bevygap_shared::protocol::*;
-use edgegap_async::{apis::sessions_api::*, apis::Error as EdgegapError, models::SessionModel};
 use futures::StreamExt;
 use lightyear::prelude::ConnectToken;
 use log::*;
-use serde::{de, Deserialize};
+use serde::{de, Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
+use tokio::sync::{oneshot, Notify};
 use tokio::time::Instant;
 
 #[derive(Deserialize, Debug)]
@@ -17,7 +20,6 @@ pub struct SessionRequest {
     /// the ip of the client that wants a session
     pub client_ip: String,
     /// the rest of the request, with no fixed schema.
-    #[allow(dead_code)]
     pub obj: serde_json::Map<String, serde_json::Value>,
 }
 
@@ -44,15 +46,80 @@ impl SessionRequest {
             obj: parsed,
         })
     }
+
+    /// The party part of the request, if the client wants to play with others.
+    pub fn party(&self) -> Result<Option<PartyRequest>, serde_json::Error> {
+        match self.obj.get("party") {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(party) => serde_json::from_value(party.clone()).map(Some),
+        }
+    }
+
+    /// The region hint from the request, if any.
+    pub fn region(&self) -> Option<String> {
+        self.obj
+            .get("region")
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string())
+    }
+
+    /// The lobby part of the request, if the client wants to create or join a lobby.
+    pub fn lobby(&self) -> Result<Option<LobbyRequest>, serde_json::Error> {
+        match self.obj.get("lobby") {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(lobby) => serde_json::from_value(lobby.clone()).map(Some),
+        }
+    }
+
+    /// Who the player is, if whoever sent the request authenticated them.
+    /// Checked here, so we find out it won't fit in a connect token before making a session.
+    pub fn identity(&self) -> Result<Option<PlayerIdentity>, String> {
+        let identity = match self.obj.get("identity") {
+            None | Some(serde_json::Value::Null) => return Ok(None),
+            Some(identity) => serde_json::from_value::<PlayerIdentity>(identity.clone())
+                .map_err(|e| e.to_string())?,
+        };
+        identity.to_user_data()?;
+        Ok(Some(identity))
+    }
+
+    /// The client's beacon latencies, if it probed them. They're only a hint, so ones we
+    /// can't make sense of are ignored.
+    pub fn latencies(&self) -> Vec<BeaconLatency> {
+        self.obj
+            .get("latencies")
+            .and_then(|v| serde_json::from_value(v.clone()).ok())
+            .unwrap_or_default()
+    }
+
+    /// The secret from a previous SessionReady, if the client wants to rejoin that session.
+    pub fn reconnect_secret(&self) -> Option<String> {
+        self.obj
+            .get("reconnect_secret")
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string())
+    }
 }
 
 /// For sending progress updates back to the caller
-struct ChunkResponder {
+#[derive(Clone)]
+pub(crate) struct ChunkResponder {
     client: Client,
     reply_to: Subject,
 }
 impl ChunkResponder {
-    async fn send(
+    /// For sending to a client whose request is handled elsewhere, eg a lobby member
+    /// waiting on another matchmaker instance.
+    pub(crate) fn for_subject(client: Client, reply_to: String) -> Self {
+        Self {
+            client,
+            reply_to: reply_to.into(),
+        }
+    }
+    pub(crate) fn reply_subject(&self) -> &str {
+        self.reply_to.as_str()
+    }
+    pub(crate) async fn send(
         &self,
         feedback: SessionRequestFeedback,
     ) -> Result<(), NatsError<async_nats::client::PublishErrorKind>> {
@@ -62,74 +129,486 @@ impl ChunkResponder {
             .publish(self.reply_to.clone(), payload.into())
             .await
     }
-    async fn finish(&self) -> Result<(), NatsError<async_nats::client::PublishErrorKind>> {
+    /// Where matchmaker_httpd forwards any later messages the client sends, eg lobby commands,
+    /// and says when the client goes away.
+    pub(crate) fn control_subject(&self) -> String {
+        format!("{}.control", self.reply_to)
+    }
+    /// Subscribes to [`Self::control_subject`]. Do this before the request can be seen by
+    /// anyone else, eg before queueing it, so nothing sent there is missed.
+    pub(crate) async fn subscribe_control(&self) -> Result<Subscriber, MyError> {
+        self.client
+            .subscribe(self.control_subject())
+            .await
+            .map_err(|e| MyError::Nats(Box::new(e)))
+    }
+    pub(crate) async fn finish(
+        &self,
+    ) -> Result<(), NatsError<async_nats::client::PublishErrorKind>> {
         self.client.publish(self.reply_to.clone(), "".into()).await
     }
 }
 
+/// Resolves once matchmaker_httpd says the client went away. Other control messages are
+/// ignored, and if the subscription ends, this never resolves.
+pub(crate) async fn cancelled(control: &mut Subscriber) {
+    while let Some(message) = control.next().await {
+        if let Ok(RequestControl::Cancel) = RequestControl::decode(&message.payload) {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
 /// one of these spawned per request.
 /// should send response parts back to reply_to.
 async fn stream_request_processor(
     state: &MatchmakerState,
+    app: &AppEntry,
     session_request: SessionRequest,
     responder: &ChunkResponder,
-) -> Result<(), MyError<SessionPostError>> {
+) -> Result<(), MyError> {
     // Sender for feedback responses, client will recieve multiple before the Finished one.
-    info!("Generating streaming session for {session_request:?}");
+    info!("Generating streaming session for {app}: {session_request:?}");
     responder.send(SessionRequestFeedback::Acknowledged).await?;
 
-    let mut session_model = SessionModel::new(state.settings.app_name.clone());
-    session_model.ip_list = Some(vec![session_request.client_ip.to_string()]);
-    session_model
-        .webhook_url
-        .clone_from(&state.settings.session_webhook_url);
-    // create session via edgegap api.
+    let identity = session_request.identity().map_err(|e| {
+        MyError::Bevygap(
+            MatchmakerErrorKind::BadRequest,
+            format!("Invalid player identity: {e}"),
+        )
+    })?;
+
+    if let Some(secret) = session_request.reconnect_secret() {
+        crate::reconnect::reconnect_session(state, app, &secret, responder).await?;
+        responder.finish().await?;
+        return Ok(());
+    }
+
+    let party = session_request.party().map_err(|e| {
+        MyError::Bevygap(
+            MatchmakerErrorKind::BadRequest,
+            format!("Invalid party request: {e}"),
+        )
+    })?;
+
+    let lobby = session_request.lobby().map_err(|e| {
+        MyError::Bevygap(
+            MatchmakerErrorKind::BadRequest,
+            format!("Invalid lobby request: {e}"),
+        )
+    })?;
+
+    let session = match (party, lobby) {
+        (Some(_), Some(_)) => {
+            return Err(MyError::Bevygap(
+                MatchmakerErrorKind::BadRequest,
+                "Can't request a party and a lobby at once".into(),
+            ));
+        }
+        (None, Some(lobby)) => {
+            crate::lobby::lobby_session(
+                state,
+                app,
+                lobby,
+                session_request.client_ip.clone(),
+                crate::beacons::client_location(state, &session_request).await,
+                responder,
+            )
+            .await?
+        }
+        (None, None) if state.settings.match_size > 1 => {
+            crate::queue::queue_session(state, app, &session_request, responder).await?
+        }
+        (None, None) => {
+            let ip_list = vec![session_request.client_ip.to_string()];
+            let location = crate::beacons::client_location(state, &session_request).await;
+            let region = session_request.region();
+            create_ready_session(
+                state,
+                app,
+                ip_list,
+                location.into_iter().collect(),
+                region.as_deref(),
+                std::slice::from_ref(responder),
+            )
+            .await?
+        }
+        (Some(party), None) => {
+            crate::party::party_session(
+                state,
+                app,
+                party,
+                session_request.client_ip.clone(),
+                crate::beacons::client_location(state, &session_request).await,
+                responder,
+            )
+            .await?
+        }
+    };
+
+    send_connect_token(state, app, &session, identity.as_ref(), responder).await?;
+    // send an empty chunk to finish:
+    responder.finish().await?;
+    Ok(())
+}
+
+/// A session that's ready for players, with everything needed to make connect tokens for it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ReadySession {
+    pub session_id: String,
+    /// The deployment's request id, if the backend told us.
+    #[serde(default)]
+    pub deployment: Option<String>,
+    pub ip: IpAddr,
+    /// The external port of each transport clients can use, all on `ip`.
+    #[serde(default)]
+    pub ports: BTreeMap<GameTransport, u16>,
+    /// Empty if there's no WebTransport port.
+    pub cert_digest: String,
+}
+
+impl ReadySession {
+    /// The port for clients that only read SessionReady's `port`: the first --port-mapping
+    /// transport we have a port for, or failing that, any.
+    pub(crate) fn primary_port(&self, state: &MatchmakerState) -> Option<u16> {
+        state
+            .port_mappings()
+            .iter()
+            .find_map(|(transport, _)| self.ports.get(transport))
+            .or_else(|| self.ports.values().next())
+            .copied()
+    }
+
+    /// Where the connect token says the gameserver is. Lightyear's netcode client tries these
+    /// in order, and only UDP clients use them, since the other transports connect to the
+    /// address they're given. So UDP goes first.
+    pub(crate) fn server_addresses(&self) -> Vec<SocketAddr> {
+        let mut ports: Vec<(GameTransport, u16)> = self.ports.clone().into_iter().collect();
+        ports.sort_by_key(|(transport, _)| *transport != GameTransport::Udp);
+        ports
+            .into_iter()
+            .map(|(_, port)| SocketAddr::new(self.ip, port))
+            .collect()
+    }
+}
+
+/// Creates a session for all the clients in ip_list, and waits until it's ready.
+/// Progress is reported to all the responders. `locations` are used for placement, for the
+/// clients that probed the beacons.
+///
+/// Uses an idle deployment from the warm pool if there is one in `region`, or near the clients.
+/// Deployments whose gameserver says it's full are never asked for.
+pub(crate) async fn create_ready_session(
+    state: &MatchmakerState,
+    app: &AppEntry,
+    ip_list: Vec<String>,
+    locations: Vec<ClientLocation>,
+    region: Option<&str>,
+    responders: &[ChunkResponder],
+) -> Result<ReadySession, MyError> {
+    let claimed = claim_warm_deployment(state, app, region, &locations).await;
+    let spec = SessionSpec {
+        app_name: app.app_name.clone(),
+        app_version: app.app_version.clone(),
+        ip_list,
+        webhook_url: state.settings.session_webhook_url.clone(),
+        deployment: claimed.as_ref().map(|c| c.request_id().to_string()),
+        locations,
+    };
+    // create session via the backend.
     // this gives us our session_id, but could be in a non-Ready state for a while.
-    let post_session = session_post(state.configuration(), session_model).await?;
+    let session_id = match state.backend().create_session(spec).await {
+        Ok(session_id) => session_id,
+        Err(e) => {
+            // a transport error says nothing about the deployment, so it can go back in the
+            // pool. if the backend refused it, stop it and let the pool replace it.
+            match (claimed, &e) {
+                (Some(claimed), BackendError::Other(_)) => state.warm_pool.unclaim(claimed),
+                (Some(claimed), _) => {
+                    crate::warm_pool::stop_deployment(state, claimed.request_id()).await
+                }
+                (None, _) => {}
+            }
+            return Err(e.into());
+        }
+    };
 
-    // info!("{post_session:?}");
+    broadcast(
+        responders,
+        SessionRequestFeedback::SessionRequestAccepted(session_id.clone()),
+    )
+    .await?;
+
+    // woken early by the session webhook, if there is one. polling is the fallback.
+    let ready_notify = state.session_ready_notifier.register(session_id.as_str());
+    let result =
+        wait_for_ready_session(state, session_id.as_str(), &ready_notify, responders).await;
+    state.session_ready_notifier.unregister(session_id.as_str());
+    let session_get = result?;
+
+    let Some(deployment) = session_get.deployment else {
+        return Err(MyError::Bevygap(
+            MatchmakerErrorKind::Internal,
+            "No deployment found".into(),
+        ));
+    };
+
+    let ports = deployment_ports(state, &deployment).await?;
+
+    let ip = deployment
+        .public_ip
+        .parse::<std::net::IpAddr>()
+        .expect("Failed parsing server ip");
+
+    // TODO once the session is ready, the cert digest should have been reported, but
+    // there is definitely a race here so we should block on it for a second or so?
+    let cert_digest = session_cert_digest(state, &ip, &ports).await?;
+
+    // the backend picks the deployment if we didn't, and might put us on a server the
+    // gameserver says is full.
+    if let Some(request_id) = &deployment.request_id {
+        if deployment_is_full(state, request_id).await {
+            state
+                .nats
+                .enqueue_session_delete(session_get.session_id.clone())
+                .await?;
+            return Err(MyError::Bevygap(
+                MatchmakerErrorKind::NoCapacity,
+                "Server is full".into(),
+            ));
+        }
+    }
+
+    Ok(ReadySession {
+        session_id: session_get.session_id,
+        deployment: deployment.request_id,
+        ip,
+        ports,
+        cert_digest,
+    })
+}
+
+/// Claims an idle warm pool deployment that hasn't reported being full, if there is one.
+/// Full ones go back in the pool, to be tried again once they have room, or replaced once
+/// they've been idle too long.
+async fn claim_warm_deployment(
+    state: &MatchmakerState,
+    app: &AppEntry,
+    region: Option<&str>,
+    locations: &[ClientLocation],
+) -> Option<ClaimedDeployment> {
+    let mut full = Vec::new();
+    // claimed deployments leave the pool until they're unclaimed, so this can't loop forever.
+    let mut claimed = None;
+    while let Some(candidate) = state.warm_pool.claim(app, region, locations) {
+        if !deployment_is_full(state, candidate.request_id()).await {
+            claimed = Some(candidate);
+            break;
+        }
+        warn!(
+            "Warm pool deployment {} is full, not using it",
+            candidate.request_id()
+        );
+        full.push(candidate);
+    }
+    for candidate in full {
+        state.warm_pool.unclaim(candidate);
+    }
+    claimed
+}
+
+/// What each member of a group gets once the group has a session, or why it failed.
+pub(crate) type GroupResult = Result<ReadySession, (MatchmakerErrorKind, String)>;
+
+/// A client waiting to share a session with others, eg a party member or a queued player.
+/// Whoever completes the group creates the session and sends it to every member.
+pub(crate) struct GroupMember {
+    pub client_ip: String,
+    /// Where the client seems to be, if they probed the beacons.
+    pub location: Option<ClientLocation>,
+    pub responder: ChunkResponder,
+    pub result_tx: oneshot::Sender<GroupResult>,
+}
+
+/// Creates one session for a group of clients, and hands the result to each member.
+/// `label` is used for logging, eg "party ABC234".
+pub(crate) async fn start_group_session(
+    state: &MatchmakerState,
+    app: &AppEntry,
+    label: &str,
+    region: Option<&str>,
+    members: Vec<GroupMember>,
+) {
+    info!("Creating session for {label} of {} players", members.len());
+    let ip_list = members.iter().map(|m| m.client_ip.clone()).collect();
+    let locations = members.iter().filter_map(|m| m.location.clone()).collect();
+    let responders: Vec<ChunkResponder> = members.iter().map(|m| m.responder.clone()).collect();
+    let result = create_ready_session(state, app, ip_list, locations, region, &responders)
+        .await
+        .map_err(|e| {
+            error!("Failed to create session for {label}: {e}");
+            e.kind_and_message()
+        });
+    for member in members {
+        let _ = member.result_tx.send(result.clone());
+    }
+}
+
+/// Assigns a new client_id, and sends the client a connect token for the session,
+/// along with a secret they can use to reconnect to it later.
+async fn send_connect_token(
+    state: &MatchmakerState,
+    app: &AppEntry,
+    session: &ReadySession,
+    identity: Option<&PlayerIdentity>,
+    responder: &ChunkResponder,
+) -> Result<(), MyError> {
+    //  assign a new client_id
+    let client_id = rand::random();
+    let reconnect_secret =
+        crate::reconnect::store_reconnect_secret(state, app, session, client_id, identity).await?;
+    send_token_for_client(
+        state,
+        app,
+        session,
+        client_id,
+        identity,
+        reconnect_secret,
+        responder,
+    )
+    .await
+}
+
+/// Builds a connect token for this client_id and sends it to the client.
+/// The player's identity, if known, goes in the token's user data.
+pub(crate) async fn send_token_for_client(
+    state: &MatchmakerState,
+    app: &AppEntry,
+    session: &ReadySession,
+    client_id: u64,
+    identity: Option<&PlayerIdentity>,
+    reconnect_secret: String,
+    responder: &ChunkResponder,
+) -> Result<(), MyError> {
+    let Some(port) = session.primary_port(state) else {
+        return Err(MyError::Bevygap(
+            MatchmakerErrorKind::Internal,
+            format!(
+                "No gameserver ports known for session {}",
+                session.session_id
+            ),
+        ));
+    };
+    let token_base64 = issue_connect_token(state, app, session, client_id, identity).await?;
 
     responder
-        .send(SessionRequestFeedback::SessionRequestAccepted(
-            post_session.session_id.clone(),
-        ))
+        .send(SessionRequestFeedback::SessionReady {
+            token: token_base64,
+            ip: session.ip.to_string(),
+            port,
+            cert_digest: session.cert_digest.clone(),
+            ports: session.ports.clone(),
+            reconnect_secret: Some(reconnect_secret),
+        })
         .await?;
+    Ok(())
+}
 
-    let mut session_get;
+/// Builds a base64 connect token for this client_id, and registers the client_id in NATS so
+/// the session's gameserver will admit it.
+pub(crate) async fn issue_connect_token(
+    state: &MatchmakerState,
+    app: &AppEntry,
+    session: &ReadySession,
+    client_id: u64,
+    identity: Option<&PlayerIdentity>,
+) -> Result<String, MyError> {
+    // gameservers only admit client ids issued for their own deployment.
+    let Some(request_id) = session.deployment.as_deref() else {
+        return Err(MyError::Bevygap(
+            MatchmakerErrorKind::Internal,
+            format!("No deployment known for session {}", session.session_id),
+        ));
+    };
+    let server_addresses = session.server_addresses();
+    let private_key = app.keys.signing_key(state, Some(request_id)).await;
+
+    info!(
+        "🏠 BUILD ConnectToken: server_addresses = {server_addresses:?} proto id: {}, client_id: {client_id}, key id: {}",
+        app.protocol_id,
+        private_key_id(&private_key)
+    );
+    let mut token = ConnectToken::build(
+        server_addresses.as_slice(),
+        app.protocol_id,
+        client_id,
+        private_key,
+    );
+    if let Some(identity) = identity {
+        let user_data = identity
+            .to_user_data()
+            .map_err(|e| MyError::Bevygap(MatchmakerErrorKind::BadRequest, e))?;
+        token = token.user_data(user_data);
+    }
+    let token = token.generate().expect("Failed to generate token");
+
+    let token_bytes = token.try_into_bytes().expect("Failed to serialize token");
+
+    let issued = IssuedClientId {
+        session_id: session.session_id.clone(),
+        identity: identity.cloned(),
+    };
+    register_ids_in_nats(state, request_id, client_id.to_string(), issued).await?;
+
+    Ok(BASE64_STANDARD.encode(token_bytes))
+}
+
+/// Sends the same feedback to several clients, eg all the members of a party.
+pub(crate) async fn broadcast(
+    responders: &[ChunkResponder],
+    feedback: SessionRequestFeedback,
+) -> Result<(), MyError> {
+    for responder in responders {
+        responder.send(feedback.clone()).await?;
+    }
+    Ok(())
+}
+
+/// Polls the session until it's ready, waking early if the session webhook says it's ready.
+///
+/// We must wait until the session is ready / linked before telling the client to connect.
+async fn wait_for_ready_session(
+    state: &MatchmakerState,
+    session_id: &str,
+    ready_notify: &Notify,
+    responders: &[ChunkResponder],
+) -> Result<SessionInfo, MyError> {
     let mut tries = 0;
-    // let mut first_seen_session_id = false;
     let start_time = Instant::now();
+    let poll_interval = state.settings.session_poll_interval();
     tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
     loop {
         tries += 1;
         info!("GET SESSION... ({tries})");
-        session_get = get_session(state.configuration(), post_session.session_id.as_str())
+        let session_get = state
+            .backend()
+            .poll_session(session_id)
             .await
-            .map_err(|e| {
-                error!("get session error: {:?}", e);
-                EdgegapError::Io(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("get session error: {}", e),
-                ))
-            })?;
+            .inspect_err(|e| error!("get session error: {e}"))?;
         let feedback = SessionRequestFeedback::ProgressReport(format!(
             "{} ({})",
             session_get.status, session_get.elapsed
         ));
-        responder.send(feedback).await?;
-
-        // info!("{session_get:?}");
+        broadcast(responders, feedback).await?;
 
         // Avoid session leakage!
-        // the first time we get a response with a session_id, we store it in unclaimed_sessions,
-        // so we can automatically delete it if it goes unused.
-        // if !first_seen_session_id {
-        // first_seen_session_id = true;
+        // we store the session_id in unclaimed_sessions, so we can automatically delete it
+        // if it goes unused.
         // this kv buckets has 30sec expiry i think, but that starts ticking
         // while we spend 20+ secs waiting on a session sometimes, so it's deleted
         // before we need it. So renew the timeout each iteration for now:
         let session_id_str = session_get.session_id.clone();
-        // info!("Writing session_id to unclaimed_sessions KV: {session_id_str}");
         let val = session_id_str.clone().into();
         state
             .nats
@@ -137,141 +616,213 @@ async fn stream_request_processor(
             .put(session_id_str, val)
             .await
             .expect("Failed to put session_id in unclaimed_sessions KV");
-        // }
 
         if session_get.ready {
-            break;
+            return Ok(session_get);
         }
 
         let elapsed = Instant::now().duration_since(start_time);
-        if elapsed > Duration::from_secs(crate::MAX_SESSION_CREATION_SECONDS) {
+        let max_wait = Duration::from_secs(crate::MAX_SESSION_CREATION_SECONDS);
+        if elapsed > max_wait {
             //TODO schedule delete of session id!
             return Err(MyError::Bevygap(
-                408,
+                MatchmakerErrorKind::Timeout,
                 "session still not ready, timed out.".into(),
             ));
         }
 
-        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
-    }
-
-    // info!("{session_get:?}");
-
-    // We must wait until the session is ready / linked before telling the client to connect.
-    // You can ask for a webhook, but for now we just poll until it's ready.
-
-    let Some(deployment) = session_get.deployment else {
-        return Err(MyError::Bevygap(500, "No deployment found".into()));
-    };
-
-    let Some(ports) = deployment.ports else {
-        return Err(MyError::Bevygap(500, "No ports found in deployment".into()));
-    };
-
-    if ports.len() > 1 {
-        warn!("multiple ports found for deployment.. using first one");
+        // poll again when the webhook says it's ready, or after the poll interval,
+        // whichever comes first.
+        let wait = poll_interval.min(max_wait - elapsed + Duration::from_millis(1));
+        tokio::select! {
+            _ = ready_notify.notified() => info!("Session webhook says {session_id} is ready"),
+            _ = tokio::time::sleep(wait) => {}
+        }
     }
-
-    // use first port.
-    // to support multiple, we'd need to store the name of the port mapping definition that we
-    // use in edgegap, to look it up here.
-    let port = ports
-        .iter()
-        .next()
-        .and_then(|(_, port_info)| port_info.external)
-        .expect("Couldn't get port");
-
-    //  assign a new client_id
-    let client_id = rand::random();
-    // info!("client_id = {client_id}");
-
-    let public_ip_str = deployment.public_ip.as_str();
-
-    let ip = public_ip_str
-        .parse::<std::net::IpAddr>()
-        .expect("Failed parsing server ip");
-
-    // TODO once the session is ready, the cert digest should have been reported, but
-    // there is definitely a race here so we should block on it for a second or so?
-    let cert_digest = lookup_cert_digest(state, &ip).await?;
-
-    let server_addresses = SocketAddr::new(ip, port as u16);
-
-    info!(
-        "🏠 BUILD ConnectToken: server_addresses = {server_addresses} proto id: {}, client_id: {client_id}, privkey: {:?}",
-        state.settings.protocol_id(),
-        state.lightyear_private_key()
-    );
-    let token = ConnectToken::build(
-        server_addresses,
-        state.settings.protocol_id(),
-        client_id,
-        state.lightyear_private_key(),
-    )
-    .generate()
-    .expect("Failed to generate token");
-
-    let token_bytes = token.try_into_bytes().expect("Failed to serialize token");
-    let token_base64 = BASE64_STANDARD.encode(token_bytes);
-
-    register_ids_in_nats(state, client_id.to_string(), session_get.session_id).await?;
-
-    responder
-        .send(SessionRequestFeedback::SessionReady {
-            token: token_base64,
-            ip: deployment.public_ip,
-            port: port as u16,
-            cert_digest,
-        })
-        .await?;
-    // send an empty chunk to finish:
-    responder.finish().await?;
-    Ok(())
 }
 
-async fn register_ids_in_nats(
+/// Several clients can share a session (parties), so the session to client mapping
+/// is keyed by "{session_id}.{client_id}".
+///
+/// The client to session mapping is keyed by "{request_id}.{client_id}", so each gameserver
+/// can watch for the client ids it should admit, and who they are.
+pub(crate) async fn register_ids_in_nats(
     state: &MatchmakerState,
+    request_id: &str,
     client_id: String,
-    session_id: String,
-) -> Result<(), MyError<SessionPostError>> {
-    let session_id_val = session_id.clone().into();
+    issued: IssuedClientId,
+) -> Result<(), MyError> {
+    let session_id = issued.session_id.clone();
+    let issued_val = serde_json::to_vec(&issued).unwrap().into();
     state
         .nats
         .kv_c2s()
-        .put(client_id.as_str(), session_id_val)
+        .put(format!("{request_id}.{client_id}"), issued_val)
         .await
         .map_err(|e| {
-            EdgegapError::Io(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            MyError::Bevygap(
+                MatchmakerErrorKind::Internal,
                 format!("Failed to put token KV entry: {}", e),
-            ))
+            )
         })?;
     state
         .nats
         .kv_s2c()
-        .put(session_id.as_str(), client_id.into())
+        .put(format!("{session_id}.{client_id}"), client_id.into())
         .await
         .map_err(|e| {
-            EdgegapError::Io(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            MyError::Bevygap(
+                MatchmakerErrorKind::Internal,
                 format!("Failed to put token KV entry: {}", e),
-            ))
+            )
         })?;
     Ok(())
 }
 
-async fn lookup_cert_digest(
+/// The transports a deployment's gameserver says it listens on, if it has reported them yet.
+async fn reported_transports(
+    state: &MatchmakerState,
+    request_id: Option<&str>,
+) -> Option<ServerTransports> {
+    let request_id = request_id?;
+    match state.nats.kv_server_transports().get(request_id).await {
+        Ok(Some(payload)) => serde_json::from_slice(&payload)
+            .inspect_err(|e| warn!("Bad transports entry for {request_id}: {e}"))
+            .ok(),
+        Ok(None) => None,
+        Err(e) => {
+            warn!("Unable to look up transports of {request_id}: {e}");
+            None
+        }
+    }
+}
+
+/// The external port of each transport clients can use on this deployment.
+///
+/// Transports with a --port-mapping are found by name, and fail if the deployment is missing
+/// that port mapping, since clients couldn't connect. Other transports the gameserver reported
+/// are found by their internal port. If the gameserver hasn't reported its transports, it's
+/// assumed to have all the --port-mapping ones, or without any --port-mapping, to be a
+/// WebTransport server on the deployment's first port.
+pub(crate) async fn deployment_ports(
+    state: &MatchmakerState,
+    deployment: &DeploymentInfo,
+) -> Result<BTreeMap<GameTransport, u16>, MyError> {
+    let reported = reported_transports(state, deployment.request_id.as_deref()).await;
+    if state.port_mappings().is_empty() && reported.is_none() {
+        return first_port_as_webtransport(deployment);
+    }
+    let mut ports = BTreeMap::new();
+    for (transport, name) in state.port_mappings() {
+        if reported
+            .as_ref()
+            .is_some_and(|reported| !reported.ports.contains_key(transport))
+        {
+            continue;
+        }
+        let Some(port) = deployment.ports.get(name).and_then(|p| p.external) else {
+            let mut available: Vec<&str> = deployment.ports.keys().map(String::as_str).collect();
+            available.sort();
+            return Err(MyError::Bevygap(
+                MatchmakerErrorKind::Internal,
+                format!(
+                    "Deployment has no port mapping named '{name}' for {transport} (has: {})",
+                    available.join(", ")
+                ),
+            ));
+        };
+        ports.insert(*transport, port);
+    }
+    for (transport, internal) in reported.iter().flat_map(|reported| &reported.ports) {
+        if ports.contains_key(transport) {
+            continue;
+        }
+        let external = deployment
+            .ports
+            .values()
+            .find(|p| p.internal == Some(*internal))
+            .and_then(|p| p.external);
+        match external {
+            Some(port) => {
+                ports.insert(*transport, port);
+            }
+            None => warn!("No port mapping for {transport} port {internal}, clients can't use it"),
+        }
+    }
+    if ports.is_empty() {
+        return Err(MyError::Bevygap(
+            MatchmakerErrorKind::Internal,
+            "Deployment has no ports for any transport the gameserver offers".into(),
+        ));
+    }
+    Ok(ports)
+}
+
+/// What we did before --port-mapping existed: the deployment's one port is WebTransport.
+fn first_port_as_webtransport(
+    deployment: &DeploymentInfo,
+) -> Result<BTreeMap<GameTransport, u16>, MyError> {
+    if deployment.ports.len() > 1 {
+        warn!(
+            "multiple ports found for deployment.. using first one, set --port-mapping to choose"
+        );
+    }
+    let mut names: Vec<&String> = deployment.ports.keys().collect();
+    names.sort();
+    let Some(port) = names
+        .first()
+        .and_then(|name| deployment.ports[*name].external)
+    else {
+        return Err(MyError::Bevygap(
+            MatchmakerErrorKind::Internal,
+            "No ports found in deployment".into(),
+        ));
+    };
+    Ok(BTreeMap::from([(GameTransport::WebTransport, port)]))
+}
+
+/// The cert digest clients need for WebTransport, or empty if there's no WebTransport port.
+pub(crate) async fn session_cert_digest(
     state: &MatchmakerState,
     public_ip: &IpAddr,
-) -> Result<String, MyError<SessionPostError>> {
+    ports: &BTreeMap<GameTransport, u16>,
+) -> Result<String, MyError> {
+    if !ports.contains_key(&GameTransport::WebTransport) {
+        return Ok(String::new());
+    }
+    lookup_cert_digest(state, public_ip).await
+}
+
+/// Has this deployment's gameserver reported it's full?
+/// If we can't tell, assume it isn't.
+pub(crate) async fn deployment_is_full(state: &MatchmakerState, request_id: &str) -> bool {
+    match state.nats.kv_server_capacity().get(request_id).await {
+        Ok(Some(payload)) => serde_json::from_slice::<ServerCapacity>(&payload)
+            .map(|capacity| capacity.is_full())
+            .unwrap_or(false),
+        Ok(None) => false,
+        Err(e) => {
+            warn!("Unable to check capacity of {request_id}: {e}");
+            false
+        }
+    }
+}
+
+pub(crate) async fn lookup_cert_digest(
+    state: &MatchmakerState,
+    public_ip: &IpAddr,
+) -> Result<String, MyError> {
     let ip_str = public_ip.to_string();
     match state.nats.kv_cert_digests().get(ip_str).await {
         Ok(Some(cert_digest)) => Ok(String::from_utf8(cert_digest.into()).unwrap()),
-        Ok(None) => Err(MyError::Bevygap(500, "No cert digest found".into())),
+        Ok(None) => Err(MyError::Bevygap(
+            MatchmakerErrorKind::Internal,
+            "No cert digest found".into(),
+        )),
         Err(e) => {
             error!("err getting digest for {public_ip}: {e:?}");
             Err(MyError::Bevygap(
-                500,
+                MatchmakerErrorKind::Internal,
                 "Error'ed on lookup for cert_digest".into(),
             ))
         }
@@ -290,15 +841,18 @@ pub(crate) async fn streaming_session_request_handler(
 ) -> Result<(), async_nats::Error> {
     let client = state.nats_client().clone();
 
-    let subject = format!(
-        "matchmaker.request.{}.{}",
-        state.settings.app_name, state.settings.app_version
-    );
-    info!("Listening for session requests on '{subject}'");
-
-    let mut sub = client.subscribe(subject).await?;
+    // one subscription per app we serve, tagged with the app's index so we know which
+    // app each request is for.
+    let mut subs = Vec::new();
+    for (index, app) in state.apps().iter().enumerate() {
+        let subject = app.request_subject(state.nats.namespace());
+        info!("Listening for session requests on '{subject}'");
+        let sub = client.subscribe(subject).await?;
+        subs.push(sub.map(move |message| (index, message)));
+    }
+    let mut sub = futures::stream::select_all(subs);
 
-    while let Some(message) = sub.next().await {
+    while let Some((app_index, message)) = sub.next().await {
         info!("Matchmaking request on {}", message.subject);
         let Some(reply_to) = message.reply else {
             error!("got message with no reply-to, discarding");
@@ -315,7 +869,10 @@ pub(crate) async fn streaming_session_request_handler(
             Err(e) => {
                 let err_response = format!("ERROR decoding session request {e:?}");
                 responder
-                    .send(SessionRequestFeedback::Error(500, err_response))
+                    .send(SessionRequestFeedback::error(
+                        MatchmakerErrorKind::BadRequest,
+                        err_response,
+                    ))
                     .await?;
                 responder.finish().await?;
                 continue;
@@ -327,38 +884,14 @@ pub(crate) async fn streaming_session_request_handler(
         // or more likely a response from the edgegap api.
         let state = state.clone();
         tokio::spawn(async move {
-            match stream_request_processor(&state, request, &responder).await {
+            let app = &state.apps()[app_index];
+            match stream_request_processor(&state, app, request, &responder).await {
                 Ok(()) => {}
-                Err(MyError::Bevygap(err_code, err_msg)) => {
-                    error!("error in stream_request_processor: {err_code}={err_msg}");
-                    let _ = responder
-                        .send(SessionRequestFeedback::Error(err_code, err_msg))
-                        .await;
-                }
-                Err(MyError::Edgegap(edgegap_async::apis::Error::ResponseError(e))) => {
-                    let (err_code, err_msg) = match e.entity {
-                        Some(SessionPostError::Status400(ee)) => (400, ee.message),
-                        Some(SessionPostError::Status401(ee)) => (401, ee.message),
-                        Some(SessionPostError::Status409(ee)) => (409, ee.message),
-                        _ => (503, "unknown error".to_string()),
-                    };
-                    error!("error in session_responder: {err_code}={err_msg}");
-                    let _ = responder
-                        .send(SessionRequestFeedback::Error(err_code, err_msg))
-                        .await;
-                }
-                Err(MyError::Nats(e)) => {
-                    error!("Nats error in stream_request_processor: {:?}", e);
-                    let err_response = format!("NATS error: {e:?}");
-                    let _ = responder
-                        .send(SessionRequestFeedback::Error(500, err_response))
-                        .await;
-                }
                 Err(e) => {
-                    error!("Error in stream_request_processor: {:?}", e);
-                    let err_response = format!("SessionPostError: {e:?}");
+                    error!("error in stream_request_processor: {e}");
+                    let (kind, msg) = e.kind_and_message();
                     let _ = responder
-                        .send(SessionRequestFeedback::Error(500, err_response))
+                        .send(SessionRequestFeedback::error(kind, msg))
                         .await;
                 }
             }
@@ -371,26 +904,50 @@ pub(crate) async fn streaming_session_request_handler(
     Ok(())
 }
 
-// don't want to modify the auto-generated edgegap client error type,
-// so wrap it up here to also add our nats error type.
+// wraps up the session backend error type, and adds our nats error type.
 #[derive(Debug)]
-pub(crate) enum MyError<T> {
-    Edgegap(EdgegapError<T>), // Wrap the third-party error
-    Nats(async_nats::Error),  // Add a variant for async_nats errors
-    Bevygap(u16, String),
+pub(crate) enum MyError {
+    Backend(BackendError),
+    Nats(async_nats::Error), // Add a variant for async_nats errors
+    Bevygap(MatchmakerErrorKind, String),
 }
-impl<T> From<EdgegapError<T>> for MyError<T> {
-    fn from(err: EdgegapError<T>) -> Self {
-        MyError::Edgegap(err)
+impl From<BackendError> for MyError {
+    fn from(err: BackendError) -> Self {
+        MyError::Backend(err)
     }
 }
-impl<T> From<NatsError<async_nats::client::PublishErrorKind>> for MyError<T> {
+impl From<NatsError<async_nats::client::PublishErrorKind>> for MyError {
     fn from(err: NatsError<async_nats::client::PublishErrorKind>) -> Self {
         MyError::Nats(Box::new(err))
     }
 }
-impl<T> From<async_nats::Error> for MyError<T> {
+impl From<async_nats::Error> for MyError {
     fn from(err: async_nats::Error) -> Self {
         MyError::Nats(err)
     }
 }
+
+impl MyError {
+    /// The kind of error and message to send to clients.
+    pub(crate) fn kind_and_message(&self) -> (MatchmakerErrorKind, String) {
+        match self {
+            MyError::Bevygap(kind, msg) => (*kind, msg.clone()),
+            MyError::Backend(BackendError::Rejected(kind, msg)) => (*kind, msg.clone()),
+            MyError::Nats(e) => (MatchmakerErrorKind::Internal, format!("NATS error: {e:?}")),
+            MyError::Backend(e) => (
+                MatchmakerErrorKind::UpstreamEdgegap,
+                format!("Session backend error: {e}"),
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for MyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MyError::Backend(e) => write!(f, "session backend error: {e}"),
+            MyError::Nats(e) => write!(f, "nats error: {e}"),
+            MyError::Bevygap(kind, msg) => write!(f, "{kind:?}: {msg}"),
+        }
+    }
+}
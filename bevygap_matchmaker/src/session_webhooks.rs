@@ -0,0 +1,78 @@
+use crate::MatchmakerState;
+use futures::StreamExt;
+use log::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Lets session requests wait for the webhook that says their session is ready,
+/// instead of hammering the backend with polls.
+///
+/// A webhook can arrive before the request starts waiting (the session is created, then we
+/// register), so a notification for an unknown session_id is kept until it's claimed or
+/// goes stale. Stale entries belong to other matchmaker instances or abandoned requests.
+#[derive(Clone, Default)]
+pub(crate) struct SessionReadyNotifier {
+    waiters: Arc<Mutex<HashMap<String, (Arc<Notify>, Instant)>>>,
+}
+
+impl SessionReadyNotifier {
+    /// Get the Notify that fires when this session is reported ready.
+    pub(crate) fn register(&self, session_id: &str) -> Arc<Notify> {
+        let mut waiters = self.waiters.lock().unwrap();
+        waiters
+            .entry(session_id.to_string())
+            .or_insert_with(|| (Arc::new(Notify::new()), Instant::now()))
+            .0
+            .clone()
+    }
+
+    pub(crate) fn unregister(&self, session_id: &str) {
+        self.waiters.lock().unwrap().remove(session_id);
+    }
+
+    fn notify_ready(&self, session_id: &str) {
+        let mut waiters = self.waiters.lock().unwrap();
+        let max_age = Duration::from_secs(crate::MAX_SESSION_CREATION_SECONDS);
+        waiters.retain(|_, (_, created)| created.elapsed() < max_age);
+        // notify_one stores a permit if nobody is waiting yet, so this can't be missed.
+        waiters
+            .entry(session_id.to_string())
+            .or_insert_with(|| (Arc::new(Notify::new()), Instant::now()))
+            .0
+            .notify_one();
+    }
+}
+
+/// Subscribes to "webhook.session", which bevygap_webhook_sink publishes Edgegap's session
+/// callbacks to, and wakes any request waiting on a session that became ready.
+pub(crate) async fn session_webhook_listener(
+    state: &MatchmakerState,
+) -> Result<(), async_nats::Error> {
+    let client = state.nats_client();
+    let subject = state.nats.namespace().webhook("session");
+    let mut sub = client.subscribe(subject.clone()).await?;
+    info!("Listening for session webhooks on '{subject}'");
+
+    while let Some(message) = sub.next().await {
+        let body: serde_json::Value = match serde_json::from_slice(&message.payload) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Ignoring undecodable session webhook: {e}");
+                continue;
+            }
+        };
+        let Some(session_id) = body.get("session_id").and_then(|v| v.as_str()) else {
+            warn!("Ignoring session webhook without session_id: {body}");
+            continue;
+        };
+        let ready = body.get("ready").and_then(|v| v.as_bool()).unwrap_or(false);
+        debug!("Session webhook for {session_id}, ready: {ready}");
+        if ready {
+            state.session_ready_notifier.notify_ready(session_id);
+        }
+    }
+    warn!("Session webhook listener exiting");
+    Ok(())
+}
@@ -0,0 +1,471 @@
+//! Keeps some idle deployments running, so new sessions don't have to wait for a deploy.
+//!
+//! For each --warm-pool NAME:VERSION:REGION:SIZE, the pool manager deploys gameservers until
+//! SIZE of them are starting or idle. A deployment becomes idle once its gameserver announces
+//! itself on "gameserver.contexts". New sessions for that app claim an idle deployment if
+//! there is one, and the pool is topped up again.
+//!
+//! Requests that name a region only use that region's pool. Otherwise the clients' beacon
+//! locations are compared with where each idle deployment says it is, and the nearest one
+//! within MAX_CLAIM_DISTANCE_KM of every client is used. Clients with no region or location
+//! don't use the warm pool, since it could put them on the far side of the world.
+//!
+//! Pool levels are logged when they change, and published to "matchmaker.metrics.warm_pool".
+use crate::session_backend::{BackendError, ClientLocation, DeploySpec};
+use crate::{AppEntry, MatchmakerState};
+use bevygap_shared::names::WARM_POOL_METRICS;
+use log::*;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+const POOL_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Deployments that haven't announced themselves by then probably failed to start.
+const MAX_DEPLOY_SECONDS: u64 = 180;
+/// Edgegap stops deployments that go 10 minutes without a session, so replace them before then.
+const MAX_IDLE_SECONDS: u64 = 480;
+/// Requests without a region only get warm deployments at most this far from each client.
+const MAX_CLAIM_DISTANCE_KM: f64 = 2500.0;
+
+/// One --warm-pool setting.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PoolSpec {
+    pub app_name: String,
+    pub app_version: String,
+    pub region: String,
+    pub size: usize,
+}
+
+impl PoolSpec {
+    /// Parses NAME:VERSION:REGION:SIZE
+    pub(crate) fn parse(s: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let [app_name, app_version, region, size] = parts.as_slice() else {
+            return Err(format!("expected NAME:VERSION:REGION:SIZE, got '{s}'"));
+        };
+        let size = size
+            .parse::<usize>()
+            .map_err(|_| format!("invalid pool size in '{s}'"))?;
+        Ok(Self {
+            app_name: app_name.to_string(),
+            app_version: app_version.to_string(),
+            region: region.to_string(),
+            size,
+        })
+    }
+
+    fn is_for(&self, app: &AppEntry) -> bool {
+        self.app_name == app.app_name && self.app_version == app.app_version
+    }
+}
+
+impl std::fmt::Display for PoolSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} @ {} in {}",
+            self.app_name, self.app_version, self.region
+        )
+    }
+}
+
+struct PooledDeployment {
+    request_id: String,
+    deployed: Instant,
+    /// When the gameserver announced itself, and so is ready for a session.
+    idle_since: Option<Instant>,
+    /// (latitude, longitude), from the gameserver's announcement.
+    location: Option<(f64, f64)>,
+}
+
+/// Great-circle distance, near enough for picking a server.
+fn distance_km((lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64)) -> f64 {
+    const EARTH_RADIUS_KM: f64 = 6371.0;
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (lon2 - lon1).to_radians();
+    let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+struct Pool {
+    spec: PoolSpec,
+    deployments: Vec<PooledDeployment>,
+    /// Total deployments handed out to sessions.
+    claimed: u64,
+    /// (idle, starting) when we last logged, so we only log changes.
+    last_logged: (usize, usize),
+}
+
+impl Pool {
+    fn idle(&self) -> usize {
+        self.deployments
+            .iter()
+            .filter(|d| d.idle_since.is_some())
+            .count()
+    }
+
+    fn starting(&self) -> usize {
+        self.deployments.len() - self.idle()
+    }
+}
+
+/// Pool levels, as published for metrics.
+#[derive(Serialize, Debug)]
+struct PoolStats {
+    app_name: String,
+    app_version: String,
+    region: String,
+    target: usize,
+    idle: usize,
+    starting: usize,
+    claimed: u64,
+}
+
+/// An idle deployment taken from a pool. It must end up with a session, or back in the pool
+/// via [`WarmPool::unclaim`], or it's left running unused.
+pub(crate) struct ClaimedDeployment {
+    pool_index: usize,
+    deployment: PooledDeployment,
+}
+
+impl ClaimedDeployment {
+    pub(crate) fn request_id(&self) -> &str {
+        &self.deployment.request_id
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct WarmPool {
+    pools: Arc<Mutex<Vec<Pool>>>,
+    /// Wakes the pool manager early, when a deployment is claimed.
+    top_up: Arc<Notify>,
+}
+
+impl WarmPool {
+    pub(crate) fn new(specs: Vec<PoolSpec>) -> Self {
+        let pools = specs
+            .into_iter()
+            .map(|spec| Pool {
+                spec,
+                deployments: Vec::new(),
+                claimed: 0,
+                last_logged: (0, 0),
+            })
+            .collect();
+        Self {
+            pools: Arc::new(Mutex::new(pools)),
+            top_up: Arc::default(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pools.lock().unwrap().is_empty()
+    }
+
+    /// Called when a gameserver announces itself. Marks it idle if it's one of ours.
+    pub(crate) fn announced(&self, request_id: &str, location: Option<(f64, f64)>) {
+        let mut pools = self.pools.lock().unwrap();
+        for pool in pools.iter_mut() {
+            if let Some(d) = pool
+                .deployments
+                .iter_mut()
+                .find(|d| d.request_id == request_id)
+            {
+                info!("Warm pool deployment {request_id} is ready ({})", pool.spec);
+                d.idle_since.get_or_insert_with(Instant::now);
+                d.location = location;
+                return;
+            }
+        }
+    }
+
+    /// Takes an idle deployment for this app, if there is one.
+    /// With a region, only that region's pool is used. Without, the deployment nearest the
+    /// clients is used, if it's near enough to all of them.
+    pub(crate) fn claim(
+        &self,
+        app: &AppEntry,
+        region: Option<&str>,
+        locations: &[ClientLocation],
+    ) -> Option<ClaimedDeployment> {
+        let mut pools = self.pools.lock().unwrap();
+        // (pool index, deployment index, furthest client's distance)
+        let mut best: Option<(usize, usize, f64)> = None;
+        for (pool_index, pool) in pools.iter().enumerate() {
+            if !pool.spec.is_for(app) || region.is_some_and(|region| region != pool.spec.region) {
+                continue;
+            }
+            for (index, d) in pool.deployments.iter().enumerate() {
+                if d.idle_since.is_none() {
+                    continue;
+                }
+                let distance = match (region, d.location) {
+                    (Some(_), _) => 0.0,
+                    (None, Some(location)) if !locations.is_empty() => locations
+                        .iter()
+                        .map(|l| distance_km(location, (l.latitude, l.longitude)))
+                        .fold(0.0, f64::max),
+                    (None, _) => continue,
+                };
+                if distance <= MAX_CLAIM_DISTANCE_KM
+                    && best.map_or(true, |(_, _, best)| distance < best)
+                {
+                    best = Some((pool_index, index, distance));
+                }
+            }
+        }
+        let (pool_index, index, _) = best?;
+        let pool = &mut pools[pool_index];
+        let deployment = pool.deployments.remove(index);
+        pool.claimed += 1;
+        info!(
+            "Claimed warm pool deployment {} ({})",
+            deployment.request_id, pool.spec
+        );
+        self.top_up.notify_one();
+        Some(ClaimedDeployment {
+            pool_index,
+            deployment,
+        })
+    }
+
+    /// Puts a claimed deployment back in its pool, eg because creating a session on it failed.
+    /// It keeps its idle time, so it is still replaced once it has been idle too long.
+    pub(crate) fn unclaim(&self, claimed: ClaimedDeployment) {
+        let mut pools = self.pools.lock().unwrap();
+        let pool = &mut pools[claimed.pool_index];
+        info!(
+            "Returning warm pool deployment {} ({})",
+            claimed.deployment.request_id, pool.spec
+        );
+        pool.claimed = pool.claimed.saturating_sub(1);
+        pool.deployments.push(claimed.deployment);
+    }
+
+    /// Removes deployments that never started, or have been idle too long. Returns how many
+    /// new deployments each pool needs, and the request ids of the removed ones, which still
+    /// need stopping.
+    fn prune_and_count_missing(&self) -> (Vec<(usize, PoolSpec, usize)>, Vec<String>) {
+        let mut pools = self.pools.lock().unwrap();
+        let max_deploy = Duration::from_secs(MAX_DEPLOY_SECONDS);
+        let max_idle = Duration::from_secs(MAX_IDLE_SECONDS);
+        let mut missing = Vec::new();
+        let mut pruned = Vec::new();
+        for (index, pool) in pools.iter_mut().enumerate() {
+            pool.deployments.retain(|d| {
+                let keep = match d.idle_since {
+                    None if d.deployed.elapsed() > max_deploy => {
+                        warn!(
+                            "Warm pool deployment {} never announced itself, replacing it",
+                            d.request_id
+                        );
+                        false
+                    }
+                    Some(idle_since) if idle_since.elapsed() > max_idle => {
+                        info!(
+                            "Warm pool deployment {} was idle too long, replacing it",
+                            d.request_id
+                        );
+                        false
+                    }
+                    _ => true,
+                };
+                if !keep {
+                    pruned.push(d.request_id.clone());
+                }
+                keep
+            });
+            let count = pool.spec.size.saturating_sub(pool.deployments.len());
+            if count > 0 {
+                missing.push((index, pool.spec.clone(), count));
+            }
+        }
+        (missing, pruned)
+    }
+
+    fn add(&self, index: usize, request_id: String) {
+        self.pools.lock().unwrap()[index]
+            .deployments
+            .push(PooledDeployment {
+                request_id,
+                deployed: Instant::now(),
+                idle_since: None,
+                location: None,
+            });
+    }
+
+    /// Logs pools whose levels changed, and returns everyone's levels.
+    fn stats(&self) -> Vec<PoolStats> {
+        let mut pools = self.pools.lock().unwrap();
+        pools
+            .iter_mut()
+            .map(|pool| {
+                let levels = (pool.idle(), pool.starting());
+                if levels != pool.last_logged {
+                    info!(
+                        "Warm pool {}: {} idle, {} starting, target {}",
+                        pool.spec, levels.0, levels.1, pool.spec.size
+                    );
+                    pool.last_logged = levels;
+                }
+                PoolStats {
+                    app_name: pool.spec.app_name.clone(),
+                    app_version: pool.spec.app_version.clone(),
+                    region: pool.spec.region.clone(),
+                    target: pool.spec.size,
+                    idle: levels.0,
+                    starting: levels.1,
+                    claimed: pool.claimed,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Keeps the pools topped up. Runs every few seconds, or as soon as a deployment is claimed.
+pub(crate) async fn warm_pool_manager(state: &MatchmakerState) -> Result<(), async_nats::Error> {
+    info!("Warm pool manager started");
+    let pool = &state.warm_pool;
+    let mut interval = tokio::time::interval(POOL_CHECK_INTERVAL);
+    loop {
+        let (missing, pruned) = pool.prune_and_count_missing();
+        for request_id in pruned {
+            stop_deployment(state, &request_id).await;
+        }
+        for (index, spec, count) in missing {
+            for _ in 0..count {
+                let deploy_spec = DeploySpec {
+                    app_name: spec.app_name.clone(),
+                    app_version: spec.app_version.clone(),
+                    region: spec.region.clone(),
+                };
+                match state.backend().deploy(deploy_spec).await {
+                    Ok(request_id) => {
+                        info!("Deployed {request_id} for warm pool {spec}");
+                        pool.add(index, request_id);
+                    }
+                    Err(e) => {
+                        error!("Failed to deploy for warm pool {spec}: {e}");
+                        // try again next time round
+                        break;
+                    }
+                }
+            }
+        }
+
+        let stats = pool.stats();
+        // pool levels are published as json, every time the pool manager runs.
+        let payload = serde_json::to_vec(&stats).unwrap();
+        state
+            .nats_client()
+            .publish(
+                state.nats.namespace().subject(WARM_POOL_METRICS),
+                payload.into(),
+            )
+            .await?;
+
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = pool.top_up.notified() => {}
+        }
+    }
+}
+
+/// Stops a deployment that was removed from its pool, so it doesn't run until Edgegap notices.
+pub(crate) async fn stop_deployment(state: &MatchmakerState, request_id: &str) {
+    match state.backend().stop_deployment(request_id).await {
+        Ok(()) => info!("Stopped warm pool deployment {request_id}"),
+        Err(BackendError::NotFound(_) | BackendError::Gone(_)) => {
+            debug!("Warm pool deployment {request_id} was already stopped")
+        }
+        Err(e) => warn!("Failed to stop warm pool deployment {request_id}: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::Keyring;
+
+    const LONDON: (f64, f64) = (51.5, -0.1);
+    const FRANKFURT: (f64, f64) = (50.1, 8.7);
+
+    fn app() -> AppEntry {
+        AppEntry {
+            app_name: "spacepit_server".to_string(),
+            app_version: "v1".to_string(),
+            protocol_id: 1982,
+            keys: Keyring::new([0; 32]),
+        }
+    }
+
+    fn client((latitude, longitude): (f64, f64)) -> ClientLocation {
+        ClientLocation {
+            ip: "81.128.157.123".to_string(),
+            latitude,
+            longitude,
+        }
+    }
+
+    /// One pool per (region, deployment location), each with one idle deployment.
+    fn warm_pool(deployments: &[(&str, (f64, f64))]) -> WarmPool {
+        let specs = deployments
+            .iter()
+            .map(|(region, _)| PoolSpec::parse(&format!("spacepit_server:v1:{region}:1")).unwrap())
+            .collect();
+        let pool = WarmPool::new(specs);
+        for (index, (region, location)) in deployments.iter().enumerate() {
+            let request_id = format!("{region}-{index}");
+            pool.add(index, request_id.clone());
+            pool.announced(&request_id, Some(*location));
+        }
+        pool
+    }
+
+    fn claim(
+        pool: &WarmPool,
+        region: Option<&str>,
+        locations: &[ClientLocation],
+    ) -> Option<String> {
+        pool.claim(&app(), region, locations)
+            .map(|claimed| claimed.request_id().to_string())
+    }
+
+    #[test]
+    fn distance() {
+        assert!((distance_km(LONDON, FRANKFURT) - 640.0).abs() < 10.0);
+        assert_eq!(distance_km(LONDON, LONDON), 0.0);
+    }
+
+    #[test]
+    fn claims_only_from_the_given_region() {
+        let pool = warm_pool(&[("Europe", LONDON)]);
+        assert_eq!(claim(&pool, Some("Asia"), &[]), None);
+        assert_eq!(claim(&pool, Some("Europe"), &[]), Some("Europe-0".into()));
+        assert_eq!(claim(&pool, Some("Europe"), &[]), None);
+    }
+
+    #[test]
+    fn claims_nearest_to_the_clients_without_a_region() {
+        let pool = warm_pool(&[("Europe", LONDON), ("Germany", FRANKFURT)]);
+        let berlin = client((52.5, 13.4));
+        assert_eq!(claim(&pool, None, &[berlin]), Some("Germany-1".into()));
+        let paris = client((48.9, 2.35));
+        assert_eq!(claim(&pool, None, &[paris]), Some("Europe-0".into()));
+    }
+
+    #[test]
+    fn skips_far_away_deployments_without_a_region() {
+        let pool = warm_pool(&[("Europe", LONDON)]);
+        assert_eq!(claim(&pool, None, &[]), None);
+        let sydney = client((-33.9, 151.2));
+        assert_eq!(claim(&pool, None, &[sydney]), None);
+        // everyone needs to be near enough.
+        let paris = client((48.9, 2.35));
+        let sydney = client((-33.9, 151.2));
+        assert_eq!(claim(&pool, None, &[paris.clone(), sydney]), None);
+        assert_eq!(claim(&pool, None, &[paris]), Some("Europe-0".into()));
+    }
+}
@@ -0,0 +1,312 @@
+//! Player authentication, so only your players can make the matchmaker create sessions.
+//!
+//! Clients send a bearer token, either in the `Authorization` header, or as `auth_token` in the
+//! websocket's RequestSession message (browsers can't set headers on websockets).
+//! The verified player is forwarded to the matchmaker as the request's `identity`.
+use axum::http::HeaderMap;
+use bevygap_shared::protocol::PlayerIdentity;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use log::*;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use crate::Settings;
+
+/// Decides who is making a request, from the token they sent.
+pub(crate) trait Authenticator: Send + Sync {
+    /// Returns the verified player, or why the token was rejected.
+    fn authenticate(&self, token: &str) -> Result<PlayerIdentity, String>;
+}
+
+/// Accepts JWTs signed with any of the configured HS256 secrets or RS256 public keys.
+pub(crate) struct JwtAuthenticator {
+    keys: Vec<(Algorithm, DecodingKey)>,
+    validation: Validation,
+    /// Claims copied into the PlayerIdentity, for the gameserver.
+    forward_claims: Vec<String>,
+}
+
+/// The claims we care about. `sub` is the player id.
+#[derive(Deserialize, Debug)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl JwtAuthenticator {
+    /// None if no keys are configured, in which case requests aren't authenticated.
+    pub(crate) fn from_settings(settings: &Settings) -> Result<Option<Self>, String> {
+        let mut keys = Vec::new();
+        for secret in &settings.jwt_hs256_secret {
+            keys.push((
+                Algorithm::HS256,
+                DecodingKey::from_secret(secret.as_bytes()),
+            ));
+        }
+        for path in &settings.jwt_rs256_public_key {
+            let pem = std::fs::read(path)
+                .map_err(|e| format!("Can't read RS256 public key {path}: {e}"))?;
+            let key = DecodingKey::from_rsa_pem(&pem)
+                .map_err(|e| format!("Invalid RS256 public key {path}: {e}"))?;
+            keys.push((Algorithm::RS256, key));
+        }
+        if keys.is_empty() {
+            return Ok(None);
+        }
+        let mut validation = Validation::default();
+        validation.algorithms = vec![Algorithm::HS256, Algorithm::RS256];
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        if let Some(issuer) = &settings.jwt_issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &settings.jwt_audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        Ok(Some(Self {
+            keys,
+            validation,
+            forward_claims: settings.jwt_forward_claim.clone(),
+        }))
+    }
+
+    fn identity(&self, claims: Claims) -> PlayerIdentity {
+        let forwarded: BTreeMap<String, String> = self
+            .forward_claims
+            .iter()
+            .filter_map(|name| {
+                let value = match claims.other.get(name)? {
+                    serde_json::Value::String(s) => s.clone(),
+                    value => value.to_string(),
+                };
+                Some((name.clone(), value))
+            })
+            .collect();
+        PlayerIdentity {
+            display_name: claims.name.unwrap_or_else(|| claims.sub.clone()),
+            player_id: claims.sub,
+            claims: forwarded,
+        }
+    }
+}
+
+impl Authenticator for JwtAuthenticator {
+    fn authenticate(&self, token: &str) -> Result<PlayerIdentity, String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| format!("Bad token: {e}"))?;
+        let mut last_err = "No key for token algorithm".to_string();
+        for (alg, key) in self.keys.iter().filter(|(alg, _)| *alg == header.alg) {
+            let mut validation = self.validation.clone();
+            validation.algorithms = vec![*alg];
+            match jsonwebtoken::decode::<Claims>(token, key, &validation) {
+                Ok(data) => return Ok(self.identity(data.claims)),
+                Err(e) => last_err = format!("Invalid token: {e}"),
+            }
+        }
+        Err(last_err)
+    }
+}
+
+/// The token from an `Authorization: Bearer xxx` header, if there is one.
+pub(crate) fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers
+        .get(axum::http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?;
+    let token = value
+        .strip_prefix("Bearer ")
+        .or_else(|| value.strip_prefix("bearer "))?;
+    Some(token.trim().to_string())
+}
+
+/// Checks the token, if authentication is enabled.
+/// Ok(None) means authentication is disabled, so there's nobody to vouch for.
+pub(crate) fn authenticate(
+    authenticator: Option<&dyn Authenticator>,
+    token: Option<&str>,
+) -> Result<Option<PlayerIdentity>, String> {
+    let Some(authenticator) = authenticator else {
+        return Ok(None);
+    };
+    let Some(token) = token else {
+        return Err("Authentication required".to_string());
+    };
+    let identity = authenticator.authenticate(token).inspect_err(|e| {
+        warn!("Rejecting request: {e}");
+    })?;
+    info!("Authenticated player {}", identity.player_id);
+    Ok(Some(identity))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    const SECRET: &str = "hunter2";
+    /// A key pair made just for these tests.
+    const RS256_PRIVATE_KEY: &[u8] = include_bytes!("../testdata/jwt_rs256.pem");
+    const RS256_PUBLIC_KEY: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/jwt_rs256.pub.pem");
+
+    fn authenticator(args: &[&str]) -> JwtAuthenticator {
+        let args = std::iter::once("bevygap_matchmaker_httpd").chain(args.iter().copied());
+        JwtAuthenticator::from_settings(&Settings::parse_from(args))
+            .unwrap()
+            .unwrap()
+    }
+
+    fn in_an_hour() -> u64 {
+        jsonwebtoken::get_current_timestamp() + 3600
+    }
+
+    fn hs256(claims: serde_json::Value, secret: &[u8]) -> String {
+        let key = EncodingKey::from_secret(secret);
+        jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &key).unwrap()
+    }
+
+    fn rs256(claims: serde_json::Value) -> String {
+        let key = EncodingKey::from_rsa_pem(RS256_PRIVATE_KEY).unwrap();
+        jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &key).unwrap()
+    }
+
+    #[test]
+    fn no_keys_means_no_authentication() {
+        let settings = Settings::parse_from(["bevygap_matchmaker_httpd"]);
+        assert!(JwtAuthenticator::from_settings(&settings)
+            .unwrap()
+            .is_none());
+        assert_eq!(authenticate(None, None), Ok(None));
+    }
+
+    #[test]
+    fn hs256_token() {
+        let auth = authenticator(&["--jwt-hs256-secret", SECRET]);
+        let token = hs256(
+            json!({"sub": "p1", "name": "Player One", "exp": in_an_hour()}),
+            SECRET.as_bytes(),
+        );
+        let identity = auth.authenticate(&token).unwrap();
+        assert_eq!(identity.player_id, "p1");
+        assert_eq!(identity.display_name, "Player One");
+        assert!(identity.claims.is_empty());
+
+        let token = hs256(json!({"sub": "p1", "exp": in_an_hour()}), b"wrong");
+        assert!(auth.authenticate(&token).is_err());
+    }
+
+    #[test]
+    fn rs256_token() {
+        let auth = authenticator(&["--jwt-rs256-public-key", RS256_PUBLIC_KEY]);
+        let token = rs256(json!({"sub": "p2", "exp": in_an_hour()}));
+        let identity = auth.authenticate(&token).unwrap();
+        assert_eq!(identity.player_id, "p2");
+        // no name, so players are shown by id.
+        assert_eq!(identity.display_name, "p2");
+    }
+
+    #[test]
+    fn wrong_algorithm_for_key() {
+        let auth = authenticator(&["--jwt-rs256-public-key", RS256_PUBLIC_KEY]);
+        // signing with the public key as an HS256 secret mustn't get past an RS256 key.
+        let public_key = std::fs::read(RS256_PUBLIC_KEY).unwrap();
+        let token = hs256(json!({"sub": "p1", "exp": in_an_hour()}), &public_key);
+        assert!(auth.authenticate(&token).is_err());
+
+        let auth = authenticator(&["--jwt-hs256-secret", SECRET]);
+        let token = rs256(json!({"sub": "p1", "exp": in_an_hour()}));
+        assert!(auth.authenticate(&token).is_err());
+    }
+
+    #[test]
+    fn expiry_is_required() {
+        let auth = authenticator(&["--jwt-hs256-secret", SECRET]);
+        let expired = jsonwebtoken::get_current_timestamp() - 3600;
+        let token = hs256(json!({"sub": "p1", "exp": expired}), SECRET.as_bytes());
+        assert!(auth.authenticate(&token).is_err());
+        let token = hs256(json!({"sub": "p1"}), SECRET.as_bytes());
+        assert!(auth.authenticate(&token).is_err());
+        let token = hs256(json!({"exp": in_an_hour()}), SECRET.as_bytes());
+        assert!(auth.authenticate(&token).is_err());
+    }
+
+    #[test]
+    fn issuer_and_audience_are_checked() {
+        let auth = authenticator(&[
+            "--jwt-hs256-secret",
+            SECRET,
+            "--jwt-issuer",
+            "https://auth.example.com",
+            "--jwt-audience",
+            "spacepit",
+        ]);
+        let token = |iss: &str, aud: &str| {
+            let claims = json!({"sub": "p1", "exp": in_an_hour(), "iss": iss, "aud": aud});
+            hs256(claims, SECRET.as_bytes())
+        };
+        assert!(auth
+            .authenticate(&token("https://auth.example.com", "spacepit"))
+            .is_ok());
+        assert!(auth
+            .authenticate(&token("https://evil.example.com", "spacepit"))
+            .is_err());
+        assert!(auth
+            .authenticate(&token("https://auth.example.com", "other_game"))
+            .is_err());
+        let no_iss_or_aud = hs256(json!({"sub": "p1", "exp": in_an_hour()}), SECRET.as_bytes());
+        assert!(auth.authenticate(&no_iss_or_aud).is_err());
+    }
+
+    #[test]
+    fn only_chosen_claims_are_forwarded() {
+        let auth = authenticator(&[
+            "--jwt-hs256-secret",
+            SECRET,
+            "--jwt-forward-claim",
+            "team",
+            "--jwt-forward-claim",
+            "level",
+            "--jwt-forward-claim",
+            "missing",
+        ]);
+        let claims = json!({
+            "sub": "p1",
+            "exp": in_an_hour(),
+            "team": "red",
+            "level": 3,
+            "email": "p1@example.com",
+        });
+        let identity = auth
+            .authenticate(&hs256(claims, SECRET.as_bytes()))
+            .unwrap();
+        let expected = BTreeMap::from([
+            ("level".to_string(), "3".to_string()),
+            ("team".to_string(), "red".to_string()),
+        ]);
+        assert_eq!(identity.claims, expected);
+    }
+
+    #[test]
+    fn tokens_are_required_when_enabled() {
+        let auth = authenticator(&["--jwt-hs256-secret", SECRET]);
+        assert!(authenticate(Some(&auth), None).is_err());
+        assert!(authenticate(Some(&auth), Some("not a jwt")).is_err());
+        let token = hs256(json!({"sub": "p1", "exp": in_an_hour()}), SECRET.as_bytes());
+        let identity = authenticate(Some(&auth), Some(&token)).unwrap().unwrap();
+        assert_eq!(identity.player_id, "p1");
+    }
+
+    #[test]
+    fn bearer_tokens() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(bearer_token(&headers), None);
+        headers.insert("authorization", "Bearer abc.def ".parse().unwrap());
+        assert_eq!(bearer_token(&headers).as_deref(), Some("abc.def"));
+        headers.insert("authorization", "Basic dXNlcjpwYXNz".parse().unwrap());
+        assert_eq!(bearer_token(&headers), None);
+    }
+}
@@ -0,0 +1,64 @@
+//! Passes on the matchmaker's list of Edgegap beacons, so clients can measure their latency to
+//! each one before requesting a session.
+//!
+//! The list is a json array of [`bevygap_shared::protocol::Beacon`]. The client plugin only
+//! speaks websockets, so /matchmaker/beacons/ws sends it as one text message, then closes.
+use axum::body::Bytes;
+use axum::extract::ws::{CloseFrame, Message, WebSocketUpgrade};
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use bevygap_shared::names::MATCHMAKER_BEACONS;
+use log::*;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{request_error, status_code, AppState};
+
+async fn fetch_beacons(state: &AppState) -> Result<Bytes, (StatusCode, &'static str)> {
+    let request = async_nats::client::Request::new()
+        .timeout(Some(Duration::from_secs(10)))
+        .payload(Bytes::new());
+    match state
+        .bgnats
+        .client()
+        .send_request(
+            state.bgnats.namespace().subject(MATCHMAKER_BEACONS),
+            request,
+        )
+        .await
+    {
+        Ok(resp) => Ok(resp.payload),
+        Err(e) => {
+            warn!("Failed to fetch beacons: {e:?}");
+            let (kind, msg) = request_error(e.kind());
+            Err((status_code(kind), msg))
+        }
+    }
+}
+
+pub(crate) async fn beacons_handler(State(state): State<Arc<AppState>>) -> Response {
+    match fetch_beacons(&state).await {
+        Ok(payload) => ([(header::CONTENT_TYPE, "text/json")], payload).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+pub(crate) async fn beacons_handler_websocket(
+    State(state): State<Arc<AppState>>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |mut socket| async move {
+        let msg = match fetch_beacons(&state).await {
+            Ok(payload) => String::from_utf8_lossy(&payload).to_string(),
+            Err((_, e)) => format!("ERR {e}"),
+        };
+        let _ = socket.send(Message::Text(msg)).await;
+        let _ = socket
+            .send(Message::Close(Some(CloseFrame {
+                code: axum::extract::ws::close_code::NORMAL,
+                reason: std::borrow::Cow::from("Goodbye"),
+            })))
+            .await;
+    })
+}
@@ -1,6 +1,6 @@
 use async_nats::client::RequestErrorKind;
 use axum::extract::{Request, State};
-use axum::http::{header, HeaderValue, Method};
+use axum::http::{header, HeaderMap, HeaderValue, Method};
 use axum::routing::post;
 use axum::{
     extract::ConnectInfo,
@@ -11,17 +11,22 @@ use axum::{
     routing::{any, get},
     Router,
 };
+use bevygap_shared::names::SESSION_SERVICE;
 use bevygap_shared::nats::*;
+use bevygap_shared::protocol::MatchmakerErrorKind;
 use clap::Parser;
 use log::*;
 use serde::{de, Deserialize, Deserializer};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
 use std::{fmt, str::FromStr};
 use tower_http::cors::CorsLayer;
 use tracing_subscriber::{layer::*, util::*};
 
+mod auth;
+mod beacons;
+mod rate_limit;
 mod session_request_handler;
 mod session_request_handler_ws;
 
@@ -45,6 +50,67 @@ pub struct Settings {
     /// The default fake IP is near London, United Kindom.
     #[arg(long, default_value = "81.128.157.100")]
     fake_ip: String,
+
+    /// Let clients choose their IP with a ?client_ip= querystring param, for development.
+    ///
+    /// Sessions are placed, and requests rate limited, by this IP, so never set this in
+    /// production.
+    #[arg(long)]
+    dev_client_ip_param: bool,
+
+    /// The IP of a reverse proxy in front of this service, whose X-Forwarded-For header we
+    /// believe. Can be repeated. Without one, X-Forwarded-For is ignored, since clients can
+    /// send whatever they like in it.
+    #[arg(long, value_delimiter = ',')]
+    trusted_proxy: Vec<IpAddr>,
+
+    /// HS256 secret for verifying player JWTs. Can be repeated, eg while rotating secrets.
+    ///
+    /// If no JWT keys are given, requests aren't authenticated, and anyone who can reach
+    /// this service can create sessions.
+    #[arg(long, env = "JWT_HS256_SECRET", value_delimiter = ',')]
+    jwt_hs256_secret: Vec<String>,
+
+    /// Path to a PEM file with an RS256 public key for verifying player JWTs. Can be repeated.
+    #[arg(long)]
+    jwt_rs256_public_key: Vec<String>,
+
+    /// If set, player JWTs must have this `iss` claim.
+    #[arg(long)]
+    jwt_issuer: Option<String>,
+
+    /// If set, player JWTs must have this `aud` claim.
+    #[arg(long)]
+    jwt_audience: Option<String>,
+
+    /// A JWT claim to pass on to the gameserver, in the player's identity. Can be repeated.
+    /// Keep it small: the whole identity has to fit in a connect token's 256 bytes of user data.
+    #[arg(long)]
+    jwt_forward_claim: Vec<String>,
+
+    /// Session requests allowed per minute from each client IP. 0 for no limit.
+    #[arg(long, default_value_t = 10)]
+    ip_requests_per_minute: u32,
+
+    /// How many requests a client IP can make at once, before the per minute limit applies.
+    #[arg(long, default_value_t = 5)]
+    ip_request_burst: u32,
+
+    /// Session requests allowed per minute from each authenticated player. 0 for no limit.
+    #[arg(long, default_value_t = 10)]
+    player_requests_per_minute: u32,
+
+    /// How many requests a player can make at once, before the per minute limit applies.
+    #[arg(long, default_value_t = 5)]
+    player_request_burst: u32,
+
+    /// Most session requests being handled at once, from everyone. 0 for no limit.
+    #[arg(long, default_value_t = 200)]
+    max_in_flight_requests: usize,
+
+    /// NATS connection settings, which otherwise come from NATS_* env vars
+    #[command(flatten)]
+    nats: NatsArgs,
 }
 
 impl Settings {
@@ -56,6 +122,15 @@ impl Settings {
 pub(crate) struct AppState {
     pub(crate) bgnats: BevygapNats,
     pub(crate) settings: Settings,
+    /// None if authentication is disabled.
+    pub(crate) authenticator: Option<Box<dyn auth::Authenticator>>,
+    pub(crate) rate_limits: rate_limit::RateLimits,
+}
+
+impl AppState {
+    pub(crate) fn authenticator(&self) -> Option<&dyn auth::Authenticator> {
+        self.authenticator.as_deref()
+    }
 }
 
 #[tokio::main]
@@ -63,12 +138,28 @@ async fn main() {
     setup_logging();
     let settings = Settings::parse();
 
-    let bgnats = BevygapNats::new_and_connect("bevygap_matchmaker_httpd")
-        .await
-        .unwrap();
+    let bgnats = match settings.nats.config("bevygap_matchmaker_httpd") {
+        Ok(config) => BevygapNats::connect(&config).await,
+        Err(e) => Err(e.into()),
+    };
+    let bgnats = match bgnats {
+        Ok(bgnats) => bgnats,
+        Err(e) => {
+            error!("Failed to connect to NATS: {e}");
+            std::process::exit(1);
+        }
+    };
+    let authenticator = auth::JwtAuthenticator::from_settings(&settings)
+        .expect("Failed to set up JWT authentication")
+        .map(|jwt| Box::new(jwt) as Box<dyn auth::Authenticator>);
+    if authenticator.is_none() {
+        warn!("No JWT keys configured, player authentication is DISABLED");
+    }
     let app_state = Arc::new(AppState {
         bgnats,
         settings: settings.clone(),
+        authenticator,
+        rate_limits: rate_limit::RateLimits::from_settings(&settings),
     });
 
     info!(
@@ -108,6 +199,11 @@ async fn main() {
             "/matchmaker/ws",
             any(session_request_handler_ws::handler_websocket),
         )
+        .route("/matchmaker/beacons", get(beacons::beacons_handler))
+        .route(
+            "/matchmaker/beacons/ws",
+            any(beacons::beacons_handler_websocket),
+        )
         .layer(cors_layer)
         .with_state(app_state);
 
@@ -145,30 +241,38 @@ async fn wannaplay_handler(
     State(state): State<Arc<AppState>>,
     req: Request,
 ) -> Response {
-    //Result<impl IntoResponse, AppError> {
-    // client_ip is the one sent to Edgegap, to decide which server to assign the player to.
-    // We use one provided in the qs, otherwise the connecting IP of the http client.
-    let mut client_ip = params.client_ip.unwrap_or(addr.ip().to_string());
-    // Check for X-Forwarded-For header, since this is probably running behind a proxy
-    if let Some(forwarded_for) = req.headers().get("X-Forwarded-For") {
-        if let Ok(forwarded_ip) = forwarded_for.to_str() {
-            // Use the first IP in the X-Forwarded-For header
-            if let Some(first_ip) = forwarded_ip.split(',').next() {
-                client_ip = first_ip.trim().to_string();
-                info!("Using X-Forwarded-For IP: {}", client_ip);
-            }
-        }
-    }
+    let client_ip = get_client_ip(
+        params.client_ip.as_deref(),
+        &addr,
+        req.headers(),
+        &state.settings,
+    );
 
-    // TODO this default IP should be configurable too, for easier dev
-    if client_ip == "127.0.0.1" || client_ip == "::1" {
-        // localhost tends to spawn deployments in random places..
-        client_ip = state.settings.fake_ip.to_string();
-        warn!("Using fake IP, request came from localhost: {client_ip}");
+    let too_many = |e| (status_code(MatchmakerErrorKind::RateLimited), e).into_response();
+    if let Err(e) = state.rate_limits.check_ip(&client_ip) {
+        return too_many(e);
     }
+    let token = auth::bearer_token(req.headers());
+    let identity = match auth::authenticate(state.authenticator(), token.as_deref()) {
+        Ok(identity) => identity,
+        Err(e) => return (status_code(MatchmakerErrorKind::Unauthorized), e).into_response(),
+    };
+    if let Some(identity) = &identity {
+        if let Err(e) = state.rate_limits.check_player(&identity.player_id) {
+            return too_many(e);
+        }
+    }
+    let _permit = match state.rate_limits.start_request() {
+        Ok(permit) => permit,
+        Err(e) => return too_many(e),
+    };
 
     info!("wannaplay_handler req for ip {client_ip}");
-    let payload = format!("{{\"client_ip\":\"{client_ip}\"}}");
+    let payload = serde_json::json!({
+        "client_ip": client_ip,
+        "identity": identity,
+    })
+    .to_string();
 
     // this timeout should far exceed the cutoff time in the matchmaker.
     // it is merely a last line of defense.
@@ -179,7 +283,13 @@ async fn wannaplay_handler(
     match state
         .bgnats
         .client()
-        .send_request("session.gensession", request)
+        .send_request(
+            state
+                .bgnats
+                .namespace()
+                .subject(&format!("{SESSION_SERVICE}.gensession")),
+            request,
+        )
         .await
     {
         // Don't really understand the reasoning here, but if you respond to a service
@@ -187,13 +297,9 @@ async fn wannaplay_handler(
         // to figure out if it was actually an error?
         // see: https://github.com/nats-io/nats.rs/blob/main/async-nats/tests/service_tests.rs#L245
         Ok(resp) => {
-            if let Some((code, msg)) = maybe_message_error(&resp) {
+            if let Some((kind, msg)) = maybe_message_error(&resp) {
                 error!("Got error matchmaker response: {:?}", msg);
-                (
-                    StatusCode::from_u16(code as u16).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
-                    msg,
-                )
-                    .into_response()
+                (status_code(kind), msg).into_response()
             } else {
                 info!("Got OK matchmaker response: {:?}", resp);
                 ([(header::CONTENT_TYPE, "text/json")], resp.payload).into_response()
@@ -201,34 +307,99 @@ async fn wannaplay_handler(
         }
         Err(e) => {
             warn!("Got Err matchmaker response: {:?}", e);
-            match e.kind() {
-                RequestErrorKind::TimedOut => {
-                    (StatusCode::REQUEST_TIMEOUT, "Request timeout").into_response()
-                }
-                RequestErrorKind::NoResponders => {
-                    (StatusCode::SERVICE_UNAVAILABLE, "No service responders").into_response()
-                }
-                RequestErrorKind::Other => {
-                    (StatusCode::INTERNAL_SERVER_ERROR, "Unhandled error").into_response()
-                }
+            let (kind, msg) = request_error(e.kind());
+            (status_code(kind), msg).into_response()
+        }
+    }
+}
+
+/// Logic to decide what to use as the clients IP address for the purposes of Edgegap sessions,
+/// and rate limiting.
+///
+/// In order of preference:
+/// ?client_ip=XXX querystring param, if --dev-client-ip-param is set
+/// X-Forwarded-For header, if the request came from a --trusted-proxy
+/// the source IP of the http client
+///
+/// Additionally if the above yields a localhost address, we replace it with
+/// settings.fake_ip, which is also useful for dev.
+pub(crate) fn get_client_ip(
+    qs_client_ip: Option<&str>,
+    addr: &SocketAddr,
+    headers: &HeaderMap,
+    settings: &Settings,
+) -> String {
+    let mut ip = addr.ip().to_canonical();
+    // each proxy appends who it heard from, so working back from the end, the first
+    // address a trusted proxy didn't add is the client's.
+    if settings.trusted_proxy.contains(&ip) {
+        let forwarded: Vec<&str> = headers
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .collect();
+        for hop in forwarded.iter().rev() {
+            let Ok(hop_ip) = hop.parse::<IpAddr>() else {
+                warn!("Ignoring the rest of X-Forwarded-For, bad entry: {hop:?}");
+                break;
+            };
+            ip = hop_ip.to_canonical();
+            if !settings.trusted_proxy.contains(&ip) {
+                info!("Using X-Forwarded-For IP: {ip}");
+                break;
             }
         }
     }
+    let mut client_ip = ip.to_string();
+
+    if let Some(qs_client_ip) = qs_client_ip {
+        if settings.dev_client_ip_param {
+            client_ip = qs_client_ip.to_string();
+        } else {
+            warn!("Ignoring ?client_ip={qs_client_ip} from {client_ip}, --dev-client-ip-param isn't set");
+        }
+    }
+
+    if client_ip == "127.0.0.1" || client_ip == "::1" {
+        // localhost tends to spawn deployments in random places..
+        client_ip = settings.fake_ip.to_string();
+        warn!("Using fake IP, request came from localhost: {client_ip}");
+    }
+    client_ip
 }
 
-fn maybe_message_error(message: &async_nats::Message) -> Option<(usize, String)> {
+fn maybe_message_error(message: &async_nats::Message) -> Option<(MatchmakerErrorKind, String)> {
     let h = message.headers.clone()?;
     if let Some(code) = h.get(async_nats::service::NATS_SERVICE_ERROR_CODE) {
         let msg_str = h
             .get(async_nats::service::NATS_SERVICE_ERROR)
             .unwrap()
             .to_string();
-        Some((code.as_str().parse::<usize>().unwrap(), msg_str))
+        let code = code.as_str().parse::<u16>().unwrap_or_default();
+        Some((MatchmakerErrorKind::from_code(code), msg_str))
     } else {
         None
     }
 }
 
+/// The http status to answer with, for a kind of matchmaker error.
+pub(crate) fn status_code(kind: MatchmakerErrorKind) -> StatusCode {
+    StatusCode::from_u16(kind.code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// What went wrong when a NATS request to the matchmaker failed.
+pub(crate) fn request_error(kind: RequestErrorKind) -> (MatchmakerErrorKind, &'static str) {
+    match kind {
+        RequestErrorKind::TimedOut => (MatchmakerErrorKind::Timeout, "Request timeout"),
+        RequestErrorKind::NoResponders => {
+            (MatchmakerErrorKind::NoCapacity, "No service responders")
+        }
+        RequestErrorKind::Other => (MatchmakerErrorKind::Internal, "Unhandled error"),
+    }
+}
+
 /// Serde deserialization decorator to map empty Strings to None,
 pub(crate) fn empty_string_as_none<'de, D, T>(de: D) -> Result<Option<T>, D::Error>
 where
@@ -254,3 +425,82 @@ fn setup_logging() {
         .with(tracing_subscriber::fmt::Layer::default().compact())
         .init();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(args: &[&str]) -> Settings {
+        Settings::parse_from(
+            std::iter::once("bevygap_matchmaker_httpd").chain(args.iter().copied()),
+        )
+    }
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-For", value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn client_ip_from_peer() {
+        let settings = settings(&[]);
+        let addr: SocketAddr = "203.0.113.7:5000".parse().unwrap();
+        assert_eq!(
+            get_client_ip(None, &addr, &HeaderMap::new(), &settings),
+            "203.0.113.7"
+        );
+        // localhost is swapped for the fake IP.
+        let addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        assert_eq!(
+            get_client_ip(None, &addr, &HeaderMap::new(), &settings),
+            settings.fake_ip
+        );
+    }
+
+    #[test]
+    fn forwarded_for_ignored_from_untrusted_peer() {
+        let settings = settings(&["--trusted-proxy", "10.0.0.1"]);
+        let addr: SocketAddr = "203.0.113.7:5000".parse().unwrap();
+        let headers = forwarded_for("198.51.100.1");
+        assert_eq!(
+            get_client_ip(None, &addr, &headers, &settings),
+            "203.0.113.7"
+        );
+    }
+
+    #[test]
+    fn forwarded_for_from_trusted_proxy() {
+        let settings = settings(&["--trusted-proxy", "10.0.0.1,10.0.0.2"]);
+        let addr: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let headers = forwarded_for("198.51.100.1");
+        assert_eq!(
+            get_client_ip(None, &addr, &headers, &settings),
+            "198.51.100.1"
+        );
+        // the client can put anything at the front, so we take the last hop our proxies
+        // didn't add.
+        let headers = forwarded_for("1.1.1.1, 198.51.100.1, 10.0.0.2");
+        assert_eq!(
+            get_client_ip(None, &addr, &headers, &settings),
+            "198.51.100.1"
+        );
+        // IPv4-mapped IPv6 peers count as the proxy too.
+        let addr: SocketAddr = "[::ffff:10.0.0.1]:5000".parse().unwrap();
+        assert_eq!(
+            get_client_ip(None, &addr, &headers, &settings),
+            "198.51.100.1"
+        );
+    }
+
+    #[test]
+    fn client_ip_param_needs_dev_flag() {
+        let addr: SocketAddr = "203.0.113.7:5000".parse().unwrap();
+        let headers = HeaderMap::new();
+        let ip = get_client_ip(Some("198.51.100.1"), &addr, &headers, &settings(&[]));
+        assert_eq!(ip, "203.0.113.7");
+        let settings = settings(&["--dev-client-ip-param"]);
+        let ip = get_client_ip(Some("198.51.100.1"), &addr, &headers, &settings);
+        assert_eq!(ip, "198.51.100.1");
+    }
+}
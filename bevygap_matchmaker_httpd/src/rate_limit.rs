@@ -0,0 +1,204 @@
+//! Limits how fast session requests are passed on to the matchmaker, since each one can
+//! cost money in Edgegap sessions.
+//!
+//! Each client IP, and each authenticated player, gets a token bucket: a burst of requests,
+//! refilled at so many per minute. There's also a cap on requests being handled at once.
+//! Requests over a limit are answered with a 429.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::Settings;
+
+/// Most buckets we keep per limiter. At the cap, full buckets are forgotten, since they're the
+/// same as new ones, then the least recently used, down to [`EVICT_TO_BUCKETS`].
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+/// Evicting down to below the cap means we only do it every so often.
+const EVICT_TO_BUCKETS: usize = MAX_TRACKED_BUCKETS * 9 / 10;
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// A token bucket per key, eg per IP.
+struct KeyedLimiter {
+    /// 0 means no limit.
+    per_minute: u32,
+    burst: f64,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl KeyedLimiter {
+    fn new(per_minute: u32, burst: u32) -> Self {
+        Self {
+            per_minute,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Takes a token from the key's bucket, if there is one.
+    fn check(&self, key: &str) -> bool {
+        if self.per_minute == 0 {
+            return true;
+        }
+        let per_second = self.per_minute as f64 / 60.0;
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_BUCKETS && !buckets.contains_key(key) {
+            self.evict(&mut buckets, now, per_second);
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(TokenBucket {
+            tokens: self.burst,
+            last_refill: now,
+        });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(self.burst);
+        bucket.last_refill = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn evict(&self, buckets: &mut HashMap<String, TokenBucket>, now: Instant, per_second: f64) {
+        let burst = self.burst;
+        buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens + elapsed * per_second < burst
+        });
+        if buckets.len() <= EVICT_TO_BUCKETS {
+            return;
+        }
+        // lots of keys are still limited, eg someone cycling through IPs. Forget the ones we
+        // heard from longest ago, so the map can't grow without bound.
+        let mut by_age: Vec<(Instant, String)> = buckets
+            .iter()
+            .map(|(key, bucket)| (bucket.last_refill, key.clone()))
+            .collect();
+        let excess = buckets.len() - EVICT_TO_BUCKETS;
+        by_age.select_nth_unstable_by_key(excess - 1, |(last_refill, _)| *last_refill);
+        for (_, key) in &by_age[..excess] {
+            buckets.remove(key);
+        }
+    }
+}
+
+pub(crate) struct RateLimits {
+    per_ip: KeyedLimiter,
+    per_player: KeyedLimiter,
+    /// None means no limit.
+    in_flight: Option<Arc<Semaphore>>,
+}
+
+impl RateLimits {
+    pub(crate) fn from_settings(settings: &Settings) -> Self {
+        Self {
+            per_ip: KeyedLimiter::new(settings.ip_requests_per_minute, settings.ip_request_burst),
+            per_player: KeyedLimiter::new(
+                settings.player_requests_per_minute,
+                settings.player_request_burst,
+            ),
+            in_flight: (settings.max_in_flight_requests > 0)
+                .then(|| Arc::new(Semaphore::new(settings.max_in_flight_requests))),
+        }
+    }
+
+    pub(crate) fn check_ip(&self, client_ip: &str) -> Result<(), String> {
+        if self.per_ip.check(client_ip) {
+            Ok(())
+        } else {
+            Err("Too many requests from your IP, try again shortly".to_string())
+        }
+    }
+
+    pub(crate) fn check_player(&self, player_id: &str) -> Result<(), String> {
+        if self.per_player.check(player_id) {
+            Ok(())
+        } else {
+            Err("Too many requests for your player, try again shortly".to_string())
+        }
+    }
+
+    /// Hold the returned permit until the request is finished.
+    pub(crate) fn start_request(&self) -> Result<Option<OwnedSemaphorePermit>, String> {
+        let Some(in_flight) = &self.in_flight else {
+            return Ok(None);
+        };
+        in_flight
+            .clone()
+            .try_acquire_owned()
+            .map(Some)
+            .map_err(|_| "Matchmaker is busy, try again shortly".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Pretend the key was last seen `secs` ago, rather than waiting for a refill.
+    fn rewind(limiter: &KeyedLimiter, key: &str, secs: u64) {
+        let mut buckets = limiter.buckets.lock().unwrap();
+        let bucket = buckets.get_mut(key).unwrap();
+        bucket.last_refill -= Duration::from_secs(secs);
+    }
+
+    #[test]
+    fn burst_then_limited() {
+        let limiter = KeyedLimiter::new(6, 3);
+        assert!(limiter.check("1.2.3.4"));
+        assert!(limiter.check("1.2.3.4"));
+        assert!(limiter.check("1.2.3.4"));
+        assert!(!limiter.check("1.2.3.4"));
+        // other keys have their own bucket.
+        assert!(limiter.check("5.6.7.8"));
+    }
+
+    #[test]
+    fn refills_per_minute() {
+        // one token every 10 seconds.
+        let limiter = KeyedLimiter::new(6, 2);
+        assert!(limiter.check("a"));
+        assert!(limiter.check("a"));
+        assert!(!limiter.check("a"));
+        rewind(&limiter, "a", 5);
+        assert!(!limiter.check("a"));
+        rewind(&limiter, "a", 5);
+        assert!(limiter.check("a"));
+        assert!(!limiter.check("a"));
+        // a long wait refills the bucket, but only up to the burst.
+        rewind(&limiter, "a", 3600);
+        assert!(limiter.check("a"));
+        assert!(limiter.check("a"));
+        assert!(!limiter.check("a"));
+    }
+
+    #[test]
+    fn zero_per_minute_is_no_limit() {
+        let limiter = KeyedLimiter::new(0, 1);
+        for _ in 0..100 {
+            assert!(limiter.check("a"));
+        }
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn in_flight_cap() {
+        let limits = RateLimits {
+            per_ip: KeyedLimiter::new(0, 1),
+            per_player: KeyedLimiter::new(0, 1),
+            in_flight: Some(Arc::new(Semaphore::new(1))),
+        };
+        let permit = limits.start_request().unwrap();
+        assert!(permit.is_some());
+        assert!(limits.start_request().is_err());
+        drop(permit);
+        assert!(limits.start_request().is_ok());
+    }
+}
@@ -1,7 +1,9 @@
 use axum::body::Body;
 use axum::extract::{Path, Request, State};
 use axum::http::{header, HeaderMap, HeaderValue};
+use axum::response::Response;
 use axum::{extract::ConnectInfo, extract::Query, response::IntoResponse};
+use bevygap_shared::protocol::{MatchmakerErrorKind, SessionRequestFeedback};
 use log::*;
 use serde::Deserialize;
 use std::convert::Infallible;
@@ -10,7 +12,7 @@ use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio_stream::StreamExt as _;
 
-use crate::AppState;
+use crate::{status_code, AppState};
 
 #[derive(Debug, Deserialize)]
 #[allow(dead_code)]
@@ -30,12 +32,39 @@ pub(crate) async fn session_chunked_responder(
     Path((game_name, game_ver)): Path<(String, String)>,
     State(state): State<Arc<AppState>>,
     req: Request,
-) -> impl IntoResponse {
-    let client_ip = get_client_ip(&params, &addr, req.headers(), &state);
+) -> Response {
+    let client_ip = crate::get_client_ip(
+        params.client_ip.as_deref(),
+        &addr,
+        req.headers(),
+        &state.settings,
+    );
+
+    if let Err(e) = state.rate_limits.check_ip(&client_ip) {
+        return too_many_requests(e);
+    }
+    let token = crate::auth::bearer_token(req.headers());
+    let identity = match crate::auth::authenticate(state.authenticator(), token.as_deref()) {
+        Ok(identity) => identity,
+        Err(e) => return (status_code(MatchmakerErrorKind::Unauthorized), e).into_response(),
+    };
+    if let Some(identity) = &identity {
+        if let Err(e) = state.rate_limits.check_player(&identity.player_id) {
+            return too_many_requests(e);
+        }
+    }
+    let permit = match state.rate_limits.start_request() {
+        Ok(permit) => permit,
+        Err(e) => return too_many_requests(e),
+    };
 
     info!("session_chunked_responder for ip {client_ip}");
     // should include app name/ver?
-    let payload = format!("{{\"client_ip\":\"{client_ip}\"}}");
+    let payload = serde_json::json!({
+        "client_ip": client_ip,
+        "identity": identity,
+    })
+    .to_string();
 
     let client = state.bgnats.client().clone();
     let reply_inbox = client.new_inbox();
@@ -44,7 +73,10 @@ pub(crate) async fn session_chunked_responder(
     // https://docs.nats.io/reference/reference-protocols/nats-protocol
     client
         .publish_with_reply(
-            format!("matchmaker.request.{game_name}.{game_ver}"),
+            state
+                .bgnats
+                .namespace()
+                .matchmaker_request(&game_name, &game_ver),
             reply_inbox,
             payload.into(),
         )
@@ -57,6 +89,8 @@ pub(crate) async fn session_chunked_responder(
     let (tx, rx) = mpsc::channel::<String>(100);
 
     let _j = tokio::spawn(async move {
+        // counts as in flight until the matchmaker is done with it.
+        let _permit = permit;
         while let Some(msg) = response_subscriber.next().await {
             if msg.payload.is_empty() {
                 // info!("got empty response, breaking");
@@ -91,42 +125,17 @@ pub(crate) async fn session_chunked_responder(
         headers,
         Body::from_stream(stream), // Wrap the stream in an HTTP body for chunked transfer
     )
+        .into_response()
 }
 
-/// Logic to decide what to use as the clients IP address for the purposes of Edgegap sessions.
-///
-/// In order of preference:
-/// ?client_ip=XXX querystring param (for dev)
-/// X-Forwarded-For header (for proxy setups)
-/// the source IP of the http client
-///
-/// Additionally if they above yields a localhost address, we replace it with
-/// settings.fake_ip, which is also useful for dev.
-fn get_client_ip(
-    params: &QsParams,
-    addr: &SocketAddr,
-    headers: &HeaderMap,
-    state: &AppState,
-) -> String {
-    // client_ip is the one sent to Edgegap, to decide which server to assign the player to.
-    // We use one provided in the qs, otherwise the connecting IP of the http client.
-    let mut client_ip = params.client_ip.clone().unwrap_or(addr.ip().to_string());
-
-    // Check for X-Forwarded-For header, since this is probably running behind a proxy
-    if let Some(forwarded_for) = headers.get("X-Forwarded-For") {
-        if let Ok(forwarded_ip) = forwarded_for.to_str() {
-            // Use the first IP in the X-Forwarded-For header
-            if let Some(first_ip) = forwarded_ip.split(',').next() {
-                client_ip = first_ip.trim().to_string();
-                info!("Using X-Forwarded-For IP: {}", client_ip);
-            }
-        }
-    }
-    // TODO this default IP should be configurable too, for easier dev
-    if client_ip == "127.0.0.1" || client_ip == "::1" {
-        // localhost tends to spawn deployments in random places..
-        client_ip = state.settings.fake_ip.to_string();
-        warn!("Using fake IP, request came from localhost: {client_ip}");
-    }
-    client_ip
+/// Same body as the websocket would get, so clients can handle it the same way.
+fn too_many_requests(msg: String) -> Response {
+    let kind = MatchmakerErrorKind::RateLimited;
+    let feedback = SessionRequestFeedback::error(kind, msg);
+    (
+        status_code(kind),
+        [(header::CONTENT_TYPE, "application/json")],
+        serde_json::to_string(&feedback).unwrap(),
+    )
+        .into_response()
 }
@@ -1,6 +1,5 @@
 use async_nats::client::PublishErrorKind;
 use axum::extract::{Request, State};
-use axum::http::HeaderMap;
 use axum::{
     extract::ws::CloseFrame,
     extract::ws::{Message, WebSocket, WebSocketUpgrade},
@@ -8,7 +7,10 @@ use axum::{
     extract::Query,
     response::IntoResponse,
 };
-use bevygap_shared::protocol::RequestSession;
+use bevygap_shared::protocol::{
+    LobbyCommand, MatchmakerErrorKind, RequestControl, RequestSession, SessionRequestFeedback,
+};
+use bevygap_shared::wire::*;
 use log::*;
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
@@ -37,49 +39,152 @@ pub(crate) async fn handler_websocket(
     ws: WebSocketUpgrade,
     req: Request,
 ) -> impl IntoResponse {
-    let client_ip = get_client_ip(&params, &addr, req.headers(), &state);
+    let client_ip = crate::get_client_ip(
+        params.client_ip.as_deref(),
+        &addr,
+        req.headers(),
+        &state.settings,
+    );
+    // non-browser clients can authenticate with a header, browsers use the request message.
+    let header_token = crate::auth::bearer_token(req.headers());
 
     info!("ws responder for ip {client_ip}");
     // finalize the upgrade process by returning upgrade callback.
     // we can customize the callback by sending additional info such as address.
-    ws.on_upgrade(move |socket| handle_socket(socket, client_ip, state))
+    ws.on_upgrade(move |socket| handle_socket(socket, client_ip, header_token, state))
+}
+
+/// How to talk to this client, decided by its first message.
+enum ClientCodec {
+    /// Bare json, for clients from before the envelope.
+    Legacy,
+    Envelope {
+        format: WireFormat,
+        request_id: String,
+    },
 }
 
-/// Reads the initial RequestSession message from the client.
+impl ClientCodec {
+    /// Feedback chunks from the matchmaker are bare json. Enveloping them as json values
+    /// means kinds we don't know yet still get through.
+    fn feedback_message(&self, chunk: String) -> Message {
+        let ClientCodec::Envelope { format, request_id } = self else {
+            return Message::Text(chunk);
+        };
+        match serde_json::from_str(&chunk) {
+            Ok(value) => {
+                to_message(Envelope::from_tagged(value, Some(request_id.clone())).encode(*format))
+            }
+            Err(e) => {
+                warn!("Passing on undecodable feedback chunk: {e}");
+                Message::Text(chunk)
+            }
+        }
+    }
+
+    fn feedback(&self, feedback: &SessionRequestFeedback) -> Message {
+        self.feedback_message(serde_json::to_string(feedback).unwrap())
+    }
+
+    /// Decodes a later message from the client, eg a lobby command.
+    /// Ok(None) for kinds we don't know, which newer clients might send.
+    fn lobby_command(&self, msg: Message) -> Result<Option<LobbyCommand>, String> {
+        let frame = match msg {
+            Message::Text(text) => Frame::Text(text),
+            Message::Binary(bytes) => Frame::Binary(bytes),
+            _ => return Ok(None),
+        };
+        match (self, frame) {
+            (ClientCodec::Legacy, Frame::Text(text)) => serde_json::from_str(&text)
+                .map(Some)
+                .map_err(|e| format!("Failed to parse message as LobbyCommand: {e}")),
+            (ClientCodec::Legacy, Frame::Binary(_)) => Ok(None),
+            (ClientCodec::Envelope { .. }, frame) => {
+                let (envelope, _) = Envelope::decode(&frame)?;
+                match envelope.body_of(KIND_LOBBY_COMMAND) {
+                    Some(command) => command.map(Some),
+                    None => {
+                        warn!("Skipping {} message from client", envelope.kind);
+                        Ok(None)
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn to_message(frame: Frame) -> Message {
+    match frame {
+        Frame::Text(text) => Message::Text(text),
+        Frame::Binary(bytes) => Message::Binary(bytes),
+    }
+}
+
+/// Reads the initial RequestSession message from the client, either bare or in an envelope.
 /// Clients must sent this as the first message on connect, otherwise the connection is closed.
 async fn read_initial_request_message(
     socket: &mut WebSocket,
     timeout: Duration,
-) -> Result<RequestSession, String> {
-    if let Ok(Some(msg)) = tokio::time::timeout(timeout, socket.recv()).await {
-        if let Ok(msg) = msg {
-            info!("< {msg:?}");
-            if let Message::Text(text) = msg {
-                match serde_json::from_str::<RequestSession>(&text) {
-                    Ok(request) => Ok(request),
-                    Err(e) => Err(format!(
-                        "Failed to parse request message as RequestSession: {}",
-                        e
-                    )),
-                }
-            } else {
-                Err(format!("Expected text message, got {msg:?}"))
-            }
-        } else {
-            Err(format!("Failed to receive request message: {msg:?}"))
-        }
-    } else {
-        Err(format!(
+    request_id: String,
+) -> Result<(RequestSession, ClientCodec), String> {
+    let Ok(Some(msg)) = tokio::time::timeout(timeout, socket.recv()).await else {
+        return Err(format!(
             "Timeout waiting for websocket request message: {timeout:?}"
-        ))
+        ));
+    };
+    let Ok(msg) = msg else {
+        return Err(format!("Failed to receive request message: {msg:?}"));
+    };
+    // debug, since it can contain the auth token
+    debug!("< {msg:?}");
+    let frame = match msg {
+        Message::Text(text) => Frame::Text(text),
+        Message::Binary(bytes) => Frame::Binary(bytes),
+        msg => return Err(format!("Expected text or binary message, got {msg:?}")),
+    };
+    if let Ok((envelope, format)) = Envelope::decode(&frame) {
+        let Some(request) = envelope.body_of::<RequestSession>(KIND_REQUEST_SESSION) else {
+            return Err(format!(
+                "Expected {KIND_REQUEST_SESSION} message, got {}",
+                envelope.kind
+            ));
+        };
+        let request =
+            request.map_err(|e| format!("Failed to parse RequestSession envelope: {e}"))?;
+        info!(
+            "Client speaks protocol version {}, {format:?}",
+            envelope.version
+        );
+        let codec = ClientCodec::Envelope {
+            format,
+            request_id: envelope.request_id.unwrap_or(request_id),
+        };
+        return Ok((request, codec));
+    }
+    let Frame::Text(text) = frame else {
+        return Err("Failed to parse binary request message".to_string());
+    };
+    match serde_json::from_str::<RequestSession>(&text) {
+        Ok(request) => Ok((request, ClientCodec::Legacy)),
+        Err(e) => Err(format!(
+            "Failed to parse request message as RequestSession: {}",
+            e
+        )),
     }
 }
 
 /// Actual websocket statemachine (one will be spawned per connection)
 ///
-async fn handle_socket(mut socket: WebSocket, client_ip: String, state: Arc<AppState>) {
-    // all errors are strings that we send back to the client.
-    match handle_socket_inner(&mut socket, client_ip, state).await {
+async fn handle_socket(
+    mut socket: WebSocket,
+    client_ip: String,
+    header_token: Option<String>,
+    state: Arc<AppState>,
+) {
+    // until we know better from the client's first message.
+    let mut codec = ClientCodec::Legacy;
+    // all errors are sent back to the client.
+    match handle_socket_inner(&mut socket, &mut codec, client_ip, header_token, state).await {
         Ok(()) => {
             let _ = socket
                 .send(Message::Close(Some(CloseFrame {
@@ -88,9 +193,22 @@ async fn handle_socket(mut socket: WebSocket, client_ip: String, state: Arc<AppS
                 })))
                 .await;
         }
-        Err(s) => {
+        Err(SocketError::Feedback(kind, msg)) => {
+            warn!("{kind:?}: {msg}");
+            let feedback = SessionRequestFeedback::error(kind, msg);
+            let _ = socket.send(codec.feedback(&feedback)).await;
+        }
+        Err(SocketError::Text(s)) => {
             warn!("{s}");
-            let _ = socket.send(Message::Text(format!("ERR {s}"))).await;
+            let msg = match codec {
+                ClientCodec::Legacy => Message::Text(format!("ERR {s}")),
+                // enveloped clients understand errors, so don't make them parse text.
+                ClientCodec::Envelope { .. } => codec.feedback(&SessionRequestFeedback::error(
+                    MatchmakerErrorKind::BadRequest,
+                    s,
+                )),
+            };
+            let _ = socket.send(msg).await;
             // let _ = socket
             //     .send(Message::Close(Some(CloseFrame {
             //         code: axum::extract::ws::close_code::ERROR,
@@ -102,32 +220,100 @@ async fn handle_socket(mut socket: WebSocket, client_ip: String, state: Arc<AppS
     info!("websocket connection closed");
 }
 
+/// Why we're closing the websocket.
+enum SocketError {
+    /// Sent as "ERR ..." text, or an error feedback to clients that use envelopes.
+    Text(String),
+    /// Sent as a [`SessionRequestFeedback::Error`], for errors the client plugin should act on,
+    /// like being rate limited.
+    Feedback(MatchmakerErrorKind, String),
+}
+
+impl From<String> for SocketError {
+    fn from(s: String) -> Self {
+        SocketError::Text(s)
+    }
+}
+
 /// We await the request message, which includes the game name and version,
 /// send a request to NATS, subscribe to replies, and stream back to the client.
 async fn handle_socket_inner(
     socket: &mut WebSocket,
+    codec: &mut ClientCodec,
     client_ip: String,
+    header_token: Option<String>,
     state: Arc<AppState>,
-) -> Result<(), String> {
+) -> Result<(), SocketError> {
+    let client = state.bgnats.client().clone();
+    let reply_inbox = client.new_inbox();
+    // the inbox is unique, so it doubles as the request id, if the client didn't send one.
+    let request_id = reply_inbox
+        .rsplit('.')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+
     // Await the request message the client should send once the websocket is connected.
-    let request_session = read_initial_request_message(socket, Duration::from_secs(10)).await?;
+    let (request_session, client_codec) =
+        read_initial_request_message(socket, Duration::from_secs(10), request_id).await?;
+    *codec = client_codec;
+    let request_id = match codec {
+        ClientCodec::Envelope { request_id, .. } => Some(request_id.clone()),
+        ClientCodec::Legacy => None,
+    };
+
+    let too_many = |msg| SocketError::Feedback(MatchmakerErrorKind::RateLimited, msg);
+    state.rate_limits.check_ip(&client_ip).map_err(too_many)?;
+
+    // nothing gets published for requests that don't authenticate.
+    let token = request_session
+        .auth_token
+        .as_deref()
+        .or(header_token.as_deref());
+    let identity = crate::auth::authenticate(state.authenticator(), token)
+        .map_err(|msg| SocketError::Feedback(MatchmakerErrorKind::Unauthorized, msg))?;
+    if let Some(identity) = &identity {
+        state
+            .rate_limits
+            .check_player(&identity.player_id)
+            .map_err(too_many)?;
+    }
+    // held until we're done streaming responses.
+    let _permit = state.rate_limits.start_request().map_err(too_many)?;
 
     let (game_name, game_ver) = request_session.game_name_and_version()?;
 
-    let subject = format!("matchmaker.request.{game_name}.{game_ver}");
+    let subject = state
+        .bgnats
+        .namespace()
+        .matchmaker_request(&game_name, &game_ver);
 
-    // this should be safe because of our regex check on name and version..
-    let payload = format!(
-        "{{\"client_ip\":\"{client_ip}\", \"game\":\"{game_name}\", \"version\":\"{game_ver}\"}}"
-    );
+    let payload = serde_json::json!({
+        "client_ip": client_ip,
+        "game": game_name,
+        "version": game_ver,
+        "party": request_session.party,
+        "lobby": request_session.lobby,
+        "reconnect_secret": request_session.reconnect_secret,
+        "identity": identity,
+        "latencies": request_session.latencies,
+        "request_id": request_id,
+    })
+    .to_string();
 
     info!("Sending request to {subject} with payload {payload}");
 
-    let client = state.bgnats.client().clone();
-    let reply_inbox = client.new_inbox();
     let mut response_subscriber = client.subscribe(reply_inbox.to_owned()).await.unwrap();
     // TODO this publish needs to "opt in to no_responder messages" somehow, per
     // https://docs.nats.io/reference/reference-protocols/nats-protocol
+    // lobby hosts send commands later on the same websocket, and the matchmaker needs to know
+    // if the client goes away while it's queued or in a party or lobby. The matchmaker task
+    // handling the request listens for both on a subject only this connection knows.
+    let control_subject = format!("{reply_inbox}.control");
+    let publish_control = |control: RequestControl| {
+        let payload = serde_json::to_string(&control).unwrap();
+        client.publish(control_subject.clone(), payload.into())
+    };
     client
         .publish_with_reply(subject, reply_inbox, payload.into())
         .await
@@ -139,53 +325,41 @@ async fn handle_socket_inner(
     // now we wait for response messages on this nats inbox, and send back to ws client.
     // receiving an empty message from nats means the end of stream.
 
-    while let Some(msg) = response_subscriber.next().await {
-        if msg.payload.is_empty() {
-            info!("got empty response, breaking");
-            break;
-        }
-        let chunk = String::from_utf8(msg.payload.to_vec()).unwrap();
-        info!("> {chunk}");
-        if socket.send(Message::Text(chunk)).await.is_err() {
-            return Err("Can't send chunk to ws client".to_string());
-        }
-    }
-    Ok(())
-}
-
-/// Logic to decide what to use as the clients IP address for the purposes of Edgegap sessions.
-///
-/// In order of preference:
-/// ?client_ip=XXX querystring param (for dev)
-/// X-Forwarded-For header (for proxy setups)
-/// the source IP of the http client
-///
-/// Additionally if the above yields a localhost address, we replace it with
-/// settings.fake_ip, which is also useful for dev.
-fn get_client_ip(
-    params: &QsParams,
-    addr: &SocketAddr,
-    headers: &HeaderMap,
-    state: &AppState,
-) -> String {
-    // client_ip is the one sent to Edgegap, to decide which server to assign the player to.
-    // We use one provided in the qs, otherwise the connecting IP of the http client.
-    let mut client_ip = params.client_ip.clone().unwrap_or(addr.ip().to_string());
-
-    // Check for X-Forwarded-For header, since this is probably running behind a proxy
-    if let Some(forwarded_for) = headers.get("X-Forwarded-For") {
-        if let Ok(forwarded_ip) = forwarded_for.to_str() {
-            // Use the first IP in the X-Forwarded-For header
-            if let Some(first_ip) = forwarded_ip.split(',').next() {
-                client_ip = first_ip.trim().to_string();
-                info!("Using X-Forwarded-For IP: {}", client_ip);
+    loop {
+        tokio::select! {
+            msg = response_subscriber.next() => {
+                let Some(msg) = msg else {
+                    break;
+                };
+                if msg.payload.is_empty() {
+                    info!("got empty response, breaking");
+                    break;
+                }
+                let chunk = String::from_utf8(msg.payload.to_vec()).unwrap();
+                info!("> {chunk}");
+                if socket.send(codec.feedback_message(chunk)).await.is_err() {
+                    let _ = publish_control(RequestControl::Cancel).await;
+                    return Err("Can't send chunk to ws client".to_string().into());
+                }
+            }
+            msg = socket.recv() => {
+                let msg = match msg {
+                    None | Some(Err(_)) | Some(Ok(Message::Close(_))) => {
+                        let _ = publish_control(RequestControl::Cancel).await;
+                        return Err("Client went away".to_string().into());
+                    }
+                    Some(Ok(msg)) => msg,
+                };
+                info!("< {msg:?}");
+                // pings, or messages we don't know.
+                let Some(command) = codec.lobby_command(msg)? else {
+                    continue;
+                };
+                publish_control(RequestControl::Lobby(command))
+                    .await
+                    .map_err(|_| "Failed to send lobby command".to_string())?;
             }
         }
     }
-    if client_ip == "127.0.0.1" || client_ip == "::1" {
-        // localhost tends to spawn deployments in random places..
-        client_ip = state.settings.fake_ip.to_string();
-        warn!("Using fake IP, request came from localhost: {client_ip}");
-    }
-    client_ip
+    Ok(())
 }
@@ -6,6 +6,11 @@ pub async fn get_context(
     context_url: &str,
     context_token: &str,
 ) -> Result<serde_json::Value, Error> {
+    // The matchmaker's local backend writes the context to a file instead of serving it
+    if let Some(path) = context_url.strip_prefix("file://") {
+        let content = tokio::fs::read_to_string(path).await?;
+        return Ok(serde_json::from_str(&content)?);
+    }
     let client = Client::new();
     let req = client
         .request(Method::GET, context_url)
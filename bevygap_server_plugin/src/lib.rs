@@ -7,5 +7,9 @@ pub mod prelude {
     pub use crate::arbitrium_env::ArbitriumEnv;
     pub use crate::edgegap_context::ArbitriumContext;
     pub use crate::plugin::BevygapReady;
+    pub use crate::plugin::BevygapServerConfig;
     pub use crate::plugin::BevygapServerPlugin;
+    pub use crate::plugin::PlayerIdentities;
+    pub use bevygap_shared::nats::{BevygapNatsConfig, NatsAuth};
+    pub use bevygap_shared::protocol::PlayerIdentity;
 }
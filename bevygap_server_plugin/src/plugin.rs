@@ -1,12 +1,22 @@
+use async_nats::jetstream::kv::Operation;
 use bevy::prelude::*;
-use bevy::utils::HashMap;
+use bevy::utils::{HashMap, HashSet};
 use bevy_tokio_tasks::{TokioTasksPlugin, TokioTasksRuntime};
+use bevygap_shared::names::GAMESERVER_CONTEXTS;
 use bevygap_shared::nats::*;
+use bevygap_shared::protocol::{
+    private_key_id, GameTransport, IssuedClientId, PlayerIdentity, ServerCapacity, ServerTransports,
+};
+use futures::StreamExt;
 use lightyear::connection::netcode::ClientId;
-use lightyear::connection::server::{ConnectionRequestHandler, DeniedReason};
+use lightyear::connection::server::{
+    ConnectionRequestHandler, DeniedReason, ServerConnection, ServerConnections,
+};
 use lightyear::prelude::server::*;
 use lightyear::server::events::{ConnectEvent, DisconnectEvent};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use crate::arbitrium_env::ArbitriumEnv;
 use crate::edgegap_context::{self, ArbitriumContext};
@@ -17,8 +27,48 @@ use crate::edgegap_context::{self, ArbitriumContext};
 /// If not, and it's a trusted cert, do nothing.
 pub struct BevygapServerPlugin;
 
+/// None if we have no WebTransport server, so clients don't need a digest.
 #[derive(Resource)]
-struct CertDigest(String);
+struct CertDigest(Option<String>);
+
+/// The transports clients can reach us on, reported to the matchmaker.
+#[derive(Resource)]
+struct Transports(ServerTransports);
+
+/// Identifies our lightyear private key, so the matchmaker knows which key to sign our
+/// clients' connect tokens with, while keys are being rotated.
+#[derive(Resource)]
+struct PrivateKeyId(String);
+
+/// Optional gameserver settings. Insert before adding the plugin to change them.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct BevygapServerConfig {
+    /// Connections beyond this are denied with `DeniedReason::ServerFull`.
+    /// Defaults to the deployment's socket count, from the Edgegap context.
+    pub max_players: Option<u32>,
+    /// How to connect to NATS. Defaults to [`BevygapNatsConfig::from_env`].
+    pub nats: Option<BevygapNatsConfig>,
+}
+
+/// Who each connected client is, for clients whose session request came with a
+/// [`PlayerIdentity`]. The matchmaker puts it in the connect token's user data, and tells us
+/// about it along with the client id, so it's here by the time the client connects.
+/// Identities are only kept if the token and the matchmaker agree on them.
+/// Entries are removed when the client disconnects.
+#[derive(Resource, Debug, Default)]
+pub struct PlayerIdentities(HashMap<lightyear::connection::id::ClientId, PlayerIdentity>);
+
+impl PlayerIdentities {
+    pub fn get(&self, client_id: lightyear::connection::id::ClientId) -> Option<&PlayerIdentity> {
+        self.0.get(&client_id)
+    }
+
+    pub fn iter(
+        &self,
+    ) -> impl Iterator<Item = (&lightyear::connection::id::ClientId, &PlayerIdentity)> {
+        self.0.iter()
+    }
+}
 
 #[derive(Event)]
 pub struct NatsConnected;
@@ -44,7 +94,13 @@ impl Plugin for BevygapServerPlugin {
         // In future, we hope to just set this ENV var directly in the Edgegap Dashboard.
         inject_ca_root_env_var_from_cmdline_arg();
 
-        app.add_systems(Startup, (extract_cert_digest, setup_nats).chain());
+        app.init_resource::<BevygapServerConfig>();
+        app.init_resource::<PlayerIdentities>();
+
+        app.add_systems(
+            Startup,
+            (extract_transports, extract_private_key_id, setup_nats).chain(),
+        );
 
         app.observe(edgegap_context::fetch_context_on_nats_connected);
         app.observe(send_context_to_nats);
@@ -55,30 +111,65 @@ impl Plugin for BevygapServerPlugin {
     }
 }
 
+/// Finds the transports and ports of each netcode server, and the WebTransport cert digest.
 #[allow(unreachable_patterns)]
-fn extract_cert_digest(
+fn extract_transports(
     server_config: Res<lightyear::server::config::ServerConfig>,
     mut commands: Commands,
 ) {
-    let net_config = &server_config.net[0];
-    let digest = match &net_config {
-        NetConfig::Netcode { io, .. } => match &io.transport {
-            ServerTransport::WebTransportServer { certificate, .. } => Some(
-                certificate.certificate_chain().as_slice()[0]
-                    .hash()
-                    .to_string(),
-            ),
-            _ => None,
-        },
-        _ => None,
-    };
-    let Some(digest) = digest else {
-        panic!(
-            "Unable to extract cert digest. Is there a webtransport server transport configured?"
-        );
-    };
-    info!("Extracted cert digest: {}", digest);
+    let mut transports = ServerTransports::default();
+    let mut digest = None;
+    for net_config in server_config.net.iter() {
+        let NetConfig::Netcode { io, .. } = net_config else {
+            warn!("Ignoring non-netcode server, bevygap can't issue tokens for it");
+            continue;
+        };
+        let (transport, port) = match &io.transport {
+            ServerTransport::WebTransportServer {
+                server_addr,
+                certificate,
+                ..
+            } => {
+                digest = Some(
+                    certificate.certificate_chain().as_slice()[0]
+                        .hash()
+                        .to_string(),
+                );
+                (GameTransport::WebTransport, server_addr.port())
+            }
+            ServerTransport::UdpSocket(server_addr) => (GameTransport::Udp, server_addr.port()),
+            ServerTransport::WebSocketServer { server_addr, .. } => {
+                (GameTransport::WebSocket, server_addr.port())
+            }
+            _ => {
+                warn!("Ignoring server transport that bevygap clients can't connect with");
+                continue;
+            }
+        };
+        info!("Server transport: {transport} on port {port}");
+        transports.ports.insert(transport, port);
+    }
+    if transports.ports.is_empty() {
+        panic!("No WebTransport, UDP or WebSocket netcode server configured");
+    }
+    if let Some(digest) = &digest {
+        info!("Extracted cert digest: {digest}");
+    }
     commands.insert_resource(CertDigest(digest));
+    commands.insert_resource(Transports(transports));
+}
+
+#[allow(unreachable_patterns)]
+fn extract_private_key_id(
+    server_config: Res<lightyear::server::config::ServerConfig>,
+    mut commands: Commands,
+) {
+    let key_id = match &server_config.net[0] {
+        NetConfig::Netcode { config, .. } => private_key_id(&config.private_key),
+        _ => panic!("Unable to extract private key. Is there a netcode server configured?"),
+    };
+    info!("Private key id: {key_id}");
+    commands.insert_resource(PrivateKeyId(key_id));
 }
 
 /// If --ca_contents XXXXXX present on command line, set NATS_CA_CONTENTS to XXXXXX
@@ -111,19 +202,77 @@ fn inject_ca_root_env_var_from_cmdline_arg() {
 fn handle_lightyear_client_disconnect(
     trigger: Trigger<DisconnectEvent>,
     nats_sender: ResMut<NatsSender>,
+    mut identities: ResMut<PlayerIdentities>,
+    crh: Option<Res<CRH>>,
+    arb_env: Res<ArbitriumEnv>,
 ) {
     let client_id = trigger.event().client_id;
     info!("Lightyear disconnect event for client_id {}", client_id);
     nats_sender.client_disconnected(client_id.to_bits());
+    identities.0.remove(&client_id);
+    // clients that connected without a session were never counted.
+    if let Some(crh) = crh.filter(|crh| crh.0.client_disconnected(client_id.to_bits())) {
+        nats_sender.capacity(arb_env.request_id.clone(), crh.0.capacity());
+    }
 }
 
 fn handle_lightyear_client_connect(
     trigger: Trigger<ConnectEvent>,
     nats_sender: ResMut<NatsSender>,
+    mut identities: ResMut<PlayerIdentities>,
+    connections: Res<ServerConnections>,
+    crh: Option<Res<CRH>>,
+    arb_env: Res<ArbitriumEnv>,
 ) {
     let client_id = trigger.event().client_id;
     info!("Lightyear connect event for client_id {}", client_id);
-    nats_sender.client_connected(client_id.to_bits());
+    let Some(crh) = crh else {
+        error!("Client {client_id} connected before the connection request handler was set up");
+        return;
+    };
+    // the handler only admits client ids it has a session for, so this shouldn't fail.
+    let Some(issued) = crh.0.client_connected(client_id.to_bits()) else {
+        error!("Client {client_id} connected, but isn't mapped to a session id");
+        return;
+    };
+    // the token's user data is signed along with the rest of the token, so it should say who
+    // the matchmaker told us about. If it doesn't, we don't trust either.
+    let token_identity = connect_token_identity(&connections, client_id);
+    if token_identity.as_ref() != Ok(&issued.identity) {
+        error!(
+            "Client {client_id}'s connect token identity {token_identity:?} doesn't match the issued one {:?}, ignoring it",
+            issued.identity
+        );
+    } else if let Some(identity) = issued.identity {
+        info!(
+            "Client {client_id} is player {} ({})",
+            identity.player_id, identity.display_name
+        );
+        identities.0.insert(client_id, identity);
+    }
+    nats_sender.client_connected(client_id.to_bits(), issued.session_id);
+    nats_sender.capacity(arb_env.request_id.clone(), crh.0.capacity());
+}
+
+/// The player identity the matchmaker put in this client's connect token, if any.
+fn connect_token_identity(
+    connections: &ServerConnections,
+    client_id: lightyear::connection::id::ClientId,
+) -> Result<Option<PlayerIdentity>, String> {
+    let user_data = connections
+        .servers
+        .iter()
+        .find_map(|server| match server {
+            ServerConnection::Netcode(server) => server.user_data(client_id.to_bits()),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        })
+        .ok_or_else(|| format!("No netcode connection for client {client_id}"))?;
+    // tokens without an identity have all-zero user data.
+    if user_data.iter().all(|b| *b == 0) {
+        return Ok(None);
+    }
+    PlayerIdentity::from_user_data(&user_data).map(Some)
 }
 
 /// We create a BevygapConnectionRequestHandler and store it in a resource.
@@ -131,16 +280,94 @@ fn handle_lightyear_client_connect(
 fn setup_connection_request_handler(
     _trigger: Trigger<NatsConnected>,
     bgnats: Res<BevygapNats>,
+    arb_env: Res<ArbitriumEnv>,
+    runtime: ResMut<TokioTasksRuntime>,
     mut commands: Commands,
     mut server_config: ResMut<lightyear::server::config::ServerConfig>,
 ) {
     // we store this in a resource, because we'll need to push new data into it
-    let crh = BevygapConnectionRequestHandler::new(bgnats.clone());
-    let arc_crh = Arc::new(crh);
+    let arc_crh = Arc::new(BevygapConnectionRequestHandler::default());
     commands.insert_resource(CRH(arc_crh.clone()));
     for net in server_config.net.iter_mut() {
         net.set_connection_request_handler(arc_crh.clone());
     }
+    let request_id = arb_env.request_id.clone();
+    let kv_c2s = bgnats.kv_c2s().clone();
+    runtime.spawn_background_task(move |_ctx| async move {
+        watch_issued_client_ids(arc_crh, kv_c2s, request_id).await;
+    });
+}
+
+/// How long to wait before retrying a failed watch, doubling up to the max.
+const WATCH_RETRY_MIN: Duration = Duration::from_secs(1);
+const WATCH_RETRY_MAX: Duration = Duration::from_secs(30);
+
+/// Keeps the handler's allowed client ids in sync with the ones the matchmaker issued tokens
+/// for on this deployment. The matchmaker keys them by "{request_id}.{client_id}".
+async fn watch_issued_client_ids(
+    crh: Arc<BevygapConnectionRequestHandler>,
+    kv_c2s: async_nats::jetstream::kv::Store,
+    request_id: String,
+) {
+    let mut retry_in = WATCH_RETRY_MIN;
+    loop {
+        info!("Watching for client ids issued for deployment {request_id}");
+        let mut watcher = match kv_c2s.watch_with_history(format!("{request_id}.*")).await {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                // nobody new can connect until this works, but whoever's here can keep playing.
+                error!("Failed to watch client ids in KV, retrying in {retry_in:?}: {e:?}");
+                tokio::time::sleep(retry_in).await;
+                retry_in = (retry_in * 2).min(WATCH_RETRY_MAX);
+                continue;
+            }
+        };
+        retry_in = WATCH_RETRY_MIN;
+        while let Some(entry) = watcher.next().await {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("KV error watching for client ids: {e:?}");
+                    continue;
+                }
+            };
+            let Some(client_id) = entry
+                .key
+                .rsplit_once('.')
+                .and_then(|(_request_id, client_id)| client_id.parse::<u64>().ok())
+            else {
+                warn!("Ignoring unexpected client id key: {}", entry.key);
+                continue;
+            };
+            match entry.operation {
+                Operation::Put => {
+                    let issued = match serde_json::from_slice::<IssuedClientId>(&entry.value) {
+                        Ok(issued) => issued,
+                        Err(e) => {
+                            warn!("Ignoring bad entry for client id {client_id}: {e}");
+                            continue;
+                        }
+                    };
+                    // the history is replayed whenever the watch (re)starts, so old
+                    // entries have to be aged by when they were written, not received.
+                    let age = std::time::SystemTime::now()
+                        .duration_since(entry.created.into())
+                        .unwrap_or_default();
+                    if age >= Duration::from_secs(ISSUED_CLIENT_ID_SECONDS) {
+                        debug!("Ignoring expired client id {client_id}, issued {age:?} ago");
+                        continue;
+                    }
+                    info!(
+                        "Client ID {client_id} issued for session {}",
+                        issued.session_id
+                    );
+                    crh.allow(client_id, issued, entry.revision, age);
+                }
+                Operation::Delete | Operation::Purge => crh.disallow(client_id),
+            }
+        }
+        warn!("Client id watcher ended, restarting");
+    }
 }
 
 /// Context loaded, nats connected: time to send our metadata to NATS,
@@ -151,20 +378,41 @@ fn send_context_to_nats(
     nats_sender: ResMut<NatsSender>,
     mut commands: Commands,
     digest: Res<CertDigest>,
+    transports: Res<Transports>,
+    key_id: Res<PrivateKeyId>,
+    config: Res<BevygapServerConfig>,
+    crh: Option<Res<CRH>>,
 ) {
     info!("CONTEXT added: {context:?}");
     info!("CONTEXT fqdn: {}", context.fqdn());
-    nats_sender.cert_digest(context.public_ip(), digest.0.clone());
+    if let Some(digest) = &digest.0 {
+        nats_sender.cert_digest(context.public_ip(), digest.clone());
+    }
+    nats_sender.key_id(context.request_id(), key_id.0.clone());
+    nats_sender.transports(context.request_id(), transports.0.clone());
     nats_sender.arbitrium_context(context.clone());
+    if let Some(crh) = crh {
+        let max_players = config.max_players.unwrap_or_else(|| context.sockets());
+        info!("Max players: {max_players}");
+        let capacity = crh.0.set_max_players(max_players);
+        nats_sender.capacity(context.request_id(), capacity);
+    }
     commands.trigger(BevygapReady);
 }
 
 #[derive(Debug, Event)]
 enum NatsEvent {
-    ClientConnected(ClientId),
+    /// Client id, and the session it was issued for
+    ClientConnected(ClientId, String),
     ClientDisconnected(ClientId),
     ArbitriumContext(ArbitriumContext),
     CertDigest(String, String),
+    /// Deployment request id, and its current capacity
+    Capacity(String, ServerCapacity),
+    /// Deployment request id, and its private key id
+    KeyId(String, String),
+    /// Deployment request id, and the transports it listens on
+    Transports(String, ServerTransports),
 }
 
 #[derive(Resource)]
@@ -173,9 +421,9 @@ struct NatsSender(tokio::sync::mpsc::UnboundedSender<NatsEvent>);
 // these sends should never fail, it's an unbounded channel and if the
 // receiving task can't recv, it panics anyway.
 impl NatsSender {
-    fn client_connected(&self, client_id: u64) {
+    fn client_connected(&self, client_id: u64, session_id: String) {
         self.0
-            .send(NatsEvent::ClientConnected(client_id))
+            .send(NatsEvent::ClientConnected(client_id, session_id))
             .expect("Unable to send NatsEvent for client_connected")
     }
 
@@ -196,6 +444,24 @@ impl NatsSender {
             .send(NatsEvent::CertDigest(ip, digest))
             .expect("Unable to send NatsEvent for cert_digest")
     }
+
+    fn capacity(&self, request_id: String, capacity: ServerCapacity) {
+        self.0
+            .send(NatsEvent::Capacity(request_id, capacity))
+            .expect("Unable to send NatsEvent for capacity")
+    }
+
+    fn key_id(&self, request_id: String, key_id: String) {
+        self.0
+            .send(NatsEvent::KeyId(request_id, key_id))
+            .expect("Unable to send NatsEvent for key_id")
+    }
+
+    fn transports(&self, request_id: String, transports: ServerTransports) {
+        self.0
+            .send(NatsEvent::Transports(request_id, transports))
+            .expect("Unable to send NatsEvent for transports")
+    }
 }
 
 /// Exists purely to allow us to trigger an event via command queue
@@ -208,8 +474,13 @@ impl<T: Event> bevy::ecs::world::Command for DeferredTriggerCommand<T> {
     }
 }
 
-fn setup_nats(runtime: ResMut<TokioTasksRuntime>, mut commands: Commands) {
+fn setup_nats(
+    runtime: ResMut<TokioTasksRuntime>,
+    config: Res<BevygapServerConfig>,
+    mut commands: Commands,
+) {
     info!("Setting up NATS");
+    let nats_config = config.nats.clone();
 
     let (nats_event_sender, mut nats_event_receiver) =
         tokio::sync::mpsc::unbounded_channel::<NatsEvent>();
@@ -224,7 +495,11 @@ fn setup_nats(runtime: ResMut<TokioTasksRuntime>, mut commands: Commands) {
     }));
 
     runtime.spawn_background_task(|mut ctx| async move {
-        let bgnats = match BevygapNats::new_and_connect("bevygap_server_plugin").await {
+        let connected = match nats_config {
+            Some(nats_config) => BevygapNats::connect(&nats_config).await,
+            None => BevygapNats::new_and_connect("bevygap_server_plugin").await,
+        };
+        let bgnats = match connected {
             Ok(nats) => nats,
             Err(e) => {
                 error!("Failed to setup NATS: {}", e);
@@ -233,10 +508,13 @@ fn setup_nats(runtime: ResMut<TokioTasksRuntime>, mut commands: Commands) {
         };
         info!("NATS connected");
 
-        let kv_c2s = bgnats.kv_c2s().clone();
         let kv_sessions = bgnats.kv_active_connections().clone();
         let kv_cert_digests = bgnats.kv_cert_digests().clone();
+        let kv_server_capacity = bgnats.kv_server_capacity().clone();
+        let kv_server_key_ids = bgnats.kv_server_key_ids().clone();
+        let kv_server_transports = bgnats.kv_server_transports().clone();
         let client = bgnats.client().clone();
+        let contexts_subject = bgnats.namespace().subject(GAMESERVER_CONTEXTS);
 
         ctx.run_on_main_thread(move |ctx| {
             ctx.world.insert_resource(bgnats);
@@ -264,37 +542,25 @@ fn setup_nats(runtime: ResMut<TokioTasksRuntime>, mut commands: Commands) {
                 panic!("NatsEvent channel closed, aborting.");
             };
             match ev {
-                NatsEvent::ClientConnected(client_id) => {
+                NatsEvent::ClientConnected(client_id, session_id) => {
                     info!("Client connected: {}, writing to nats kv", client_id);
-                    // TODO lookup the session id here?
-                    let session_id = kv_c2s
-                        .get(client_id.to_string())
+                    info!("Client ID {client_id} associated with session id: {session_id}");
+                    client_id_to_session_id.insert(client_id, session_id.clone());
+                    // keyed by session and client, since a party shares one session.
+                    // the matchmaker deletes the session once no keys for it remain.
+                    kv_sessions
+                        .put(
+                            format!("{session_id}.{client_id}"),
+                            client_id.to_string().into(),
+                        )
                         .await
-                        .expect("Failed to get session_id from KV");
-                    match session_id {
-                        None => {
-                            panic!("Client ID is not mapped to a session id! wtf.");
-                        }
-                        Some(session_id) => {
-                            let session_id_key = String::from_utf8(session_id.into())
-                                .expect("Failed to convert session_id to string");
-                            info!("Client ID {client_id} associated with session id: {session_id_key}",);
-                            client_id_to_session_id.insert(client_id, session_id_key.clone());
-                            kv_sessions
-                                .put(session_id_key, client_id.to_string().into())
-                                .await
-                                .expect("Failed to put client_id in KV");
-                            // delete the mappings.
-                            // this signifies the session
-                            // let _ = kv_c2s.delete(client_id.to_string()).await;
-                        }
-                    }
+                        .expect("Failed to put client_id in KV");
                 }
                 NatsEvent::ClientDisconnected(client_id) => {
                     info!("Client disconnected: {}, writing to nats kv", client_id);
-                    if let Some(session_id) = client_id_to_session_id.get(&client_id) {
+                    if let Some(session_id) = client_id_to_session_id.remove(&client_id) {
                         kv_sessions
-                            .delete(session_id)
+                            .delete(format!("{session_id}.{client_id}"))
                             .await
                             .expect("Failed to del client_id in KV");
                     } else {
@@ -306,7 +572,7 @@ fn setup_nats(runtime: ResMut<TokioTasksRuntime>, mut commands: Commands) {
                     let arb_context_bytes = context.to_bytes();
                     // TODO nats key should be on the subject?
                     client
-                        .publish("gameserver.contexts", arb_context_bytes.into())
+                        .publish(contexts_subject.clone(), arb_context_bytes.into())
                         .await
                         .expect("Failed to write context to NATS");
                 }
@@ -319,6 +585,32 @@ fn setup_nats(runtime: ResMut<TokioTasksRuntime>, mut commands: Commands) {
                         .await
                         .expect("Failed to put digest in KV");
                 }
+                NatsEvent::Capacity(request_id, capacity) => {
+                    info!(
+                        "Capacity: {}/{} players",
+                        capacity.players, capacity.max_players
+                    );
+                    let payload = serde_json::to_vec(&capacity).unwrap();
+                    kv_server_capacity
+                        .put(request_id, payload.into())
+                        .await
+                        .expect("Failed to put capacity in KV");
+                }
+                NatsEvent::KeyId(request_id, key_id) => {
+                    info!("KeyId: {request_id} -> {key_id}");
+                    kv_server_key_ids
+                        .put(request_id, key_id.into())
+                        .await
+                        .expect("Failed to put key id in KV");
+                }
+                NatsEvent::Transports(request_id, transports) => {
+                    info!("Transports: {request_id} -> {:?}", transports.ports);
+                    let payload = serde_json::to_vec(&transports).unwrap();
+                    kv_server_transports
+                        .put(request_id, payload.into())
+                        .await
+                        .expect("Failed to put transports in KV");
+                }
             }
             client.flush().await.expect("Failed to flush NATS");
         }
@@ -348,30 +640,157 @@ fn setup_nats(runtime: ResMut<TokioTasksRuntime>, mut commands: Commands) {
 #[derive(Resource)]
 pub struct CRH(Arc<BevygapConnectionRequestHandler>);
 
+/// Client ids the matchmaker issues are only valid for this long, same as the KV entries.
+const ISSUED_CLIENT_ID_SECONDS: u64 = 30;
+/// How long an admitted client's slot is held for it to finish connecting.
+const RESERVED_SLOT_SECONDS: u64 = 10;
+
+/// A client id the matchmaker issued a connect token for.
+#[derive(Debug)]
+struct AllowedClientId {
+    issued: IssuedClientId,
+    /// When the matchmaker issued it.
+    since: Instant,
+    /// The KV revision it was issued in.
+    revision: u64,
+}
+
+/// Who may connect, kept up to date from NATS so handle_request never has to wait on it.
+#[derive(Debug, Default)]
+struct ClientIds {
+    /// Issued for this deployment, and not used yet.
+    allowed: HashMap<u64, AllowedClientId>,
+    /// Connected right now, and counted as players.
+    connected: HashSet<u64>,
+    /// Admitted by handle_request but not connected yet, and when. They hold a player slot,
+    /// so several clients admitted at once can't overfill the server.
+    reserved: HashMap<u64, Instant>,
+    /// Issued tokens that have already been used to connect. Kept until they'd have expired
+    /// anyway.
+    used: HashMap<u64, UsedClientId>,
+}
+
+#[derive(Debug)]
+struct UsedClientId {
+    /// When the matchmaker issued it.
+    since: Instant,
+    /// The KV revision it was issued in.
+    revision: u64,
+}
+
 /// Only accept connections where the ClientId is in NATS associated with a session.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct BevygapConnectionRequestHandler {
-    bgnats: BevygapNats,
+    client_ids: Arc<Mutex<ClientIds>>,
+    /// 0 until we know, from the config or the context.
+    max_players: Arc<AtomicU32>,
 }
 
-// this is set as a arc dyn trait object.
-// perhaps we should push valid ClientIDs into this struct as they arrive in nats?
-// or lookup as needed? we can keep an arc ref to this in the server plugin, and hand a clone
-// of it for registering with lightyear.
-//
-// Reasons we might actually want to reply with:
-// Full  - no, mm should prevent this? maybe we do want this, in case of lobbies and races when users pick a server.
-// banned - no, mm prevents
-// internal error - maybe
-// already connected - if client id is already connected?
-// token already used - same as already used client id?
-// invalidtoken - yep, if not registered in nats
-// custom(string) - hmm.
-//
-// we don't want this to block, so i think we need to push data into it so it's always ready.
+// this is set as a arc dyn trait object, so lightyear and the server plugin share it.
+// handle_request can't block, so valid ClientIDs are pushed into it as they arrive in nats,
+// by watch_issued_client_ids().
 impl BevygapConnectionRequestHandler {
-    pub fn new(bgnats: BevygapNats) -> Self {
-        Self { bgnats }
+    /// The matchmaker issued a token for this client id, `age` ago. A reconnecting client gets
+    /// a new token for the same client id, written in a later revision, so that makes it usable
+    /// again. Replays of the revision that was already used don't.
+    fn allow(&self, client_id: u64, issued: IssuedClientId, revision: u64, age: Duration) {
+        let mut ids = self.client_ids.lock().unwrap();
+        let max_age = Duration::from_secs(ISSUED_CLIENT_ID_SECONDS);
+        ids.allowed
+            .retain(|_, allowed| allowed.since.elapsed() < max_age);
+        // the watcher skips entries this old, so replays of them can't get this far.
+        ids.used.retain(|_, used| used.since.elapsed() < max_age);
+        if let Some(used) = ids.used.get(&client_id) {
+            if revision <= used.revision {
+                debug!("Client id {client_id} revision {revision} was already used");
+                return;
+            }
+            ids.used.remove(&client_id);
+        }
+        ids.allowed.insert(
+            client_id,
+            AllowedClientId {
+                issued,
+                since: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+                revision,
+            },
+        );
+    }
+
+    fn disallow(&self, client_id: u64) {
+        self.client_ids.lock().unwrap().allowed.remove(&client_id);
+    }
+
+    /// Uses up the client id's token, returning what it was issued for.
+    fn client_connected(&self, client_id: u64) -> Option<IssuedClientId> {
+        let mut ids = self.client_ids.lock().unwrap();
+        let allowed = ids.allowed.remove(&client_id)?;
+        let used = UsedClientId {
+            since: allowed.since,
+            revision: allowed.revision,
+        };
+        ids.used.insert(client_id, used);
+        ids.reserved.remove(&client_id);
+        ids.connected.insert(client_id);
+        Some(allowed.issued)
+    }
+
+    /// Returns false if the client wasn't counted as a player.
+    fn client_disconnected(&self, client_id: u64) -> bool {
+        self.client_ids.lock().unwrap().connected.remove(&client_id)
+    }
+
+    /// Why this client id can't connect, if it can't.
+    fn check_client_id(&self, client_id: u64) -> Option<DeniedReason> {
+        let ids = self.client_ids.lock().unwrap();
+        if ids.connected.contains(&client_id) {
+            return Some(DeniedReason::AlreadyConnected);
+        }
+        let max_age = Duration::from_secs(ISSUED_CLIENT_ID_SECONDS);
+        match ids.allowed.get(&client_id) {
+            Some(allowed) if allowed.since.elapsed() < max_age => None,
+            _ if ids.used.contains_key(&client_id) => Some(DeniedReason::TokenAlreadyUsed),
+            _ => Some(DeniedReason::InvalidToken),
+        }
+    }
+
+    /// Why this client can't connect, if it can't. Otherwise it gets a player slot until it
+    /// connects or the reservation runs out.
+    fn admit(&self, client_id: u64) -> Option<DeniedReason> {
+        if let Some(reason) = self.check_client_id(client_id) {
+            return Some(reason);
+        }
+        let mut ids = self.client_ids.lock().unwrap();
+        let max_reserved = Duration::from_secs(RESERVED_SLOT_SECONDS);
+        ids.reserved
+            .retain(|&id, since| id != client_id && since.elapsed() < max_reserved);
+        let capacity = ServerCapacity {
+            players: (ids.connected.len() + ids.reserved.len()) as u32,
+            max_players: self.max_players.load(Ordering::SeqCst),
+        };
+        // max_players is 0 until the context is loaded, in which case we don't know the limit.
+        if capacity.max_players > 0 && capacity.is_full() {
+            info!(
+                "Server full: {}/{} players, including those still connecting",
+                capacity.players, capacity.max_players
+            );
+            return Some(DeniedReason::ServerFull);
+        }
+        ids.reserved.insert(client_id, Instant::now());
+        None
+    }
+
+    /// Players are only counted once connected, not while their slot is reserved.
+    fn capacity(&self) -> ServerCapacity {
+        ServerCapacity {
+            players: self.client_ids.lock().unwrap().connected.len() as u32,
+            max_players: self.max_players.load(Ordering::SeqCst),
+        }
+    }
+
+    fn set_max_players(&self, max_players: u32) -> ServerCapacity {
+        self.max_players.store(max_players, Ordering::SeqCst);
+        self.capacity()
     }
 }
 
@@ -381,9 +800,11 @@ impl ConnectionRequestHandler for BevygapConnectionRequestHandler {
         client_id: lightyear::connection::id::ClientId,
     ) -> Option<DeniedReason> {
         info!("BevygapConnectionRequestHandler({client_id})");
-        // TODO: check this ClientID is in nats, and there isn't already a player connected
-        // TODO: check server isn't full
-        None
+        let denied = self.admit(client_id.to_bits());
+        if let Some(reason) = &denied {
+            warn!("Denying {client_id}: {reason:?}");
+        }
+        denied
 
         // pub enum DeniedReason {
         //     ServerFull,
@@ -396,3 +817,105 @@ impl ConnectionRequestHandler for BevygapConnectionRequestHandler {
         // }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn issued(session_id: &str) -> IssuedClientId {
+        IssuedClientId {
+            session_id: session_id.to_string(),
+            identity: None,
+        }
+    }
+
+    /// Allows client id 1 in revision 5, then connects and disconnects it.
+    fn used_handler() -> BevygapConnectionRequestHandler {
+        let crh = BevygapConnectionRequestHandler::default();
+        crh.allow(1, issued("S1"), 5, Duration::ZERO);
+        assert!(crh.client_connected(1).is_some());
+        crh.client_disconnected(1);
+        crh
+    }
+
+    #[test]
+    fn issued_client_id_connects_once() {
+        let crh = BevygapConnectionRequestHandler::default();
+        assert_eq!(crh.check_client_id(1), Some(DeniedReason::InvalidToken));
+        crh.allow(1, issued("S1"), 5, Duration::ZERO);
+        assert_eq!(crh.check_client_id(1), None);
+        assert_eq!(crh.client_connected(1).unwrap().session_id, "S1");
+        assert_eq!(crh.check_client_id(1), Some(DeniedReason::AlreadyConnected));
+        crh.client_disconnected(1);
+        assert_eq!(crh.check_client_id(1), Some(DeniedReason::TokenAlreadyUsed));
+        assert!(crh.client_connected(1).is_none());
+    }
+
+    #[test]
+    fn replayed_revision_is_already_used() {
+        let crh = used_handler();
+        crh.allow(1, issued("S1"), 5, Duration::ZERO);
+        assert_eq!(crh.check_client_id(1), Some(DeniedReason::TokenAlreadyUsed));
+        crh.allow(1, issued("S1"), 4, Duration::ZERO);
+        assert_eq!(crh.check_client_id(1), Some(DeniedReason::TokenAlreadyUsed));
+    }
+
+    #[test]
+    fn later_revision_is_allowed_again() {
+        let crh = used_handler();
+        crh.allow(1, issued("S2"), 6, Duration::ZERO);
+        assert_eq!(crh.check_client_id(1), None);
+        assert_eq!(crh.client_connected(1).unwrap().session_id, "S2");
+    }
+
+    #[test]
+    fn expired_client_id_is_invalid() {
+        let crh = BevygapConnectionRequestHandler::default();
+        let age = Duration::from_secs(ISSUED_CLIENT_ID_SECONDS);
+        crh.allow(1, issued("S1"), 5, age);
+        assert_eq!(crh.check_client_id(1), Some(DeniedReason::InvalidToken));
+    }
+
+    #[test]
+    fn used_client_ids_are_forgotten_once_expired() {
+        let crh = BevygapConnectionRequestHandler::default();
+        let age = Duration::from_secs(ISSUED_CLIENT_ID_SECONDS) - Duration::from_millis(50);
+        crh.allow(1, issued("S1"), 5, age);
+        assert!(crh.client_connected(1).is_some());
+        crh.client_disconnected(1);
+        std::thread::sleep(Duration::from_millis(100));
+        // allowing anyone prunes expired entries.
+        crh.allow(2, issued("S2"), 6, Duration::ZERO);
+        assert!(crh.client_ids.lock().unwrap().used.is_empty());
+        assert_eq!(crh.check_client_id(1), Some(DeniedReason::InvalidToken));
+    }
+
+    #[test]
+    fn admitted_clients_hold_a_slot() {
+        let crh = BevygapConnectionRequestHandler::default();
+        crh.set_max_players(1);
+        crh.allow(1, issued("S1"), 5, Duration::ZERO);
+        crh.allow(2, issued("S1"), 6, Duration::ZERO);
+        assert_eq!(crh.admit(1), None);
+        // 1 hasn't connected yet, but has the only slot.
+        assert_eq!(crh.admit(2), Some(DeniedReason::ServerFull));
+        assert_eq!(crh.admit(1), None);
+        assert!(crh.client_connected(1).is_some());
+        assert_eq!(crh.capacity().players, 1);
+        assert_eq!(crh.admit(2), Some(DeniedReason::ServerFull));
+        assert!(crh.client_disconnected(1));
+        assert_eq!(crh.capacity().players, 0);
+        assert_eq!(crh.admit(2), None);
+    }
+
+    #[test]
+    fn uncounted_clients_dont_leave() {
+        let crh = BevygapConnectionRequestHandler::default();
+        crh.allow(1, issued("S1"), 5, Duration::ZERO);
+        assert!(crh.client_connected(1).is_some());
+        // connected without being issued a session, so never counted.
+        assert!(crh.client_connected(2).is_none());
+        assert!(!crh.client_disconnected(2));
+        assert_eq!(crh.capacity().players, 1);
+    }
+}
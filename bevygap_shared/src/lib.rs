@@ -1,4 +1,6 @@
 #[cfg(feature = "nats")]
 pub mod nats;
 
+pub mod names;
 pub mod protocol;
+pub mod wire;
@@ -0,0 +1,110 @@
+//! Every NATS subject, KV bucket and stream name bevygap uses, in one place.
+//!
+//! Use them via a [`Namespace`], which prefixes them so that several environments (eg staging and
+//! production) or games can share one NATS cluster without seeing each other's sessions.
+//! The default, empty namespace adds no prefix, so the names are what they always were.
+
+/// Gameservers announce their Edgegap context here when they start.
+pub const GAMESERVER_CONTEXTS: &str = "gameserver.contexts";
+/// Session requests, as "matchmaker.request.{app_name}.{app_version}".
+pub const MATCHMAKER_REQUEST: &str = "matchmaker.request";
+/// matchmaker_httpd asks the matchmaker for Edgegap's beacon list here.
+pub const MATCHMAKER_BEACONS: &str = "matchmaker.beacons";
+/// Warm pool levels, published by the matchmaker when they change.
+pub const WARM_POOL_METRICS: &str = "matchmaker.metrics.warm_pool";
+/// Lobby events, as "lobby.{name}".
+pub const LOBBY: &str = "lobby";
+/// Webhooks caught by bevygap_webhook_sink, as "webhook.{hook_name}".
+pub const WEBHOOK: &str = "webhook";
+/// The NATS service group the non-streaming "session.gensession" endpoint is in.
+pub const SESSION_SERVICE: &str = "session";
+/// Session deletes are queued on "edgegap_delete_session_q.{session_id}".
+pub const DELETE_SESSION_QUEUE: &str = "edgegap_delete_session_q";
+
+/// The work queue stream holding session deletes.
+pub const DELETE_SESSION_STREAM: &str = "DELETE_SESSION_STREAM";
+
+pub const BUCKET_ACTIVE_CONNECTIONS: &str = "active_connections";
+pub const BUCKET_UNCLAIMED_SESSIONS: &str = "unclaimed_sessions";
+pub const BUCKET_SESSION_GRACE: &str = "session_grace";
+pub const BUCKET_RECONNECT_SECRETS: &str = "reconnect_secrets";
+pub const BUCKET_LOBBIES: &str = "lobbies";
+pub const BUCKET_SERVER_CAPACITY: &str = "server_capacity";
+pub const BUCKET_SERVER_KEY_IDS: &str = "server_key_ids";
+pub const BUCKET_KEY_ROTATIONS: &str = "key_rotations";
+pub const BUCKET_SERVER_TRANSPORTS: &str = "server_transports";
+pub const BUCKET_CERT_DIGESTS: &str = "cert_digests";
+pub const BUCKET_SESSIONS_EG2LY: &str = "sessions_eg2ly";
+pub const BUCKET_SESSIONS_LY2EG: &str = "sessions_ly2eg";
+
+/// A prefix for subjects, buckets and streams.
+///
+/// Subjects become "{namespace}.{subject}", and buckets and streams, which can't contain dots,
+/// become "{namespace}_{bucket}".
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Namespace(String);
+
+impl Namespace {
+    /// Namespaces can contain letters, digits, '-' and '_', so they're valid in subjects,
+    /// bucket names and stream names alike. Empty means no prefix.
+    pub fn new(namespace: impl Into<String>) -> Result<Self, String> {
+        let namespace = namespace.into();
+        if let Some(c) = namespace
+            .chars()
+            .find(|c| !c.is_ascii_alphanumeric() && *c != '-' && *c != '_')
+        {
+            return Err(format!(
+                "Invalid character {c:?} in namespace {namespace:?}"
+            ));
+        }
+        Ok(Self(namespace))
+    }
+
+    /// From the NATS_NAMESPACE env var, if set.
+    pub fn from_env() -> Result<Self, String> {
+        Self::new(std::env::var("NATS_NAMESPACE").unwrap_or_default())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn subject(&self, subject: &str) -> String {
+        if self.0.is_empty() {
+            subject.to_string()
+        } else {
+            format!("{}.{subject}", self.0)
+        }
+    }
+
+    /// For KV buckets and streams.
+    pub fn bucket(&self, bucket: &str) -> String {
+        if self.0.is_empty() {
+            bucket.to_string()
+        } else {
+            format!("{}_{bucket}", self.0)
+        }
+    }
+
+    pub fn matchmaker_request(&self, app_name: &str, app_version: &str) -> String {
+        self.subject(&format!("{MATCHMAKER_REQUEST}.{app_name}.{app_version}"))
+    }
+
+    pub fn lobby(&self, name: &str) -> String {
+        self.subject(&format!("{LOBBY}.{name}"))
+    }
+
+    pub fn webhook(&self, hook_name: &str) -> String {
+        self.subject(&format!("{WEBHOOK}.{hook_name}"))
+    }
+
+    pub fn delete_session(&self, session_id: &str) -> String {
+        self.subject(&format!("{DELETE_SESSION_QUEUE}.{session_id}"))
+    }
+}
+
+impl std::fmt::Display for Namespace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
@@ -1,3 +1,4 @@
+use crate::names::*;
 use async_nats::jetstream::stream::Stream;
 use async_nats::jetstream::{self, stream};
 use async_nats::Client;
@@ -5,6 +6,9 @@ use std::time::Duration;
 
 use log::*;
 
+mod config;
+pub use config::*;
+
 #[derive(Clone, Debug)]
 #[cfg_attr(feature = "bevy", derive(bevy::prelude::Resource))]
 pub struct BevygapNats {
@@ -14,20 +18,48 @@ pub struct BevygapNats {
     kv_cert_digests: jetstream::kv::Store,
     kv_active_connections: jetstream::kv::Store,
     kv_unclaimed_sessions: jetstream::kv::Store,
+    kv_session_grace: jetstream::kv::Store,
+    kv_reconnect_secrets: jetstream::kv::Store,
+    kv_lobbies: jetstream::kv::Store,
+    kv_server_capacity: jetstream::kv::Store,
+    kv_server_key_ids: jetstream::kv::Store,
+    kv_key_rotations: jetstream::kv::Store,
+    kv_server_transports: jetstream::kv::Store,
     delete_session_stream: Stream,
+    namespace: Namespace,
 }
 
-const DELETE_SESSION_STREAM: &str = "edgegap_delete_session_q";
-
 impl BevygapNats {
-    /// Connects to NATS based on environment variables.
+    /// Connects to NATS based on environment variables, see [`BevygapNatsConfig::from_env`].
     pub async fn new_and_connect(nats_client_name: &str) -> Result<Self, async_nats::Error> {
-        let client = Self::connect_to_nats(nats_client_name).await?;
-        let (kv_s2c, kv_c2s) = Self::create_kv_buckets_for_session_mappings(client.clone()).await?;
-        let kv_active_connections = Self::create_kv_active_connections(client.clone()).await?;
-        let kv_cert_digests = Self::create_kv_cert_digests(client.clone()).await?;
-        let kv_unclaimed_sessions = Self::create_kv_unclaimed_sessions(client.clone()).await?;
-        let delete_session_stream = Self::create_session_delete_queue(&client).await?;
+        Self::connect(&BevygapNatsConfig::from_env(nats_client_name)?).await
+    }
+
+    /// Connects to NATS, and creates any buckets and streams that don't exist yet.
+    pub async fn connect(config: &BevygapNatsConfig) -> Result<Self, async_nats::Error> {
+        let namespace = config.namespace.clone();
+        if !namespace.as_str().is_empty() {
+            info!("NATS: using namespace '{namespace}'");
+        }
+        let client = config.connect().await?;
+        let (kv_s2c, kv_c2s) =
+            Self::create_kv_buckets_for_session_mappings(client.clone(), &namespace).await?;
+        let kv_active_connections =
+            Self::create_kv_active_connections(client.clone(), &namespace).await?;
+        let kv_cert_digests = Self::create_kv_cert_digests(client.clone(), &namespace).await?;
+        let kv_unclaimed_sessions =
+            Self::create_kv_unclaimed_sessions(client.clone(), &namespace).await?;
+        let kv_session_grace = Self::create_kv_session_grace(client.clone(), &namespace).await?;
+        let kv_reconnect_secrets =
+            Self::create_kv_reconnect_secrets(client.clone(), &namespace).await?;
+        let kv_lobbies = Self::create_kv_lobbies(client.clone(), &namespace).await?;
+        let kv_server_capacity =
+            Self::create_kv_server_capacity(client.clone(), &namespace).await?;
+        let kv_server_key_ids = Self::create_kv_server_key_ids(client.clone(), &namespace).await?;
+        let kv_key_rotations = Self::create_kv_key_rotations(client.clone(), &namespace).await?;
+        let kv_server_transports =
+            Self::create_kv_server_transports(client.clone(), &namespace).await?;
+        let delete_session_stream = Self::create_session_delete_queue(&client, &namespace).await?;
         Ok(Self {
             client,
             kv_s2c,
@@ -35,13 +67,24 @@ impl BevygapNats {
             kv_cert_digests,
             kv_active_connections,
             kv_unclaimed_sessions,
+            kv_session_grace,
+            kv_reconnect_secrets,
+            kv_lobbies,
+            kv_server_capacity,
+            kv_server_key_ids,
+            kv_key_rotations,
+            kv_server_transports,
             delete_session_stream,
+            namespace,
         })
     }
 
     pub fn client(&self) -> Client {
         self.client.clone()
     }
+    pub fn namespace(&self) -> &Namespace {
+        &self.namespace
+    }
     pub fn kv_s2c(&self) -> &jetstream::kv::Store {
         &self.kv_s2c
     }
@@ -54,6 +97,27 @@ impl BevygapNats {
     pub fn kv_unclaimed_sessions(&self) -> &jetstream::kv::Store {
         &self.kv_unclaimed_sessions
     }
+    pub fn kv_session_grace(&self) -> &jetstream::kv::Store {
+        &self.kv_session_grace
+    }
+    pub fn kv_reconnect_secrets(&self) -> &jetstream::kv::Store {
+        &self.kv_reconnect_secrets
+    }
+    pub fn kv_lobbies(&self) -> &jetstream::kv::Store {
+        &self.kv_lobbies
+    }
+    pub fn kv_server_capacity(&self) -> &jetstream::kv::Store {
+        &self.kv_server_capacity
+    }
+    pub fn kv_server_key_ids(&self) -> &jetstream::kv::Store {
+        &self.kv_server_key_ids
+    }
+    pub fn kv_key_rotations(&self) -> &jetstream::kv::Store {
+        &self.kv_key_rotations
+    }
+    pub fn kv_server_transports(&self) -> &jetstream::kv::Store {
+        &self.kv_server_transports
+    }
     pub fn kv_cert_digests(&self) -> &jetstream::kv::Store {
         &self.kv_cert_digests
     }
@@ -68,7 +132,7 @@ impl BevygapNats {
     ) -> Result<(), async_nats::Error> {
         let js = jetstream::new(self.client.clone());
         js.publish(
-            format!("{DELETE_SESSION_STREAM}.{session_id}"),
+            self.namespace.delete_session(&session_id),
             session_id.into(),
         )
         .await?
@@ -76,129 +140,172 @@ impl BevygapNats {
         Ok(())
     }
 
-    /// want to support multiple connection modes. In production, I have a domain name with
-    /// LetsEncrypt certs set up, so i just need to enable TLS and provide user/pass.
-    ///
-    /// In other scenarios I want to support self-signed certs, in which case we need to provide
-    /// the CA, so our NATS client can verify the server.
-    ///
-    /// TLS is assumed, and by default we expect a trusted (LetsEncrypt or similar) cert.
-    /// if the NATS_CA=/path/to/ca.pem env is set, we use that CA to verify the cert.
-    ///
-    /// If NATS_CA_CONTENTS is set, we write it to a temp file and use that as the CA.
-    ///
-    /// Setting NATS_INSECURE env var (to anything) will disable TLS entirely (still need user/pass)
-    async fn connect_to_nats(nats_client_name: &str) -> Result<Client, async_nats::Error> {
-        info!("NATS: setting up, client name: {nats_client_name}");
-
-        let nats_insecure = std::env::var("NATS_INSECURE").is_ok();
-        let nats_self_signed_ca: Option<String> = std::env::var("NATS_CA").ok().or_else(|| {
-            // we write out the CA to a temp file, if provided in NATS_CA_CONTENTS
-            // this is useful for deploying containers on edgegap and injecting CA root certs.
-            //
-            // However, as of 5 November 2024, Edgegap limits you to 255 bytes in ENV vars
-            // so this is actually set by the server, from a command line arg.. see the book!
-            if let Ok(ca_contents) = std::env::var("NATS_CA_CONTENTS") {
-                let sanitised_nats_client_name = nats_client_name
-                    .chars()
-                    .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
-                    .collect::<String>();
-                let tmp_file =
-                    std::env::temp_dir().join(format!("rootCA-{sanitised_nats_client_name}.pem"));
-                std::fs::write(&tmp_file, ca_contents).unwrap();
-                Some(tmp_file.to_string_lossy().to_string())
-            } else {
-                None
-            }
-        });
-
-        let nats_host = std::env::var("NATS_HOST").expect("Missing NATS_HOST env");
-        let nats_user = std::env::var("NATS_USER").expect("Missing NATS_USER env");
-        let nats_pass = std::env::var("NATS_PASSWORD").expect("Missing NATS_PASSWORD env");
-
-        if nats_insecure {
-            warn!("😬 NATS: insecure - TLS is disabled.");
-        } else {
-            info!("NATS: TLS is enabled");
-        }
-
-        info!("NATS: connecting as '{nats_user}' to {nats_host}");
+    pub async fn create_kv_active_connections(
+        client: Client,
+        namespace: &Namespace,
+    ) -> Result<jetstream::kv::Store, async_nats::Error> {
+        let jetstream = jetstream::new(client);
+        let kv = jetstream
+            .create_key_value(async_nats::jetstream::kv::Config {
+                bucket: namespace.bucket(BUCKET_ACTIVE_CONNECTIONS),
+                ..Default::default()
+            })
+            .await?;
+        Ok(kv)
+    }
 
-        let mut nats_connection = async_nats::ConnectOptions::new()
-            .name(nats_client_name)
-            .user_and_password(nats_user, nats_pass)
-            .max_reconnects(10)
-            .require_tls(!nats_insecure);
+    pub async fn create_kv_unclaimed_sessions(
+        client: Client,
+        namespace: &Namespace,
+    ) -> Result<jetstream::kv::Store, async_nats::Error> {
+        let jetstream = jetstream::new(client);
+        let kv = jetstream
+            .create_key_value(async_nats::jetstream::kv::Config {
+                bucket: namespace.bucket(BUCKET_UNCLAIMED_SESSIONS),
+                max_value_size: 1024,
+                description: "Any session ids we get from the API are stored here, and if they key age gets too big, we delete the session via the API.".to_string(),
+                ..Default::default()
+            })
+            .await?;
+        Ok(kv)
+    }
 
-        if let Some(ca) = nats_self_signed_ca {
-            info!("NATS: using self-signed CA: {}", ca);
-            nats_connection = nats_connection.add_root_certificates(ca.into());
-        } else {
-            info!("NATS: expecting a trusted cert, no self-signed CA provided.");
-        }
+    pub async fn create_kv_session_grace(
+        client: Client,
+        namespace: &Namespace,
+    ) -> Result<jetstream::kv::Store, async_nats::Error> {
+        let jetstream = jetstream::new(client);
+        let kv = jetstream
+            .create_key_value(async_nats::jetstream::kv::Config {
+                bucket: namespace.bucket(BUCKET_SESSION_GRACE),
+                max_value_size: 1024,
+                description: "Sessions whose players all disconnected. Kept for a grace period so players can reconnect, then deleted.".to_string(),
+                ..Default::default()
+            })
+            .await?;
+        Ok(kv)
+    }
 
-        let client = nats_connection.connect(nats_host).await?;
-        info!("🟢 NATS: connected OK");
-        Ok(client)
+    pub async fn create_kv_reconnect_secrets(
+        client: Client,
+        namespace: &Namespace,
+    ) -> Result<jetstream::kv::Store, async_nats::Error> {
+        let jetstream = jetstream::new(client);
+        let kv = jetstream
+            .create_key_value(async_nats::jetstream::kv::Config {
+                bucket: namespace.bucket(BUCKET_RECONNECT_SECRETS),
+                max_value_size: 1024,
+                description: "Maps reconnect secrets given to clients to the session and client id they can rejoin.".to_string(),
+                max_age: Duration::from_secs(86400),
+                ..Default::default()
+            })
+            .await?;
+        Ok(kv)
+    }
 
-        // if let Some(ca) = nats_self_signed_ca {
-        //     info!("NATS_SELF_SIGNED_CA: {}", ca);
-        //     let nats_ca = std::env::var("NATS_CA").unwrap_or("./config/rootCA.pem".to_string());
-        // }
-        // let nats_cert =
-        // std::env::var("NATS_CERT").unwrap_or("./config/client-cert.pem".to_string());
-        // let nats_key = std::env::var("NATS_KEY").unwrap_or("./config/client-key.pem".to_string());
+    pub async fn create_kv_lobbies(
+        client: Client,
+        namespace: &Namespace,
+    ) -> Result<jetstream::kv::Store, async_nats::Error> {
+        let jetstream = jetstream::new(client);
+        let kv = jetstream
+            .create_key_value(async_nats::jetstream::kv::Config {
+                bucket: namespace.bucket(BUCKET_LOBBIES),
+                // room for a full lobby's member IPs, locations and reply subjects.
+                max_value_size: 16384,
+                description:
+                    "Lobby state by lobby name, and started lobbies' names by session.{session_id}."
+                        .to_string(),
+                max_age: Duration::from_secs(86400),
+                ..Default::default()
+            })
+            .await?;
+        Ok(kv)
+    }
 
-        // info!("NATS_CA: {}", nats_ca);
-        // info!("NATS_CERT: {}", nats_cert);
-        // info!("NATS_KEY: {}", nats_key);
+    pub async fn create_kv_server_capacity(
+        client: Client,
+        namespace: &Namespace,
+    ) -> Result<jetstream::kv::Store, async_nats::Error> {
+        let jetstream = jetstream::new(client);
+        let kv = jetstream
+            .create_key_value(async_nats::jetstream::kv::Config {
+                bucket: namespace.bucket(BUCKET_SERVER_CAPACITY),
+                max_value_size: 1024,
+                description: "Current and maximum player counts reported by gameservers, by deployment request id.".to_string(),
+                max_age: Duration::from_secs(86400),
+                ..Default::default()
+            })
+            .await?;
+        Ok(kv)
+    }
 
-        // let client = async_nats::ConnectOptions::new()
-        //     .name(nats_client_name)
-        //     .user_and_password(nats_user, nats_pass)
-        //     .max_reconnects(10)
-        //     .require_tls(!insecure)
-        //     // .add_root_certificates(nats_ca.into())
-        //     // .add_client_certificate(nats_cert.into(), nats_key.into())
-        //     .connect(nats_host)
-        //     .await?;
+    pub async fn create_kv_server_key_ids(
+        client: Client,
+        namespace: &Namespace,
+    ) -> Result<jetstream::kv::Store, async_nats::Error> {
+        let jetstream = jetstream::new(client);
+        let kv = jetstream
+            .create_key_value(async_nats::jetstream::kv::Config {
+                bucket: namespace.bucket(BUCKET_SERVER_KEY_IDS),
+                max_value_size: 1024,
+                description:
+                    "Which lightyear private key each gameserver uses, by deployment request id."
+                        .to_string(),
+                max_age: Duration::from_secs(86400),
+                ..Default::default()
+            })
+            .await?;
+        Ok(kv)
     }
 
-    pub async fn create_kv_active_connections(
+    pub async fn create_kv_key_rotations(
         client: Client,
+        namespace: &Namespace,
     ) -> Result<jetstream::kv::Store, async_nats::Error> {
         let jetstream = jetstream::new(client);
         let kv = jetstream
             .create_key_value(async_nats::jetstream::kv::Config {
-                bucket: "active_connections".to_string(),
+                bucket: namespace.bucket(BUCKET_KEY_ROTATIONS),
+                max_value_size: 1024,
+                description:
+                    "When each lightyear private key rotation started, by PREVIOUS_KEY_ID.NEW_KEY_ID."
+                        .to_string(),
                 ..Default::default()
             })
             .await?;
         Ok(kv)
     }
 
-    pub async fn create_kv_unclaimed_sessions(
+    pub async fn create_kv_server_transports(
         client: Client,
+        namespace: &Namespace,
     ) -> Result<jetstream::kv::Store, async_nats::Error> {
         let jetstream = jetstream::new(client);
         let kv = jetstream
             .create_key_value(async_nats::jetstream::kv::Config {
-                bucket: "unclaimed_sessions".to_string(),
+                bucket: namespace.bucket(BUCKET_SERVER_TRANSPORTS),
                 max_value_size: 1024,
-                description: "Any session ids we get from the API are stored here, and if they key age gets too big, we delete the session via the API.".to_string(),
+                description:
+                    "Transports and internal ports each gameserver listens on, by deployment request id."
+                        .to_string(),
+                max_age: Duration::from_secs(86400),
                 ..Default::default()
             })
             .await?;
         Ok(kv)
     }
 
-    pub async fn create_session_delete_queue(client: &Client) -> Result<Stream, async_nats::Error> {
+    pub async fn create_session_delete_queue(
+        client: &Client,
+        namespace: &Namespace,
+    ) -> Result<Stream, async_nats::Error> {
         let js = jetstream::new(client.clone());
         let stream = js
             .create_stream(jetstream::stream::Config {
-                name: "DELETE_SESSION_STREAM".to_string(),
+                name: namespace.bucket(DELETE_SESSION_STREAM),
                 retention: stream::RetentionPolicy::WorkQueue,
-                subjects: vec![format!("{DELETE_SESSION_STREAM}.*").to_string()],
+                subjects: vec![namespace.subject(&format!("{DELETE_SESSION_QUEUE}.*"))],
                 ..Default::default()
             })
             .await?;
@@ -207,11 +314,12 @@ impl BevygapNats {
 
     pub async fn create_kv_cert_digests(
         client: Client,
+        namespace: &Namespace,
     ) -> Result<jetstream::kv::Store, async_nats::Error> {
         let jetstream = jetstream::new(client);
         let kv = jetstream
             .create_key_value(async_nats::jetstream::kv::Config {
-                bucket: "cert_digests".to_string(),
+                bucket: namespace.bucket(BUCKET_CERT_DIGESTS),
                 description: "Maps server public ip to their self-signed cert digests".to_string(),
                 max_age: Duration::from_secs(86400 * 14),
                 max_value_size: 1024,
@@ -225,12 +333,13 @@ impl BevygapNats {
     /// Creates two buckets for mapping between LY client ids and Edgegap session tokens
     async fn create_kv_buckets_for_session_mappings(
         client: Client,
+        namespace: &Namespace,
     ) -> Result<(jetstream::kv::Store, jetstream::kv::Store), async_nats::Error> {
         let jetstream = jetstream::new(client);
 
         let kv_s2c = jetstream
             .create_key_value(async_nats::jetstream::kv::Config {
-                bucket: "sessions_eg2ly".to_string(),
+                bucket: namespace.bucket(BUCKET_SESSIONS_EG2LY),
                 description: "Maps Edgegap Session IDs to Lightyear Client IDs".to_string(),
                 max_value_size: 1024,
                 // shouldn't need long for the client to receive token, and make connection to gameserver.
@@ -242,7 +351,7 @@ impl BevygapNats {
 
         let kv_c2s = jetstream
             .create_key_value(async_nats::jetstream::kv::Config {
-                bucket: "sessions_ly2eg".to_string(),
+                bucket: namespace.bucket(BUCKET_SESSIONS_LY2EG),
                 description: "Maps Lightyear Client IDs to Edgegap Session IDs".to_string(),
                 max_value_size: 1024,
                 // shouldn't need long for the client to receive token, and make connection to gameserver.
@@ -0,0 +1,334 @@
+//! How to connect to NATS. Fill in a [`BevygapNatsConfig`] from the environment, from
+//! command line args (with the `clap` feature), or in code, then pass it to
+//! [`BevygapNats::connect`](super::BevygapNats::connect).
+use crate::names::Namespace;
+use async_nats::ConnectOptions;
+use log::*;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How we prove who we are to the NATS server.
+#[derive(Clone, Default)]
+pub enum NatsAuth {
+    #[default]
+    None,
+    UserAndPassword {
+        user: String,
+        password: String,
+    },
+    /// A .creds file, holding a user JWT and NKey seed, eg from `nsc generate creds`.
+    CredentialsFile(PathBuf),
+    /// An NKey seed, starting "SU".
+    NKey(String),
+    Token(String),
+}
+
+/// Secrets are left out, so configs can be logged.
+impl std::fmt::Debug for NatsAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NatsAuth::None => write!(f, "None"),
+            NatsAuth::UserAndPassword { user, .. } => write!(f, "UserAndPassword({user})"),
+            NatsAuth::CredentialsFile(path) => write!(f, "CredentialsFile({path:?})"),
+            NatsAuth::NKey(_) => write!(f, "NKey"),
+            NatsAuth::Token(_) => write!(f, "Token"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BevygapNatsConfig {
+    client_name: String,
+    host: Option<String>,
+    auth: NatsAuth,
+    require_tls: bool,
+    root_certificate: Option<PathBuf>,
+    client_certificate: Option<(PathBuf, PathBuf)>,
+    max_reconnects: Option<usize>,
+    max_reconnect_delay: Duration,
+    connection_timeout: Duration,
+    pub(crate) namespace: Namespace,
+}
+
+impl BevygapNatsConfig {
+    /// TLS is required by default, and we expect a trusted (LetsEncrypt or similar) cert,
+    /// unless a root certificate is given.
+    pub fn new(client_name: impl Into<String>) -> Self {
+        Self {
+            client_name: client_name.into(),
+            host: None,
+            auth: NatsAuth::None,
+            require_tls: true,
+            root_certificate: None,
+            client_certificate: None,
+            max_reconnects: Some(10),
+            max_reconnect_delay: Duration::from_secs(8),
+            connection_timeout: Duration::from_secs(5),
+            namespace: Namespace::default(),
+        }
+    }
+
+    /// Reads whichever of these env vars are set:
+    ///
+    /// * NATS_HOST, eg `nats.example.com:4222`
+    /// * NATS_CREDS (path to a .creds file), NATS_NKEY (seed), NATS_TOKEN, or
+    ///   NATS_USER and NATS_PASSWORD. If more than one is set, the first one wins.
+    /// * NATS_INSECURE (to anything) disables TLS entirely.
+    /// * NATS_CA=/path/to/ca.pem, the CA to verify a self-signed server cert with.
+    ///   Or NATS_CA_CONTENTS, which we write to a temp file and use as the CA.
+    /// * NATS_CLIENT_CERT and NATS_CLIENT_KEY, paths to a client cert and key for mTLS.
+    /// * NATS_MAX_RECONNECTS, 0 to keep trying forever.
+    /// * NATS_CONNECT_TIMEOUT, in seconds.
+    /// * NATS_NAMESPACE, see [`Namespace`].
+    pub fn from_env(client_name: impl Into<String>) -> Result<Self, String> {
+        let env = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let mut config = Self::new(client_name);
+        config.host = env("NATS_HOST");
+        config.auth = if let Some(path) = env("NATS_CREDS") {
+            NatsAuth::CredentialsFile(path.into())
+        } else if let Some(seed) = env("NATS_NKEY") {
+            NatsAuth::NKey(seed)
+        } else if let Some(token) = env("NATS_TOKEN") {
+            NatsAuth::Token(token)
+        } else if let Some(user) = env("NATS_USER") {
+            let password =
+                env("NATS_PASSWORD").ok_or("NATS_USER is set, but NATS_PASSWORD isn't")?;
+            NatsAuth::UserAndPassword { user, password }
+        } else {
+            NatsAuth::None
+        };
+        config.require_tls = std::env::var("NATS_INSECURE").is_err();
+        config.root_certificate = match env("NATS_CA") {
+            Some(path) => Some(path.into()),
+            // this is useful for deploying containers on edgegap and injecting CA root certs.
+            //
+            // However, as of 5 November 2024, Edgegap limits you to 255 bytes in ENV vars
+            // so this is actually set by the server, from a command line arg.. see the book!
+            None => match env("NATS_CA_CONTENTS") {
+                Some(contents) => Some(config.write_temp_root_certificate(&contents)?),
+                None => None,
+            },
+        };
+        config.client_certificate = match (env("NATS_CLIENT_CERT"), env("NATS_CLIENT_KEY")) {
+            (Some(cert), Some(key)) => Some((cert.into(), key.into())),
+            (None, None) => None,
+            _ => return Err("NATS_CLIENT_CERT and NATS_CLIENT_KEY must be set together".into()),
+        };
+        if let Some(max) = env("NATS_MAX_RECONNECTS") {
+            let max: usize = max
+                .parse()
+                .map_err(|e| format!("Bad NATS_MAX_RECONNECTS: {e}"))?;
+            config.max_reconnects = (max > 0).then_some(max);
+        }
+        if let Some(secs) = env("NATS_CONNECT_TIMEOUT") {
+            let secs: u64 = secs
+                .parse()
+                .map_err(|e| format!("Bad NATS_CONNECT_TIMEOUT: {e}"))?;
+            config.connection_timeout = Duration::from_secs(secs);
+        }
+        config.namespace = Namespace::from_env()?;
+        Ok(config)
+    }
+
+    fn write_temp_root_certificate(&self, contents: &str) -> Result<PathBuf, String> {
+        let sanitised_client_name = self
+            .client_name
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+            .collect::<String>();
+        let tmp_file = std::env::temp_dir().join(format!("rootCA-{sanitised_client_name}.pem"));
+        std::fs::write(&tmp_file, contents)
+            .map_err(|e| format!("Failed to write NATS_CA_CONTENTS to {tmp_file:?}: {e}"))?;
+        Ok(tmp_file)
+    }
+
+    pub fn client_name(&self) -> &str {
+        &self.client_name
+    }
+
+    /// eg `nats.example.com:4222` or `1.2.3.4`
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+
+    pub fn auth(mut self, auth: NatsAuth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn user_and_password(self, user: impl Into<String>, password: impl Into<String>) -> Self {
+        self.auth(NatsAuth::UserAndPassword {
+            user: user.into(),
+            password: password.into(),
+        })
+    }
+
+    pub fn credentials_file(self, path: impl Into<PathBuf>) -> Self {
+        self.auth(NatsAuth::CredentialsFile(path.into()))
+    }
+
+    pub fn nkey(self, seed: impl Into<String>) -> Self {
+        self.auth(NatsAuth::NKey(seed.into()))
+    }
+
+    pub fn token(self, token: impl Into<String>) -> Self {
+        self.auth(NatsAuth::Token(token.into()))
+    }
+
+    pub fn require_tls(mut self, require_tls: bool) -> Self {
+        self.require_tls = require_tls;
+        self
+    }
+
+    /// The CA to verify a self-signed server cert with.
+    pub fn root_certificate(mut self, path: impl Into<PathBuf>) -> Self {
+        self.root_certificate = Some(path.into());
+        self
+    }
+
+    /// A client cert and key, for servers that verify clients with mTLS.
+    pub fn client_certificate(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.client_certificate = Some((cert.into(), key.into()));
+        self
+    }
+
+    /// How many times to try reconnecting after losing the connection. None to keep trying.
+    pub fn max_reconnects(mut self, max_reconnects: Option<usize>) -> Self {
+        self.max_reconnects = max_reconnects;
+        self
+    }
+
+    /// Reconnects back off exponentially, up to this long between attempts.
+    pub fn max_reconnect_delay(mut self, delay: Duration) -> Self {
+        self.max_reconnect_delay = delay;
+        self
+    }
+
+    pub fn connection_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_timeout = timeout;
+        self
+    }
+
+    pub fn namespace(mut self, namespace: Namespace) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    pub(crate) async fn connect(&self) -> Result<async_nats::Client, async_nats::Error> {
+        info!("NATS: setting up, client name: {}", self.client_name);
+        let host = self
+            .host
+            .clone()
+            .ok_or("No NATS host configured, set NATS_HOST")?;
+
+        if self.require_tls {
+            info!("NATS: TLS is enabled");
+        } else {
+            warn!("😬 NATS: insecure - TLS is disabled.");
+        }
+
+        let max_delay = self.max_reconnect_delay;
+        let mut options = ConnectOptions::new()
+            .name(&self.client_name)
+            .max_reconnects(self.max_reconnects)
+            .reconnect_delay_callback(move |attempts| {
+                Duration::from_millis(100 << attempts.min(16)).min(max_delay)
+            })
+            .connection_timeout(self.connection_timeout)
+            .require_tls(self.require_tls);
+
+        options = match &self.auth {
+            NatsAuth::None => {
+                info!("NATS: connecting without credentials to {host}");
+                options
+            }
+            NatsAuth::UserAndPassword { user, password } => {
+                info!("NATS: connecting as '{user}' to {host}");
+                options.user_and_password(user.clone(), password.clone())
+            }
+            NatsAuth::CredentialsFile(path) => {
+                info!("NATS: connecting with credentials file {path:?} to {host}");
+                options.credentials_file(path).await?
+            }
+            NatsAuth::NKey(seed) => {
+                info!("NATS: connecting with an NKey to {host}");
+                options.nkey(seed.clone())
+            }
+            NatsAuth::Token(token) => {
+                info!("NATS: connecting with a token to {host}");
+                options.token(token.clone())
+            }
+        };
+
+        if let Some(ca) = &self.root_certificate {
+            info!("NATS: using self-signed CA: {ca:?}");
+            options = options.add_root_certificates(ca.clone());
+        } else {
+            info!("NATS: expecting a trusted cert, no self-signed CA provided.");
+        }
+        if let Some((cert, key)) = &self.client_certificate {
+            info!("NATS: using client cert {cert:?}");
+            options = options.add_client_certificate(cert.clone(), key.clone());
+        }
+
+        let client = options.connect(host).await?;
+        info!("🟢 NATS: connected OK");
+        Ok(client)
+    }
+}
+
+/// NATS settings as command line args, for binaries that use clap.
+/// Add them with `#[command(flatten)]`, and anything given overrides the env vars.
+#[cfg(feature = "clap")]
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct NatsArgs {
+    /// NATS server address, instead of NATS_HOST
+    #[arg(long)]
+    pub nats_host: Option<String>,
+    /// Path to a NATS .creds file, instead of NATS_CREDS
+    #[arg(long)]
+    pub nats_creds: Option<PathBuf>,
+    /// NATS client cert for mTLS, instead of NATS_CLIENT_CERT
+    #[arg(long, requires = "nats_client_key")]
+    pub nats_client_cert: Option<PathBuf>,
+    /// NATS client key for mTLS, instead of NATS_CLIENT_KEY
+    #[arg(long, requires = "nats_client_cert")]
+    pub nats_client_key: Option<PathBuf>,
+    /// How many times to reconnect to NATS, 0 for forever, instead of NATS_MAX_RECONNECTS
+    #[arg(long)]
+    pub nats_max_reconnects: Option<usize>,
+    /// Seconds to wait when connecting to NATS, instead of NATS_CONNECT_TIMEOUT
+    #[arg(long)]
+    pub nats_connect_timeout: Option<u64>,
+    /// Prefix for NATS subjects and buckets, instead of NATS_NAMESPACE
+    #[arg(long)]
+    pub nats_namespace: Option<String>,
+}
+
+#[cfg(feature = "clap")]
+impl NatsArgs {
+    /// The env config, with these args applied over it.
+    pub fn config(&self, client_name: &str) -> Result<BevygapNatsConfig, String> {
+        let mut config = BevygapNatsConfig::from_env(client_name)?;
+        if let Some(host) = &self.nats_host {
+            config = config.host(host);
+        }
+        if let Some(path) = &self.nats_creds {
+            config = config.credentials_file(path);
+        }
+        if let (Some(cert), Some(key)) = (&self.nats_client_cert, &self.nats_client_key) {
+            config = config.client_certificate(cert, key);
+        }
+        if let Some(max) = self.nats_max_reconnects {
+            config = config.max_reconnects((max > 0).then_some(max));
+        }
+        if let Some(secs) = self.nats_connect_timeout {
+            config = config.connection_timeout(Duration::from_secs(secs));
+        }
+        if let Some(namespace) = &self.nats_namespace {
+            config = config.namespace(Namespace::new(namespace.as_str())?);
+        }
+        Ok(config)
+    }
+}
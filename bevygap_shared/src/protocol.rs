@@ -1,7 +1,9 @@
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::fmt;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum SessionRequestFeedback {
     /// The service has begun processing the request.
     Acknowledged,
@@ -9,14 +11,32 @@ pub enum SessionRequestFeedback {
     SessionRequestAccepted(String),
     /// Session readyness update
     ProgressReport(String),
+    /// Party membership changed. The session is created once the party is full.
+    /// Share the code with the players who should join.
+    PartyUpdate { code: String, members: u8, size: u8 },
+    /// Lobby membership changed. The host starts the lobby by sending [`LobbyCommand::Start`].
+    LobbyUpdate {
+        name: String,
+        members: u8,
+        host: bool,
+    },
     /// The session is ready to connect to
     SessionReady {
         token: String,
         ip: String,
+        /// The port of the matchmaker's main transport, for clients that don't read `ports`.
         port: u16,
+        /// Empty if the gameserver has no WebTransport server.
         cert_digest: String,
+        /// The gameserver's port for each transport it offers, all on `ip`.
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        ports: BTreeMap<GameTransport, u16>,
+        /// Send this in a later [`RequestSession`] to get back into the same server,
+        /// if you get disconnected.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reconnect_secret: Option<String>,
     },
-    /// There was an error.
+    /// There was an error: a [`MatchmakerErrorKind::code`], and what went wrong.
     Error(u16, String),
 }
 
@@ -28,17 +48,108 @@ impl fmt::Display for SessionRequestFeedback {
                 write!(f, "Request accepted: {}", id)
             }
             SessionRequestFeedback::ProgressReport(msg) => write!(f, "In-progress: {msg}"),
-            SessionRequestFeedback::SessionReady {
-                token: _,
-                ip,
-                port,
-                cert_digest: _,
-            } => write!(f, "Session Ready! {ip}:{port}"),
+            SessionRequestFeedback::PartyUpdate {
+                code,
+                members,
+                size,
+            } => write!(f, "Party {code}: {members}/{size} players"),
+            SessionRequestFeedback::LobbyUpdate { name, members, .. } => {
+                write!(f, "Lobby {name}: {members} players")
+            }
+            SessionRequestFeedback::SessionReady { ip, port, .. } => {
+                write!(f, "Session Ready! {ip}:{port}")
+            }
             SessionRequestFeedback::Error(code, msg) => write!(f, "Error {code}: {msg}"),
         }
     }
 }
 
+impl SessionRequestFeedback {
+    pub fn error(kind: MatchmakerErrorKind, message: impl Into<String>) -> Self {
+        SessionRequestFeedback::Error(kind.code(), message.into())
+    }
+}
+
+/// Why a matchmaker request failed. Sent as [`SessionRequestFeedback::Error`]'s code, which is
+/// http-like, so clients that only look at the number still make sense of it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MatchmakerErrorKind {
+    /// The game version isn't accepting players, eg it was deactivated on Edgegap.
+    AppVersionInactive,
+    /// There's nowhere to put the player right now: servers are full, or the matchmaker
+    /// can't be reached.
+    NoCapacity,
+    /// Took too long, eg waiting for a server, or for a lobby to start.
+    Timeout,
+    /// Too many requests, from this player or from everyone.
+    RateLimited,
+    /// The player's auth token is missing or wasn't accepted.
+    Unauthorized,
+    /// Something about the request was wrong, eg an unknown party code, or a full lobby.
+    BadRequest,
+    /// Edgegap returned an error we didn't expect.
+    UpstreamEdgegap,
+    /// Anything else.
+    Internal,
+}
+
+impl MatchmakerErrorKind {
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::BadRequest => 400,
+            Self::Unauthorized => 401,
+            Self::Timeout => 408,
+            Self::AppVersionInactive => 423,
+            Self::RateLimited => 429,
+            Self::Internal => 500,
+            Self::UpstreamEdgegap => 502,
+            Self::NoCapacity => 503,
+        }
+    }
+
+    /// Also understands the other codes older matchmakers sent.
+    pub fn from_code(code: u16) -> Self {
+        match code {
+            400 | 404 | 409 | 410 | 422 => Self::BadRequest,
+            401 | 403 => Self::Unauthorized,
+            408 | 504 => Self::Timeout,
+            423 => Self::AppVersionInactive,
+            429 => Self::RateLimited,
+            502 => Self::UpstreamEdgegap,
+            503 => Self::NoCapacity,
+            _ => Self::Internal,
+        }
+    }
+
+    /// True if the same request might work if tried again shortly.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::NoCapacity | Self::Timeout | Self::RateLimited | Self::UpstreamEdgegap
+        )
+    }
+
+    /// Something to show the player.
+    pub fn user_message(&self) -> &'static str {
+        match self {
+            Self::AppVersionInactive => "This version of the game isn't available right now",
+            Self::NoCapacity => "No servers are available right now, try again shortly",
+            Self::Timeout => "Finding a server took too long",
+            Self::RateLimited => "Too many requests, try again shortly",
+            Self::Unauthorized => "You need to be logged in to play",
+            Self::BadRequest => "The matchmaker couldn't make sense of that request",
+            Self::UpstreamEdgegap => "The game server provider had a problem, try again shortly",
+            Self::Internal => "Something went wrong, sorry",
+        }
+    }
+}
+
+impl fmt::Display for MatchmakerErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.user_message())
+    }
+}
+
 /// Send up the websocket to the matchmaker when a client wants to play.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RequestSession {
@@ -48,6 +159,94 @@ pub struct RequestSession {
     pub version: String,
     /// client ip address override
     pub client_ip: Option<String>,
+    /// set to play together with other clients, on the same server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub party: Option<PartyRequest>,
+    /// set to create or join a named lobby, which the host starts when everyone's there.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lobby: Option<LobbyRequest>,
+    /// set to rejoin the server you were disconnected from, using the secret from `SessionReady`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reconnect_secret: Option<String>,
+    /// bearer token (eg a JWT), if the matchmaker requires authentication.
+    /// Sent here because browsers can't set headers on websocket requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
+    /// the client's lowest round trip times to Edgegap's beacons, best first.
+    /// The matchmaker uses these to place the server near the client.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub latencies: Vec<BeaconLatency>,
+}
+
+/// An Edgegap location beacon, which clients measure their latency to.
+/// matchmaker_httpd serves the list at /matchmaker/beacons.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Beacon {
+    pub host: String,
+    /// The beacon's TCP port, which clients probe.
+    pub port: u16,
+    pub city: String,
+    pub country: String,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// How long a client took to reach a beacon, sent in [`RequestSession`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BeaconLatency {
+    /// As in [`Beacon::host`].
+    pub host: String,
+    pub rtt_ms: u32,
+}
+
+/// Lets several clients request a session together, so they end up on the same server.
+///
+/// The leader creates a party and gets a code via [`SessionRequestFeedback::PartyUpdate`],
+/// which the other players join with. Once the party is full, one session is created
+/// for everyone, and each member gets their own connect token.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum PartyRequest {
+    /// Start a new party for this many players, including the leader.
+    Create { size: u8 },
+    /// Join an existing party using the code the leader was given.
+    Join { code: String },
+}
+
+/// Lets players gather in a named lobby before getting a server.
+///
+/// Unlike a party, a lobby has no fixed size: it starts when the host (whoever created it)
+/// sends [`LobbyCommand::Start`] on the same websocket. One deployment is created for the
+/// lobby, and every member gets their own connect token for it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum LobbyRequest {
+    Create { name: String },
+    Join { name: String },
+}
+
+/// Sent by the lobby host after their [`RequestSession`], on the same websocket.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum LobbyCommand {
+    /// Deploy the lobby's server, and send everyone in the lobby a connect token.
+    Start,
+}
+
+/// What matchmaker_httpd publishes on a request's control subject, "{reply_inbox}.control",
+/// for the matchmaker task handling that request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum RequestControl {
+    /// A command the lobby host sent on their websocket.
+    Lobby(LobbyCommand),
+    /// The client's websocket closed before it got a session, so it's no longer waiting.
+    Cancel,
+}
+
+impl RequestControl {
+    /// Also understands the bare [`LobbyCommand`]s older matchmaker_httpds sent.
+    pub fn decode(payload: &[u8]) -> Result<Self, String> {
+        serde_json::from_slice(payload)
+            .or_else(|_| serde_json::from_slice(payload).map(RequestControl::Lobby))
+            .map_err(|e| format!("Undecodable control message: {e}"))
+    }
 }
 
 impl RequestSession {
@@ -74,3 +273,120 @@ impl RequestSession {
         Ok((self.game.clone(), self.version.clone()))
     }
 }
+
+/// How busy a gameserver is. Gameservers keep this updated in the server_capacity KV,
+/// keyed by their deployment's request id, so the matchmaker can avoid full servers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerCapacity {
+    pub players: u32,
+    pub max_players: u32,
+}
+
+impl ServerCapacity {
+    pub fn is_full(&self) -> bool {
+        self.players >= self.max_players
+    }
+}
+
+/// The lightyear transports bevygap knows how to connect clients with.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum GameTransport {
+    WebTransport,
+    Udp,
+    WebSocket,
+}
+
+impl GameTransport {
+    pub const ALL: [GameTransport; 3] = [Self::WebTransport, Self::Udp, Self::WebSocket];
+}
+
+impl fmt::Display for GameTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WebTransport => write!(f, "webtransport"),
+            Self::Udp => write!(f, "udp"),
+            Self::WebSocket => write!(f, "websocket"),
+        }
+    }
+}
+
+impl std::str::FromStr for GameTransport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|t| t.to_string().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| {
+                format!("Unknown transport '{s}', expected webtransport, udp or websocket")
+            })
+    }
+}
+
+/// The transports a gameserver listens on, and their internal ports. Gameservers put this in
+/// the server_transports KV, keyed by their deployment's request id, so the matchmaker knows
+/// which ports to send clients.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerTransports {
+    pub ports: BTreeMap<GameTransport, u16>,
+}
+
+/// Size of the user data in a lightyear connect token.
+pub const USER_DATA_BYTES: usize = 256;
+
+/// Who a player is, as vouched for by whatever authenticated their session request.
+///
+/// The matchmaker puts this in the connect token's user data, as json padded with zeros,
+/// so it has to fit in [`USER_DATA_BYTES`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PlayerIdentity {
+    pub player_id: String,
+    pub display_name: String,
+    /// Anything else the game wants to know, eg roles or cosmetics.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub claims: BTreeMap<String, String>,
+}
+
+impl PlayerIdentity {
+    pub fn to_user_data(&self) -> Result<[u8; USER_DATA_BYTES], String> {
+        let json = serde_json::to_vec(self).map_err(|e| e.to_string())?;
+        if json.len() > USER_DATA_BYTES {
+            return Err(format!(
+                "Player identity too large for user data ({} > {USER_DATA_BYTES} bytes)",
+                json.len()
+            ));
+        }
+        let mut user_data = [0u8; USER_DATA_BYTES];
+        user_data[..json.len()].copy_from_slice(&json);
+        Ok(user_data)
+    }
+
+    pub fn from_user_data(user_data: &[u8]) -> Result<Self, String> {
+        let len = user_data
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(user_data.len());
+        serde_json::from_slice(&user_data[..len]).map_err(|e| e.to_string())
+    }
+}
+
+/// What the matchmaker stores in the sessions_ly2eg KV for each client id it issues a token for.
+/// Gameservers watch these, to know who to let in.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IssuedClientId {
+    pub session_id: String,
+    /// Also in the token's user data, if the request came with a [`PlayerIdentity`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<PlayerIdentity>,
+}
+
+/// Identifies a lightyear private key without giving it away: the first 8 bytes of its
+/// sha256, in hex. Gameservers report theirs in the server_key_ids KV, so during a key rotation
+/// the matchmaker can sign tokens with the key each gameserver was started with.
+pub fn private_key_id(private_key: &[u8]) -> String {
+    Sha256::digest(private_key)[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
@@ -0,0 +1,143 @@
+//! The envelope that messages between game clients and matchmaker_httpd are wrapped in.
+//!
+//! Every message says which protocol version sent it, which request it's about, when it was
+//! sent, and what kind of message it is. The message itself is kept as a json value until the
+//! receiver asks for it, so a client that doesn't know a kind of message (eg a new
+//! [`SessionRequestFeedback`] variant) can skip it, instead of failing the whole request.
+//!
+//! Envelopes are json in text frames, or with the `msgpack` feature, MessagePack in binary
+//! frames. Clients from before the envelope send a bare [`RequestSession`], and matchmaker_httpd
+//! answers those with bare json too.
+use crate::protocol::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use web_time::{SystemTime, UNIX_EPOCH};
+
+/// Bumped when a message kind changes meaning. New kinds and new optional fields don't need
+/// a bump, since receivers skip what they don't know.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Kind of the client's first message.
+pub const KIND_REQUEST_SESSION: &str = "RequestSession";
+/// Kind of the lobby host's later messages.
+pub const KIND_LOBBY_COMMAND: &str = "LobbyCommand";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Envelope {
+    /// The sender's [`PROTOCOL_VERSION`].
+    pub version: u16,
+    /// Set by the client, or by matchmaker_httpd if the client didn't, and the same in every
+    /// reply about that request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// When the message was sent, in milliseconds since the unix epoch.
+    pub sent_at_ms: u64,
+    /// eg "RequestSession", or a [`SessionRequestFeedback`] variant like "SessionReady".
+    pub kind: String,
+    #[serde(default)]
+    pub body: serde_json::Value,
+}
+
+/// How envelopes are encoded on the websocket.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    /// Text frames
+    #[default]
+    Json,
+    /// Binary frames, smaller and quicker to decode than json.
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+/// A websocket message, as sent or received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+impl Envelope {
+    pub fn new<T: Serialize>(
+        kind: &str,
+        body: &T,
+        request_id: Option<String>,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            version: PROTOCOL_VERSION,
+            request_id,
+            sent_at_ms: now_ms(),
+            kind: kind.to_string(),
+            body: serde_json::to_value(body).map_err(|e| e.to_string())?,
+        })
+    }
+
+    /// Wraps a serde enum value, eg a [`SessionRequestFeedback`], using the variant name as the
+    /// kind. Works on the json, so variants this version doesn't know about can be passed on.
+    pub fn from_tagged(value: serde_json::Value, request_id: Option<String>) -> Self {
+        let (kind, body) = match value {
+            // unit variants
+            serde_json::Value::String(kind) => (kind, serde_json::Value::Null),
+            serde_json::Value::Object(map) if map.len() == 1 => map.into_iter().next().unwrap(),
+            other => ("Unknown".to_string(), other),
+        };
+        Self {
+            version: PROTOCOL_VERSION,
+            request_id,
+            sent_at_ms: now_ms(),
+            kind,
+            body,
+        }
+    }
+
+    pub fn from_feedback(feedback: &SessionRequestFeedback, request_id: Option<String>) -> Self {
+        Self::from_tagged(serde_json::to_value(feedback).unwrap(), request_id)
+    }
+
+    /// The feedback in this envelope, or None if it's a kind we don't know.
+    pub fn feedback(&self) -> Option<SessionRequestFeedback> {
+        let tagged = if self.body.is_null() {
+            serde_json::Value::String(self.kind.clone())
+        } else {
+            let mut map = serde_json::Map::new();
+            map.insert(self.kind.clone(), self.body.clone());
+            serde_json::Value::Object(map)
+        };
+        serde_json::from_value(tagged).ok()
+    }
+
+    /// The body, if this envelope is of the given kind.
+    pub fn body_of<T: DeserializeOwned>(&self, kind: &str) -> Option<Result<T, String>> {
+        (self.kind == kind)
+            .then(|| serde_json::from_value(self.body.clone()).map_err(|e| e.to_string()))
+    }
+
+    pub fn encode(&self, format: WireFormat) -> Frame {
+        match format {
+            WireFormat::Json => Frame::Text(serde_json::to_string(self).unwrap()),
+            #[cfg(feature = "msgpack")]
+            WireFormat::MessagePack => Frame::Binary(rmp_serde::to_vec_named(self).unwrap()),
+        }
+    }
+
+    /// Decodes a frame, and says which format it was in, to reply in the same one.
+    pub fn decode(frame: &Frame) -> Result<(Self, WireFormat), String> {
+        match frame {
+            Frame::Text(text) => serde_json::from_str(text)
+                .map(|envelope| (envelope, WireFormat::Json))
+                .map_err(|e| format!("Invalid json envelope: {e}")),
+            #[cfg(feature = "msgpack")]
+            Frame::Binary(bytes) => rmp_serde::from_slice(bytes)
+                .map(|envelope| (envelope, WireFormat::MessagePack))
+                .map_err(|e| format!("Invalid MessagePack envelope: {e}")),
+            #[cfg(not(feature = "msgpack"))]
+            Frame::Binary(_) => Err("Binary frames need the msgpack feature".to_string()),
+        }
+    }
+}
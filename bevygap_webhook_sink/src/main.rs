@@ -21,9 +21,13 @@ struct AppState {
 async fn main() {
     setup_logging();
 
-    let bgnats = BevygapNats::new_and_connect("bevygap_webhook_sink")
-        .await
-        .unwrap();
+    let bgnats = match BevygapNats::new_and_connect("bevygap_webhook_sink").await {
+        Ok(bgnats) => bgnats,
+        Err(e) => {
+            error!("Failed to connect to NATS: {e}");
+            std::process::exit(1);
+        }
+    };
     let app_state = Arc::new(AppState { bgnats });
 
     // build our application with a route
@@ -56,7 +60,7 @@ async fn hook_handler(
     State(state): State<Arc<AppState>>,
     body: String,
 ) -> Result<impl IntoResponse, AppError> {
-    let subject = format!("webhook.{hook_name}");
+    let subject = state.bgnats.namespace().webhook(&hook_name);
     info!("NATS publish {subject} = {body}");
     state.bgnats.client().publish(subject, body.into()).await?;
     Ok("OK")
@@ -0,0 +1,815 @@
+//! A fake Edgegap API server, for testing bevygap without network access.
+//!
+//! Implements just the endpoints bevygap uses: app and version lookups, session
+//! create/get/delete/list, and the deployment context fetched by gameservers.
+//! Sessions created with a webhook url get a callback when they become ready, like the real
+//! API sends. Point an `edgegap_async` [`Configuration`] at it with [`FakeEdgegap::configuration`].
+//!
+//! ```no_run
+//! # async fn example() {
+//! use edgegap_fake::*;
+//! use std::time::Duration;
+//!
+//! let fake = FakeEdgegap::builder()
+//!     .app("spacepit_server", "v0.0.1")
+//!     .ready_after(Duration::from_secs(2))
+//!     .start()
+//!     .await
+//!     .unwrap();
+//! let config = fake.configuration();
+//! // ...run matchmaker code with `config`...
+//! fake.update_behaviour(|b| b.session_post_error = Some((409, "Conflict".into())));
+//! # }
+//! ```
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use edgegap_async::apis::configuration::{ApiKey, Configuration};
+use edgegap_async::models;
+use log::*;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// How often ready sessions are checked for webhooks to send.
+const WEBHOOK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How the fake responds. Can be changed while running with [`FakeEdgegap::update_behaviour`],
+/// and changes apply to existing sessions too.
+#[derive(Debug, Clone)]
+pub struct Behaviour {
+    /// How long after creation sessions become ready. `None` means never.
+    pub ready_after: Option<Duration>,
+    /// If set, session creation fails with this status code and message (eg 400, 401, 409).
+    pub session_post_error: Option<(u16, String)>,
+    /// If set, session deletion fails with this status code and message (eg 410).
+    pub session_delete_error: Option<(u16, String)>,
+    /// If set, requests without this key in the authorization header get a 401.
+    pub api_key: Option<String>,
+    /// Public IP given to deployments.
+    pub public_ip: String,
+    /// Ports given to deployments, as (name, internal, external).
+    pub ports: Vec<(String, u16, u16)>,
+    /// Where deployments are, as reported in their context.
+    pub location: models::DeploymentLocation,
+}
+
+impl Default for Behaviour {
+    fn default() -> Self {
+        Self {
+            ready_after: Some(Duration::ZERO),
+            session_post_error: None,
+            session_delete_error: None,
+            api_key: None,
+            public_ip: "127.0.0.1".to_string(),
+            ports: vec![("server".to_string(), 6420, 6420)],
+            location: models::DeploymentLocation::new(
+                "Montreal".to_string(),
+                "Canada".to_string(),
+                "North America".to_string(),
+                "Quebec".to_string(),
+                "America/Toronto".to_string(),
+                45.5,
+                -73.57,
+            ),
+        }
+    }
+}
+
+/// Builds and starts a [`FakeEdgegap`].
+#[derive(Default)]
+pub struct FakeEdgegapBuilder {
+    apps: HashMap<String, HashMap<String, bool>>,
+    behaviour: Behaviour,
+    custom_ports: bool,
+    bind: Option<SocketAddr>,
+}
+
+impl FakeEdgegapBuilder {
+    /// Registers an application version, so app and version lookups succeed.
+    pub fn app(mut self, app_name: impl Into<String>, version: impl Into<String>) -> Self {
+        self.apps
+            .entry(app_name.into())
+            .or_default()
+            .insert(version.into(), true);
+        self
+    }
+
+    /// Registers an application version that isn't active, so sessions can't be created for it.
+    pub fn inactive_app(mut self, app_name: impl Into<String>, version: impl Into<String>) -> Self {
+        self.apps
+            .entry(app_name.into())
+            .or_default()
+            .insert(version.into(), false);
+        self
+    }
+
+    /// Sessions become ready this long after they are created.
+    pub fn ready_after(mut self, delay: Duration) -> Self {
+        self.behaviour.ready_after = Some(delay);
+        self
+    }
+
+    /// Sessions are created, but never become ready.
+    pub fn never_ready(mut self) -> Self {
+        self.behaviour.ready_after = None;
+        self
+    }
+
+    /// Session creation fails with this status and message.
+    pub fn session_post_error(mut self, status: u16, message: impl Into<String>) -> Self {
+        self.behaviour.session_post_error = Some((status, message.into()));
+        self
+    }
+
+    /// Session deletion fails with this status and message.
+    pub fn session_delete_error(mut self, status: u16, message: impl Into<String>) -> Self {
+        self.behaviour.session_delete_error = Some((status, message.into()));
+        self
+    }
+
+    /// Requires this api key, like the real API does.
+    pub fn api_key(mut self, key: impl Into<String>) -> Self {
+        self.behaviour.api_key = Some(key.into());
+        self
+    }
+
+    /// Public IP given to deployments, defaults to 127.0.0.1.
+    pub fn public_ip(mut self, ip: impl Into<String>) -> Self {
+        self.behaviour.public_ip = ip.into();
+        self
+    }
+
+    /// Adds a port to the deployments' port mapping. The first call replaces the default.
+    pub fn port(mut self, name: impl Into<String>, internal: u16, external: u16) -> Self {
+        if !self.custom_ports {
+            self.behaviour.ports.clear();
+            self.custom_ports = true;
+        }
+        self.behaviour.ports.push((name.into(), internal, external));
+        self
+    }
+
+    /// Where deployments say they are, defaults to Montreal.
+    pub fn location(
+        mut self,
+        city: impl Into<String>,
+        country: impl Into<String>,
+        latitude: f64,
+        longitude: f64,
+    ) -> Self {
+        let location = &mut self.behaviour.location;
+        location.city = city.into();
+        location.country = country.into();
+        location.latitude = latitude;
+        location.longitude = longitude;
+        self
+    }
+
+    /// Address to listen on, defaults to a random port on localhost.
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.bind = Some(addr);
+        self
+    }
+
+    pub async fn start(self) -> std::io::Result<FakeEdgegap> {
+        let addr = self.bind.unwrap_or_else(|| ([127, 0, 0, 1], 0).into());
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(FakeState {
+            base_path: format!("http://{addr}"),
+            inner: Mutex::new(Inner {
+                apps: self.apps,
+                behaviour: self.behaviour,
+                sessions: HashMap::new(),
+                lobbies: HashMap::new(),
+                webhooks_sent: Vec::new(),
+            }),
+        });
+
+        let app = Router::new()
+            .route("/v1/app/:app_name", get(application_get))
+            .route(
+                "/v1/app/:app_name/version/:version_name",
+                get(app_version_get),
+            )
+            .route("/v1/session", post(session_post))
+            .route("/v1/session/:session_id", get(session_get))
+            .route("/v1/session/:session_id", delete(session_delete))
+            .route("/v1/sessions", get(list_sessions))
+            .route("/v1/context/:request_id/:security_number", get(context_get))
+            .route("/v1/stop/:request_id", delete(deployment_delete))
+            .route("/v1/lobbies", post(lobby_create))
+            .route("/v1/lobbies/:lobby_name", get(lobby_get))
+            // "/v1/lobbies:deploy" and "/v1/lobbies:terminate" aren't valid axum routes.
+            .fallback(lobby_action)
+            .with_state(state.clone());
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let res = axum::serve(listener, app)
+                .with_graceful_shutdown(async {
+                    let _ = shutdown_rx.await;
+                })
+                .await;
+            if let Err(e) = res {
+                error!("Fake Edgegap server error: {e}");
+            }
+        });
+        tokio::spawn(send_webhooks(Arc::downgrade(&state)));
+        info!("Fake Edgegap listening on {addr}");
+
+        Ok(FakeEdgegap {
+            addr,
+            state,
+            _shutdown: shutdown_tx,
+        })
+    }
+}
+
+/// A running fake Edgegap API. Stops when dropped.
+pub struct FakeEdgegap {
+    addr: SocketAddr,
+    state: Arc<FakeState>,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl FakeEdgegap {
+    pub fn builder() -> FakeEdgegapBuilder {
+        FakeEdgegapBuilder::default()
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// For `Configuration::base_path`
+    pub fn base_path(&self) -> String {
+        self.state.base_path.clone()
+    }
+
+    /// An `edgegap_async` configuration that talks to this fake.
+    pub fn configuration(&self) -> Configuration {
+        let inner = self.state.inner.lock().unwrap();
+        Configuration {
+            base_path: self.base_path(),
+            api_key: inner
+                .behaviour
+                .api_key
+                .clone()
+                .map(|key| ApiKey { prefix: None, key }),
+            ..Default::default()
+        }
+    }
+
+    pub fn update_behaviour(&self, f: impl FnOnce(&mut Behaviour)) {
+        f(&mut self.state.inner.lock().unwrap().behaviour);
+    }
+
+    /// Makes a session ready now, regardless of [`Behaviour::ready_after`].
+    pub fn make_ready(&self, session_id: &str) -> bool {
+        let mut inner = self.state.inner.lock().unwrap();
+        match inner.sessions.get_mut(session_id) {
+            Some(session) => {
+                session.forced_ready = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// IDs of sessions that exist and haven't been deleted.
+    pub fn session_ids(&self) -> Vec<String> {
+        let inner = self.state.inner.lock().unwrap();
+        inner
+            .sessions
+            .values()
+            .filter(|s| !s.deleted)
+            .map(|s| s.session_id.clone())
+            .collect()
+    }
+
+    /// Has this session been created, and then deleted via the API?
+    pub fn is_deleted(&self, session_id: &str) -> bool {
+        let inner = self.state.inner.lock().unwrap();
+        inner
+            .sessions
+            .get(session_id)
+            .map(|s| s.deleted)
+            .unwrap_or(false)
+    }
+
+    /// The status of a lobby that was created and not yet terminated, eg "WAITING" or "READY".
+    pub fn lobby_status(&self, name: &str) -> Option<String> {
+        self.state.inner.lock().unwrap().lobbies.get(name).cloned()
+    }
+
+    /// Session ids whose ready webhook was delivered, in the order they were sent.
+    pub fn webhooks_sent(&self) -> Vec<String> {
+        self.state.inner.lock().unwrap().webhooks_sent.clone()
+    }
+
+    /// The ARBITRIUM_CONTEXT_URL and ARBITRIUM_CONTEXT_TOKEN a gameserver for this
+    /// session would be given.
+    pub fn context_url_and_token(&self, session_id: &str) -> Option<(String, String)> {
+        let inner = self.state.inner.lock().unwrap();
+        inner.sessions.get(session_id).map(|s| {
+            (
+                format!(
+                    "{}/v1/context/{}/{}",
+                    self.state.base_path, s.request_id, s.security_number
+                ),
+                s.context_token.clone(),
+            )
+        })
+    }
+}
+
+struct FakeState {
+    base_path: String,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    apps: HashMap<String, HashMap<String, bool>>,
+    behaviour: Behaviour,
+    sessions: HashMap<String, FakeSession>,
+    /// Lobby statuses by name. Terminated lobbies are forgotten.
+    lobbies: HashMap<String, String>,
+    /// Session ids, see [`FakeEdgegap::webhooks_sent`].
+    webhooks_sent: Vec<String>,
+}
+
+struct FakeSession {
+    session_id: String,
+    request_id: String,
+    security_number: i32,
+    context_token: String,
+    webhook_url: Option<String>,
+    created: Instant,
+    forced_ready: bool,
+    deleted: bool,
+    /// Set once we've tried to send the ready webhook, so it's only sent once.
+    webhook_attempted: bool,
+}
+
+impl FakeSession {
+    fn ready(&self, behaviour: &Behaviour) -> bool {
+        self.forced_ready
+            || behaviour
+                .ready_after
+                .map(|delay| self.created.elapsed() >= delay)
+                .unwrap_or(false)
+    }
+
+    fn status(&self, behaviour: &Behaviour) -> String {
+        if self.ready(behaviour) {
+            "Status.READY"
+        } else {
+            "Status.SEEKING"
+        }
+        .to_string()
+    }
+
+    fn deployment(&self, behaviour: &Behaviour) -> models::Deployment {
+        let ready = self.ready(behaviour);
+        let mut deployment = models::Deployment::new(
+            self.request_id.clone(),
+            behaviour.public_ip.clone(),
+            if ready {
+                "Status.READY"
+            } else {
+                "Status.DEPLOYING"
+            }
+            .to_string(),
+            ready,
+            false,
+            format!("{}.pr.edgegap.net", self.request_id),
+        );
+        let ports = behaviour
+            .ports
+            .iter()
+            .map(|(name, internal, external)| {
+                let mut port = models::PortMapping::new();
+                port.name = Some(name.clone());
+                port.internal = Some(*internal as i32);
+                port.external = Some(*external as i32);
+                port.protocol = Some("UDP".to_string());
+                (name.clone(), port)
+            })
+            .collect();
+        deployment.ports = Some(ports);
+        deployment.sockets = Some(100);
+        deployment.location = Some(Box::new(behaviour.location.clone()));
+        deployment
+    }
+
+    fn session_get(&self, behaviour: &Behaviour) -> models::SessionGet {
+        let ready = self.ready(behaviour);
+        let mut session = models::SessionGet::new(
+            self.session_id.clone(),
+            self.status(behaviour),
+            ready,
+            ready,
+            "Seat".to_string(),
+            0,
+            1,
+            time::OffsetDateTime::now_utc().to_string(),
+            self.created.elapsed().as_secs() as i32,
+        );
+        session.webhook_url = self.webhook_url.clone();
+        if ready {
+            session.deployment = Some(Box::new(self.deployment(behaviour)));
+        }
+        session
+    }
+}
+
+/// Error responses look like the real API's: `{"message": "..."}`
+fn error_response(status: u16, message: impl Into<String>) -> Response {
+    let status = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    (status, Json(models::Error::new(message.into()))).into_response()
+}
+
+fn check_api_key(headers: &HeaderMap, behaviour: &Behaviour) -> Result<(), Response> {
+    let Some(key) = behaviour.api_key.as_ref() else {
+        return Ok(());
+    };
+    let supplied = headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if supplied == key {
+        Ok(())
+    } else {
+        Err(error_response(401, "Invalid API key"))
+    }
+}
+
+async fn application_get(
+    Path(app_name): Path<String>,
+    State(state): State<Arc<FakeState>>,
+    headers: HeaderMap,
+) -> Response {
+    let inner = state.inner.lock().unwrap();
+    if let Err(resp) = check_api_key(&headers, &inner.behaviour) {
+        return resp;
+    }
+    if !inner.apps.contains_key(&app_name) {
+        return error_response(404, format!("Application {app_name} not found"));
+    }
+    let now = time::OffsetDateTime::now_utc().to_string();
+    Json(models::Application::new(app_name, true, now.clone(), now)).into_response()
+}
+
+async fn app_version_get(
+    Path((app_name, version_name)): Path<(String, String)>,
+    State(state): State<Arc<FakeState>>,
+    headers: HeaderMap,
+) -> Response {
+    let inner = state.inner.lock().unwrap();
+    if let Err(resp) = check_api_key(&headers, &inner.behaviour) {
+        return resp;
+    }
+    let Some(is_active) = inner
+        .apps
+        .get(&app_name)
+        .and_then(|versions| versions.get(&version_name))
+        .copied()
+    else {
+        return error_response(
+            404,
+            format!("Version {version_name} of {app_name} not found"),
+        );
+    };
+    let mut version = models::AppVersionPayload::new(
+        version_name.clone(),
+        "registry.edgegap.com".to_string(),
+        app_name,
+        version_name,
+        256,
+        256,
+    );
+    version.is_active = Some(is_active);
+    Json(version).into_response()
+}
+
+async fn session_post(
+    State(state): State<Arc<FakeState>>,
+    headers: HeaderMap,
+    Json(payload): Json<models::SessionModel>,
+) -> Response {
+    let mut inner = state.inner.lock().unwrap();
+    if let Err(resp) = check_api_key(&headers, &inner.behaviour) {
+        return resp;
+    }
+    if let Some((status, message)) = inner.behaviour.session_post_error.clone() {
+        return error_response(status, message);
+    }
+    let version = payload.version_name.clone().unwrap_or_default();
+    match inner
+        .apps
+        .get(&payload.app_name)
+        .and_then(|versions| versions.get(&version))
+    {
+        Some(true) => {}
+        Some(false) => {
+            return error_response(
+                400,
+                format!("App version {} {version} is disabled", payload.app_name),
+            );
+        }
+        None => {
+            return error_response(
+                400,
+                format!("Unknown app version {} {version}", payload.app_name),
+            );
+        }
+    }
+
+    let request_id = format!("{:012x}", rand::random::<u64>() & 0xffff_ffff_ffff);
+    let session_id = format!("{request_id}-S");
+    let session = FakeSession {
+        session_id: session_id.clone(),
+        request_id,
+        security_number: rand::random::<u16>() as i32,
+        context_token: format!("{:032x}", rand::random::<u128>()),
+        webhook_url: payload.webhook_url.clone(),
+        created: Instant::now(),
+        forced_ready: false,
+        deleted: false,
+        webhook_attempted: false,
+    };
+    info!(
+        "Fake Edgegap created session {session_id} for {:?}",
+        payload.ip_list
+    );
+    let mut resp = models::SessionRequest::new(session_id.clone(), payload.app_name, version);
+    resp.webhook_url = session.webhook_url.clone();
+    inner.sessions.insert(session_id, session);
+    Json(resp).into_response()
+}
+
+async fn session_get(
+    Path(session_id): Path<String>,
+    State(state): State<Arc<FakeState>>,
+    headers: HeaderMap,
+) -> Response {
+    let inner = state.inner.lock().unwrap();
+    if let Err(resp) = check_api_key(&headers, &inner.behaviour) {
+        return resp;
+    }
+    match inner.sessions.get(&session_id) {
+        Some(session) if !session.deleted => {
+            Json(session.session_get(&inner.behaviour)).into_response()
+        }
+        _ => error_response(404, format!("Session {session_id} not found")),
+    }
+}
+
+async fn lobby_create(
+    State(state): State<Arc<FakeState>>,
+    headers: HeaderMap,
+    Json(payload): Json<models::LobbyCreatePayload>,
+) -> Response {
+    let mut inner = state.inner.lock().unwrap();
+    if let Err(resp) = check_api_key(&headers, &inner.behaviour) {
+        return resp;
+    }
+    if inner.lobbies.contains_key(&payload.name) {
+        return error_response(409, format!("Lobby {} already exists", payload.name));
+    }
+    inner
+        .lobbies
+        .insert(payload.name.clone(), "WAITING".to_string());
+    Json(lobby_response(&state, &payload.name, "WAITING")).into_response()
+}
+
+async fn lobby_get(
+    Path(lobby_name): Path<String>,
+    State(state): State<Arc<FakeState>>,
+    headers: HeaderMap,
+) -> Response {
+    let inner = state.inner.lock().unwrap();
+    if let Err(resp) = check_api_key(&headers, &inner.behaviour) {
+        return resp;
+    }
+    match inner.lobbies.get(&lobby_name) {
+        Some(status) => Json(lobby_response(&state, &lobby_name, status)).into_response(),
+        None => error_response(404, format!("Lobby {lobby_name} not found")),
+    }
+}
+
+/// POST /v1/lobbies:deploy (which is instant) and POST /v1/lobbies:terminate.
+async fn lobby_action(
+    method: Method,
+    uri: Uri,
+    State(state): State<Arc<FakeState>>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Response {
+    let deploy = match (method, uri.path()) {
+        (Method::POST, "/v1/lobbies:deploy") => true,
+        (Method::POST, "/v1/lobbies:terminate") => false,
+        (_, path) => return error_response(404, format!("No fake for {path}")),
+    };
+    let mut inner = state.inner.lock().unwrap();
+    if let Err(resp) = check_api_key(&headers, &inner.behaviour) {
+        return resp;
+    }
+    let Ok(payload) = serde_json::from_slice::<models::LobbyDeployPayload>(&body) else {
+        return error_response(400, "Expected a lobby name");
+    };
+    let name = payload.name;
+    if !inner.lobbies.contains_key(&name) {
+        return error_response(404, format!("Lobby {name} not found"));
+    }
+    let status = if deploy {
+        inner.lobbies.insert(name.clone(), "READY".to_string());
+        "READY"
+    } else {
+        inner.lobbies.remove(&name);
+        "TERMINATED"
+    };
+    info!("Fake Edgegap lobby {name} is {status}");
+    Json(lobby_response(&state, &name, status)).into_response()
+}
+
+fn lobby_response(state: &FakeState, name: &str, status: &str) -> models::LobbyReadResponse {
+    models::LobbyReadResponse::new(
+        name.to_string(),
+        format!("{}/lobbies/{name}", state.base_path),
+        status.to_string(),
+    )
+}
+
+async fn session_delete(
+    Path(session_id): Path<String>,
+    State(state): State<Arc<FakeState>>,
+    headers: HeaderMap,
+) -> Response {
+    let mut inner = state.inner.lock().unwrap();
+    if let Err(resp) = check_api_key(&headers, &inner.behaviour) {
+        return resp;
+    }
+    if let Some((status, message)) = inner.behaviour.session_delete_error.clone() {
+        return error_response(status, message);
+    }
+    match inner.sessions.get_mut(&session_id) {
+        // the real api says 410 Gone for sessions that were already deleted
+        Some(session) if session.deleted => {
+            error_response(410, format!("Session {session_id} already deleted"))
+        }
+        Some(session) => {
+            session.deleted = true;
+            info!("Fake Edgegap deleted session {session_id}");
+            Json(models::SessionDelete::new(
+                "Session deleted".to_string(),
+                session_id,
+            ))
+            .into_response()
+        }
+        None => error_response(404, format!("Session {session_id} not found")),
+    }
+}
+
+/// Stopping a session's deployment deletes the session too, like the real api.
+async fn deployment_delete(
+    Path(request_id): Path<String>,
+    State(state): State<Arc<FakeState>>,
+    headers: HeaderMap,
+) -> Response {
+    let mut inner = state.inner.lock().unwrap();
+    if let Err(resp) = check_api_key(&headers, &inner.behaviour) {
+        return resp;
+    }
+    match inner
+        .sessions
+        .values_mut()
+        .find(|s| s.request_id == request_id)
+    {
+        Some(session) if session.deleted => {
+            error_response(410, format!("Deployment {request_id} already stopped"))
+        }
+        Some(session) => {
+            session.deleted = true;
+            info!("Fake Edgegap stopped deployment {request_id}");
+            Json(models::Delete::new("Deployment stopped".to_string())).into_response()
+        }
+        None => error_response(404, format!("Deployment {request_id} not found")),
+    }
+}
+
+async fn list_sessions(State(state): State<Arc<FakeState>>, headers: HeaderMap) -> Response {
+    let inner = state.inner.lock().unwrap();
+    if let Err(resp) = check_api_key(&headers, &inner.behaviour) {
+        return resp;
+    }
+    let data: Vec<models::SessionContext> = inner
+        .sessions
+        .values()
+        .filter(|s| !s.deleted)
+        .map(|s| {
+            let ready = s.ready(&inner.behaviour);
+            let mut ctx = models::SessionContext::new(
+                s.session_id.clone(),
+                s.status(&inner.behaviour),
+                ready,
+                ready,
+                "Seat".to_string(),
+                0,
+            );
+            ctx.deployment_request_id = Some(s.request_id.clone());
+            ctx.webhook_url = s.webhook_url.clone();
+            ctx
+        })
+        .collect();
+    let mut sessions = models::Sessions::new();
+    sessions.total_count = Some(data.len() as i32);
+    sessions.data = Some(data);
+    Json(sessions).into_response()
+}
+
+async fn context_get(
+    Path((request_id, security_number)): Path<(String, i32)>,
+    State(state): State<Arc<FakeState>>,
+    headers: HeaderMap,
+) -> Response {
+    let inner = state.inner.lock().unwrap();
+    let Some(session) = inner
+        .sessions
+        .values()
+        .find(|s| s.request_id == request_id && s.security_number == security_number)
+    else {
+        return error_response(404, format!("Deployment {request_id} not found"));
+    };
+    // gameservers authenticate with the context token, not the api key
+    let token = headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if token != session.context_token {
+        return error_response(401, "Invalid context token");
+    }
+    Json(session.deployment(&inner.behaviour)).into_response()
+}
+
+/// What the real API posts to a session's webhook url.
+fn webhook_body(session: &FakeSession, behaviour: &Behaviour) -> serde_json::Value {
+    let ready = session.ready(behaviour);
+    serde_json::json!({
+        "session_id": session.session_id,
+        "status": session.status(behaviour),
+        "ready": ready,
+        "kind": "Seat",
+        "user_count": 1,
+        "linked": ready,
+        "webhook_url": session.webhook_url,
+        "deployment_request_id": session.request_id,
+    })
+}
+
+/// Posts to the webhook url of each session that has become ready, once.
+/// Runs until the fake is dropped.
+async fn send_webhooks(state: Weak<FakeState>) {
+    let client = reqwest::Client::new();
+    loop {
+        tokio::time::sleep(WEBHOOK_INTERVAL).await;
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        let due: Vec<(String, String, serde_json::Value)> = {
+            let mut inner = state.inner.lock().unwrap();
+            let Inner {
+                behaviour,
+                sessions,
+                ..
+            } = &mut *inner;
+            sessions
+                .values_mut()
+                .filter(|s| !s.deleted && !s.webhook_attempted && s.ready(behaviour))
+                .filter_map(|s| {
+                    s.webhook_attempted = true;
+                    let url = s.webhook_url.clone()?;
+                    Some((s.session_id.clone(), url, webhook_body(s, behaviour)))
+                })
+                .collect()
+        };
+        for (session_id, url, body) in due {
+            match client.post(&url).json(&body).send().await {
+                Ok(resp) if resp.status().is_success() => {
+                    debug!("Fake Edgegap sent webhook for {session_id} to {url}");
+                    state.inner.lock().unwrap().webhooks_sent.push(session_id);
+                }
+                Ok(resp) => warn!("Webhook for {session_id} to {url}: {}", resp.status()),
+                Err(e) => warn!("Failed to send webhook for {session_id} to {url}: {e}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use axum::{extract::State, routing::post, Json, Router};
+use edgegap_async::apis::sessions_api::session_post;
+use edgegap_async::models::SessionModel;
+use edgegap_fake::FakeEdgegap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const APP: &str = "spacepit_server";
+const VERSION: &str = "v0.0.1";
+
+fn session_model(webhook_url: Option<String>) -> SessionModel {
+    let mut model = SessionModel::new(APP.to_string());
+    model.version_name = Some(VERSION.to_string());
+    model.ip_list = Some(vec!["81.128.157.123".to_string()]);
+    model.webhook_url = webhook_url;
+    model
+}
+
+type Received = Arc<Mutex<Vec<serde_json::Value>>>;
+
+/// Starts an http server that records what's posted to /hook/session.
+async fn webhook_receiver() -> (String, Received) {
+    let received = Received::default();
+    let app = Router::new()
+        .route(
+            "/hook/session",
+            post(
+                |State(received): State<Received>, Json(body): Json<serde_json::Value>| async move {
+                    received.lock().unwrap().push(body);
+                },
+            ),
+        )
+        .with_state(received.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook/session", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    (url, received)
+}
+
+#[tokio::test]
+async fn ready_sessions_get_one_webhook() {
+    let (url, received) = webhook_receiver().await;
+    let fake = FakeEdgegap::builder()
+        .app(APP, VERSION)
+        .ready_after(Duration::from_millis(200))
+        .start()
+        .await
+        .unwrap();
+    let config = fake.configuration();
+    let session_id = session_post(&config, session_model(Some(url)))
+        .await
+        .unwrap()
+        .session_id;
+    // sessions without a webhook url don't get one.
+    session_post(&config, session_model(None)).await.unwrap();
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(received.lock().unwrap().is_empty());
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let received = received.lock().unwrap().clone();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0]["session_id"], session_id.as_str());
+    assert_eq!(received[0]["ready"], true);
+    assert!(received[0]["deployment_request_id"].is_string());
+    assert_eq!(fake.webhooks_sent(), vec![session_id]);
+}
+
+#[tokio::test]
+async fn never_ready_sessions_get_no_webhook() {
+    let (url, received) = webhook_receiver().await;
+    let fake = FakeEdgegap::builder()
+        .app(APP, VERSION)
+        .never_ready()
+        .start()
+        .await
+        .unwrap();
+    session_post(&fake.configuration(), session_model(Some(url)))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(received.lock().unwrap().is_empty());
+    assert!(fake.webhooks_sent().is_empty());
+}
+
+#[tokio::test]
+async fn context_has_a_location() {
+    let fake = FakeEdgegap::builder()
+        .app(APP, VERSION)
+        .location("Frankfurt", "Germany", 50.11, 8.68)
+        .start()
+        .await
+        .unwrap();
+    let session_id = session_post(&fake.configuration(), session_model(None))
+        .await
+        .unwrap()
+        .session_id;
+    let (url, token) = fake.context_url_and_token(&session_id).unwrap();
+
+    let client = reqwest::Client::new();
+    let context: serde_json::Value = client
+        .get(&url)
+        .header("authorization", token)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(context["location"]["city"], "Frankfurt");
+    assert_eq!(context["location"]["country"], "Germany");
+    assert_eq!(context["sockets"], 100);
+
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.status(), 401);
+}